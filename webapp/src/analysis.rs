@@ -1,17 +1,33 @@
 use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
+use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
 
+use crate::component;
+use crate::metadata::Metadata;
+use crate::quality::{self, Tonicity, PhClass, TemperatureClass};
 use crate::template::{Template, Render, render_template};
 
-/**
+/*
  * Resources:
  * - Serde https://serde.rs/
  */
 
-#[derive(Clone, PartialEq, Deserialize, Serialize, Debug)]
-#[serde(untagged)]
+/**
+ * Value of a cell in an analysis sheet.
+ * Serialized as a number, a text or null. Texts such as "<0.1", "微量" and
+ * "検出せず" are read as the corresponding variants, which keep the text as
+ * written to serialize it again.
+ */
+#[derive(Clone, PartialEq, Debug)]
 pub enum CellValue {
     Number(f64),
+    /// Below the detection limit, e.g. "<0.1" or "0.1未満"
+    BelowLimit { threshold: f64, text: String },
+    /// 微量
+    Trace(String),
+    /// 検出せず, e.g. "不検出" or "N.D."
+    NotDetected(String),
     Text(String),
     Null
 }
@@ -25,6 +41,126 @@ pub struct MgMvalMmol {
     pub mmol: CellValue
 }
 
+impl CellValue {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            CellValue::Number(x) => Some(*x),
+            _ => None
+        }
+    }
+
+    /// Amount used in totals. Values under the detection limit count as 0.
+    pub fn amount(&self) -> Option<f64> {
+        match self {
+            CellValue::Number(x) => Some(*x),
+            CellValue::BelowLimit { .. } |
+            CellValue::Trace(_) |
+            CellValue::NotDetected(_) => Some(0.0),
+            _ => None
+        }
+    }
+
+    /// Null and empty text are regarded as not entered.
+    pub fn is_blank(&self) -> bool {
+        match self {
+            CellValue::Null => true,
+            CellValue::Text(t) => t.trim().is_empty(),
+            _ => false
+        }
+    }
+
+    /// Read a text written in a cell, keeping it as written.
+    pub fn parse_text(text: &str) -> Self {
+        let t = text.trim();
+        match t {
+            "微量" => return CellValue::Trace(text.to_string()),
+            "検出せず" | "不検出" | "ND" | "N.D." => {
+                return CellValue::NotDetected(text.to_string())
+            }
+            _ => {}
+        }
+        let limit = t.strip_prefix('<')
+            .or_else(|| t.strip_prefix('＜'))
+            .or_else(|| t.strip_suffix("未満"))
+            .and_then(|x| x.trim().parse::<f64>().ok());
+        match limit {
+            Some(x) => CellValue::BelowLimit {
+                threshold: x,
+                text: text.to_string()
+            },
+            None => CellValue::Text(text.to_string())
+        }
+    }
+}
+
+/// Numbers as they are and texts as written.
+impl fmt::Display for CellValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CellValue::Number(x) => write!(f, "{}", x),
+            CellValue::BelowLimit { text, .. } |
+            CellValue::Trace(text) |
+            CellValue::NotDetected(text) |
+            CellValue::Text(text) => f.write_str(text),
+            CellValue::Null => Ok(())
+        }
+    }
+}
+
+impl Serialize for CellValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match self {
+            CellValue::Number(x) => serializer.serialize_f64(*x),
+            CellValue::Null => serializer.serialize_unit(),
+            v => serializer.serialize_str(&v.to_string())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CellValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        struct CellValueVisitor;
+
+        impl<'de> Visitor<'de> for CellValueVisitor {
+            type Value = CellValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number, a text or null")
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<CellValue, E> {
+                Ok(CellValue::Number(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<CellValue, E> {
+                Ok(CellValue::Number(v as f64))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<CellValue, E> {
+                Ok(CellValue::Number(v as f64))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<CellValue, E> {
+                Ok(CellValue::parse_text(v))
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<CellValue, E> {
+                Ok(CellValue::Null)
+            }
+
+            fn visit_none<E: de::Error>(self) -> Result<CellValue, E> {
+                Ok(CellValue::Null)
+            }
+        }
+
+        deserializer.deserialize_any(CellValueVisitor)
+    }
+}
+
 impl MgMvalMmol {
     pub fn new() -> Self {
         MgMvalMmol {
@@ -34,6 +170,22 @@ impl MgMvalMmol {
             mmol: CellValue::Null
         }
     }
+
+    /// Fill mmol and mval from mg.
+    pub fn derive(&mut self, c: &component::Component, overwrite: bool) {
+        let mg = match self.mg.as_f64() {
+            Some(mg) => mg,
+            None => return
+        };
+        if overwrite || self.mmol.is_blank() {
+            self.mmol = CellValue::Number(c.mmol(mg));
+        }
+        if let Some(mval) = c.mval(mg) {
+            if overwrite || self.mval.is_blank() {
+                self.mval = CellValue::Number(mval);
+            }
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Deserialize, Serialize, Debug)]
@@ -48,6 +200,46 @@ impl ComponentTable {
             components: HashMap::new()
         }
     }
+
+    /// Fill mmol and mval of each component, and mval% if `percent` is set.
+    pub fn derive(&mut self, overwrite: bool, percent: bool) {
+        for (key, value) in self.components.iter_mut() {
+            match component::lookup(key) {
+                Some(c) => value.derive(c, overwrite),
+                None => debug!("ComponentTable::derive, unknown key: {}", &key)
+            }
+        }
+        if !percent {
+            return;
+        }
+        let total: f64 = self.components.values()
+            .filter_map(|v| v.mval.as_f64())
+            .sum();
+        if total <= 0.0 {
+            return;
+        }
+        for value in self.components.values_mut() {
+            if let Some(mval) = value.mval.as_f64() {
+                if overwrite || value.mval_percent.is_blank() {
+                    value.mval_percent =
+                        CellValue::Number(mval / total * 100.0);
+                }
+            }
+        }
+    }
+
+    /// mval% of a component, computed from mval if it is not entered.
+    pub fn mval_percent(&self, key: &str) -> Option<f64> {
+        let value = self.components.get(key)?;
+        value.mval_percent.as_f64().or_else(|| {
+            let total: f64 = self.components.values()
+                .filter_map(|v| v.mval.as_f64())
+                .sum();
+            value.mval.as_f64()
+                .filter(|_| total > 0.0)
+                .map(|mval| mval / total * 100.0)
+        })
+    }
 }
 
 impl Default for ComponentTable {
@@ -57,6 +249,23 @@ impl Default for ComponentTable {
     }
 }
 
+/// Values computed from an analysis. These are not entered by hand.
+#[derive(Clone, PartialEq, Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Computed {
+    /// 泉質名 classified from components. None if not 療養泉.
+    pub quality: Option<String>,
+    /// Whether stored `quality` disagrees with computed one.
+    /// None if `quality` is not stored.
+    pub quality_mismatch: Option<bool>,
+    /// 浸透圧
+    pub tonicity: Option<Tonicity>,
+    /// 液性
+    pub ph_class: Option<PhClass>,
+    /// 泉温
+    pub temperature_class: Option<TemperatureClass>
+}
+
 #[derive(Clone, PartialEq, Deserialize, Serialize, Debug)]
 pub struct Analysis {
     pub id: Option<String>,                   // ID. None for new analysis.
@@ -110,7 +319,10 @@ pub struct Analysis {
     pub total: MgMvalMmol,                    // 成分総計
 
     #[serde(flatten)]
-    pub meta: HashMap<String, String>,
+    pub meta: Metadata,
+
+    #[serde(default)]
+    pub computed: Computed,
 
     #[serde(rename = "lastModified")]
     pub last_modified: Option<f64>,          // Timestamp [ms]
@@ -119,6 +331,428 @@ pub struct Analysis {
     pub created_at: Option<f64>              // Timestamp [ms]
 }
 
+impl Analysis {
+    /// Analysis without any value entered.
+    pub fn new(name: &str) -> Self {
+        Analysis {
+            id: None,
+            name: name.to_string(),
+            gensen_yield: CellValue::Null,
+            temperature: None,
+            ph: CellValue::Null,
+            positive_ion: ComponentTable::new(),
+            negative_ion: ComponentTable::new(),
+            undissociated: ComponentTable::new(),
+            gas: ComponentTable::new(),
+            minor: ComponentTable::new(),
+            total_positive_ion: MgMvalMmol::new(),
+            total_negative_ion: MgMvalMmol::new(),
+            total_undissociated: MgMvalMmol::new(),
+            total_gas: MgMvalMmol::new(),
+            total_minor: MgMvalMmol::new(),
+            total_melt: MgMvalMmol::new(),
+            total: MgMvalMmol::new(),
+            meta: Metadata::default(),
+            computed: Computed::default(),
+            last_modified: None,
+            created_at: None
+        }
+    }
+
+    /// Compute mval, mmol and mval% from mg in every component table.
+    /// Values entered by hand are kept unless `overwrite` is set.
+    pub fn derive_values(&mut self, overwrite: bool) {
+        self.positive_ion.derive(overwrite, true);
+        self.negative_ion.derive(overwrite, true);
+        self.undissociated.derive(overwrite, false);
+        self.gas.derive(overwrite, false);
+        self.minor.derive(overwrite, false);
+    }
+
+    /// Update computed values.
+    pub fn compute(&mut self) {
+        let q = quality::classify(self);
+        self.computed.quality_mismatch = self.meta.quality.as_ref()
+            .map(|stored| quality::is_mismatch(stored, q.as_deref()));
+        self.computed.quality = q;
+        self.computed.tonicity = quality::tonicity(self);
+        self.computed.ph_class = quality::ph_class(self);
+        self.computed.temperature_class = quality::temperature_class(self);
+    }
+}
+
+/**
+ * Recomputation of totals
+ */
+
+#[derive(Clone, PartialEq, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TotalDifference {
+    /// e.g. "totalPositiveIon.mval"
+    pub field: String,
+    pub submitted: CellValue,
+    pub computed: CellValue
+}
+
+fn add_cells(a: &CellValue, b: &CellValue) -> CellValue {
+    match (a.amount(), b.amount()) {
+        (Some(a), Some(b)) => CellValue::Number(a + b),
+        (Some(a), None) | (None, Some(a)) => CellValue::Number(a),
+        (None, None) => CellValue::Null
+    }
+}
+
+fn sum_cells<'a, I>(it: I) -> CellValue
+    where I: Iterator<Item = &'a CellValue>
+{
+    it.fold(CellValue::Null, |acc, v| add_cells(&acc, v))
+}
+
+impl MgMvalMmol {
+    fn sum(&self, other: &MgMvalMmol) -> MgMvalMmol {
+        MgMvalMmol {
+            mg: add_cells(&self.mg, &other.mg),
+            mval: CellValue::Null,
+            mval_percent: CellValue::Null,
+            mmol: add_cells(&self.mmol, &other.mmol)
+        }
+    }
+
+    /// Overwrite each value by `other` if it is a number.
+    fn replace_by(&mut self, other: MgMvalMmol) {
+        let MgMvalMmol { mg, mval, mval_percent, mmol } = other;
+        for (to, from) in [(&mut self.mg, mg),
+                           (&mut self.mval, mval),
+                           (&mut self.mval_percent, mval_percent),
+                           (&mut self.mmol, mmol)] {
+            if from.as_f64().is_some() {
+                *to = from;
+            }
+        }
+    }
+}
+
+impl ComponentTable {
+    /// Total row of the table. mval is summed up only for ions.
+    pub fn total(&self, with_mval: bool) -> MgMvalMmol {
+        let values = || self.components.values();
+        let mval = if with_mval {
+            sum_cells(values().map(|v| &v.mval))
+        } else {
+            CellValue::Null
+        };
+        let mval_percent = match mval.as_f64() {
+            Some(x) if x > 0.0 => CellValue::Number(100.0),
+            _ => CellValue::Null
+        };
+        MgMvalMmol {
+            mg: sum_cells(values().map(|v| &v.mg)),
+            mval,
+            mval_percent,
+            mmol: sum_cells(values().map(|v| &v.mmol))
+        }
+    }
+}
+
+impl Analysis {
+    /// Rebuild total rows from component tables.
+    /// - 溶存物質 (totalMelt) sums up ions and undissociated components,
+    ///   excluding gases.
+    /// - 成分総計 (total) is totalMelt and gases.
+    /// - Minor components are not included in totalMelt nor total.
+    ///
+    /// Totals which cannot be computed, e.g. for an empty table, are kept.
+    pub fn recompute_totals(&mut self) {
+        let positive_ion = self.positive_ion.total(true);
+        let negative_ion = self.negative_ion.total(true);
+        let undissociated = self.undissociated.total(false);
+        let gas = self.gas.total(false);
+        let minor = self.minor.total(false);
+        let melt = positive_ion.sum(&negative_ion).sum(&undissociated);
+        let total = melt.sum(&gas);
+        self.total_positive_ion.replace_by(positive_ion);
+        self.total_negative_ion.replace_by(negative_ion);
+        self.total_undissociated.replace_by(undissociated);
+        self.total_gas.replace_by(gas);
+        self.total_minor.replace_by(minor);
+        self.total_melt.replace_by(melt);
+        self.total.replace_by(total);
+    }
+
+    /// Differences of total rows between the submitted analysis and self.
+    pub fn total_differences(&self, submitted: &Analysis)
+                             -> Vec<TotalDifference> {
+        let mut v = vec![];
+        let totals = self.totals();
+        for ((name, computed), (_, submitted)) in
+            totals.iter().zip(submitted.totals().iter())
+        {
+            for ((field, c), (_, s)) in
+                cells(computed).iter().zip(cells(submitted).iter())
+            {
+                let same = match (c.as_f64(), s.as_f64()) {
+                    (Some(c), Some(s)) => (c - s).abs() < 1e-9,
+                    _ => c == s
+                };
+                if !same {
+                    v.push(TotalDifference {
+                        field: format!("{}.{}", name, field),
+                        submitted: (*s).clone(),
+                        computed: (*c).clone()
+                    });
+                }
+            }
+        }
+        v
+    }
+}
+
+/*
+ * Validation
+ */
+
+/// Allowed ion balance error [%]
+const TOLERANCE_ION_BALANCE: f64 = 5.0;
+/// Allowed relative error between sum of components and total
+const TOLERANCE_TOTAL_RELATIVE: f64 = 0.01;
+/// Allowed absolute error between sum of components and total,
+/// for rounding of each component
+const TOLERANCE_TOTAL_ABSOLUTE: f64 = 0.01;
+
+#[derive(Clone, PartialEq, Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Warning {
+    /// Positive ions and negative ions don't balance in mval.
+    #[serde(rename_all = "camelCase")]
+    IonBalance { error_percent: f64 },
+    /// Sum of components differs from the stored total.
+    #[serde(rename_all = "camelCase")]
+    TotalMismatch { field: String, sum: f64, total: f64 },
+    /// Value which cannot be negative.
+    #[serde(rename_all = "camelCase")]
+    NegativeValue { field: String, value: f64 },
+    /// Value out of the possible range.
+    #[serde(rename_all = "camelCase")]
+    OutOfRange { field: String, value: f64, min: f64, max: f64 }
+}
+
+#[derive(Clone, PartialEq, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Validation {
+    /// (cation - anion) / (cation + anion) [%]
+    pub ion_balance_error: Option<f64>,
+    pub warnings: Vec<Warning>
+}
+
+fn cells(v: &MgMvalMmol) -> [(&'static str, &CellValue); 4] {
+    [("mg", &v.mg), ("mval", &v.mval),
+     ("mvalPercent", &v.mval_percent), ("mmol", &v.mmol)]
+}
+
+/// Field of a row, by which totals are compared with their sums.
+type RowField = fn(&MgMvalMmol) -> &CellValue;
+
+fn validate_total(warnings: &mut Vec<Warning>, name: &str,
+                  table: &ComponentTable, total: &MgMvalMmol,
+                  with_mval: bool) {
+    let mut fields: Vec<(&str, &CellValue, RowField)> =
+        vec![("mg", &total.mg, |v| &v.mg), ("mmol", &total.mmol, |v| &v.mmol)];
+    if with_mval {
+        fields.push(("mval", &total.mval, |v| &v.mval));
+    }
+    for (field, total, get) in fields {
+        let total = match total.as_f64() {
+            Some(t) => t,
+            None => continue
+        };
+        let values: Vec<f64> = table.components.values()
+            .filter_map(|v| get(v).amount())
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+        let sum: f64 = values.iter().sum();
+        let tolerance = f64::max(total.abs() * TOLERANCE_TOTAL_RELATIVE,
+                                 TOLERANCE_TOTAL_ABSOLUTE);
+        if (sum - total).abs() > tolerance {
+            warnings.push(Warning::TotalMismatch {
+                field: format!("{}.{}", name, field),
+                sum,
+                total
+            });
+        }
+    }
+}
+
+impl Analysis {
+    pub fn tables(&self) -> [(&'static str, &ComponentTable); 5] {
+        [("positiveIon", &self.positive_ion),
+         ("negativeIon", &self.negative_ion),
+         ("undissociated", &self.undissociated),
+         ("gas", &self.gas),
+         ("minor", &self.minor)]
+    }
+
+    pub fn totals(&self) -> [(&'static str, &MgMvalMmol); 7] {
+        [("totalPositiveIon", &self.total_positive_ion),
+         ("totalNegativeIon", &self.total_negative_ion),
+         ("totalUndissociated", &self.total_undissociated),
+         ("totalGas", &self.total_gas),
+         ("totalMinor", &self.total_minor),
+         ("totalMelt", &self.total_melt),
+         ("total", &self.total)]
+    }
+
+    pub fn tables_mut(&mut self)
+                      -> [(&'static str, &mut ComponentTable); 5] {
+        [("positiveIon", &mut self.positive_ion),
+         ("negativeIon", &mut self.negative_ion),
+         ("undissociated", &mut self.undissociated),
+         ("gas", &mut self.gas),
+         ("minor", &mut self.minor)]
+    }
+
+    pub fn totals_mut(&mut self) -> [(&'static str, &mut MgMvalMmol); 7] {
+        [("totalPositiveIon", &mut self.total_positive_ion),
+         ("totalNegativeIon", &mut self.total_negative_ion),
+         ("totalUndissociated", &mut self.total_undissociated),
+         ("totalGas", &mut self.total_gas),
+         ("totalMinor", &mut self.total_minor),
+         ("totalMelt", &mut self.total_melt),
+         ("total", &mut self.total)]
+    }
+
+    /// (cation - anion) / (cation + anion) in mval [%]
+    pub fn ion_balance_error(&self) -> Option<f64> {
+        let positive = self.total_positive_ion.mval.as_f64()?;
+        let negative = self.total_negative_ion.mval.as_f64()?;
+        if positive + negative <= 0.0 {
+            return None;
+        }
+        Some((positive - negative) / (positive + negative) * 100.0)
+    }
+
+    /// Find inconsistent or impossible values.
+    pub fn validate(&self) -> Validation {
+        let mut warnings = vec![];
+        // Ion balance
+        let ion_balance_error = self.ion_balance_error();
+        if let Some(e) = ion_balance_error {
+            if e.abs() > TOLERANCE_ION_BALANCE {
+                warnings.push(Warning::IonBalance { error_percent: e });
+            }
+        }
+        // Sum of each table
+        validate_total(&mut warnings, "totalPositiveIon",
+                       &self.positive_ion, &self.total_positive_ion, true);
+        validate_total(&mut warnings, "totalNegativeIon",
+                       &self.negative_ion, &self.total_negative_ion, true);
+        validate_total(&mut warnings, "totalUndissociated",
+                       &self.undissociated, &self.total_undissociated, false);
+        validate_total(&mut warnings, "totalGas",
+                       &self.gas, &self.total_gas, false);
+        validate_total(&mut warnings, "totalMinor",
+                       &self.minor, &self.total_minor, false);
+        // Impossible values
+        let mut rows: Vec<(String, &MgMvalMmol)> = vec![];
+        for (name, table) in self.tables().iter() {
+            for (key, value) in table.components.iter() {
+                rows.push((format!("{}.{}", name, key), value));
+            }
+        }
+        for (name, total) in self.totals().iter() {
+            rows.push((name.to_string(), total));
+        }
+        for (name, row) in rows {
+            for (field, cell) in cells(row).iter() {
+                match cell.as_f64() {
+                    Some(x) if x < 0.0 =>
+                        warnings.push(Warning::NegativeValue {
+                            field: format!("{}.{}", name, field),
+                            value: x
+                        }),
+                    Some(x) if *field == "mvalPercent" && x > 100.0 =>
+                        warnings.push(Warning::OutOfRange {
+                            field: format!("{}.{}", name, field),
+                            value: x, min: 0.0, max: 100.0
+                        }),
+                    _ => ()
+                }
+            }
+        }
+        if let Some(ph) = self.ph.as_f64() {
+            if !(0.0..=14.0).contains(&ph) {
+                warnings.push(Warning::OutOfRange {
+                    field: "pH".to_string(), value: ph, min: 0.0, max: 14.0
+                });
+            }
+        }
+        for (field, value, max) in
+            [("latitude", self.meta.latitude, 90.0),
+             ("longitude", self.meta.longitude, 180.0)].iter()
+        {
+            match value {
+                Some(x) if x.abs() > *max =>
+                    warnings.push(Warning::OutOfRange {
+                        field: field.to_string(), value: *x,
+                        min: -max, max: *max
+                    }),
+                _ => ()
+            }
+        }
+        if let Some(y) = self.gensen_yield.as_f64() {
+            if y < 0.0 {
+                warnings.push(Warning::NegativeValue {
+                    field: "yield".to_string(), value: y
+                });
+            }
+        }
+        Validation {
+            ion_balance_error,
+            warnings
+        }
+    }
+}
+
+/*
+ * Fingerprint to find springs of similar composition
+ */
+
+/// Ions whose mval% compose a fingerprint.
+pub const FINGERPRINT_POSITIVE_IONS: [&str; 6] =
+    ["Na", "K", "Ca", "Mg", "FeII", "H"];
+pub const FINGERPRINT_NEGATIVE_IONS: [&str; 6] =
+    ["Cl", "HCO3", "SO4", "CO3", "HS", "F"];
+/// Ions, temperature and pH
+pub const FINGERPRINT_DIMENSIONS: usize =
+    FINGERPRINT_POSITIVE_IONS.len() + FINGERPRINT_NEGATIVE_IONS.len() + 2;
+
+impl Analysis {
+    /// Vector of mval% of major ions, temperature and pH, each scaled into
+    /// [0, 1]. None unless ions, temperature and pH are all available.
+    pub fn fingerprint(&self) -> Option<Vec<f64>> {
+        let mut v = Vec::with_capacity(FINGERPRINT_DIMENSIONS);
+        let mut has_ion = false;
+        for (table, keys) in [(&self.positive_ion, &FINGERPRINT_POSITIVE_IONS),
+                              (&self.negative_ion, &FINGERPRINT_NEGATIVE_IONS)]
+            .iter()
+        {
+            for key in keys.iter() {
+                let p = table.mval_percent(key);
+                has_ion |= p.is_some();
+                v.push(p.unwrap_or(0.0).clamp(0.0, 100.0) / 100.0);
+            }
+        }
+        if !has_ion {
+            return None;
+        }
+        // Up to 100℃
+        v.push(self.temperature?.clamp(0.0, 100.0) / 100.0);
+        v.push(self.ph.as_f64()?.clamp(0.0, 14.0) / 14.0);
+        Some(v)
+    }
+}
+
 /**
  * Conversions from JSON to MgMvalMmol.
  */
@@ -450,18 +1084,57 @@ mod tests {
 
     #[test]
     fn it_serialize_cellvalue_text() {
-        let it = CellValue::Text("微量".to_string());
+        let it = CellValue::Text("--".to_string());
         let r = serde_json::to_string(&it);
         assert!(r.is_ok());
-        assert_eq!(r.unwrap(), r#""微量""#);
+        assert_eq!(r.unwrap(), r#""--""#);
     }
 
     #[test]
     fn it_deserialize_cellvalue_text() {
-        let it = r#""微量""#;
+        let it = r#""--""#;
         let r = serde_json::from_str::<CellValue>(it);
         assert!(r.is_ok());
-        assert_eq!(r.unwrap(), CellValue::Text("微量".to_string()));
+        assert_eq!(r.unwrap(), CellValue::Text("--".to_string()));
+    }
+
+    #[test]
+    fn it_serialize_cellvalue_detection_limit() {
+        let r = serde_json::to_string(&vec![
+            CellValue::BelowLimit {threshold: 0.1, text: "<0.1".to_string()},
+            CellValue::Trace("微量".to_string()),
+            CellValue::NotDetected("検出せず".to_string())
+        ]);
+        assert!(r.is_ok());
+        assert_eq!(r.unwrap(), r#"["<0.1","微量","検出せず"]"#);
+    }
+
+    #[test]
+    fn it_deserialize_cellvalue_detection_limit() {
+        let it = r#"["<0.1", "＜0.05", "0.2未満", "微量", "検出せず", "N.D."]"#;
+        let r = serde_json::from_str::<Vec<CellValue>>(it);
+        assert!(r.is_ok());
+        let below = |threshold: f64, text: &str| CellValue::BelowLimit {
+            threshold,
+            text: text.to_string()
+        };
+        let r = r.unwrap();
+        assert_eq!(r, vec![below(0.1, "<0.1"),
+                           below(0.05, "＜0.05"),
+                           below(0.2, "0.2未満"),
+                           CellValue::Trace("微量".to_string()),
+                           CellValue::NotDetected("検出せず".to_string()),
+                           CellValue::NotDetected("N.D.".to_string())]);
+        assert!(r.iter().all(|v| v.amount() == Some(0.0)));
+        assert_eq!(CellValue::Text("--".to_string()).amount(), None);
+    }
+
+    #[test]
+    fn it_keep_text_of_cellvalue() {
+        let it = r#"["<0.10","不検出","ND","0.2未満","--"]"#;
+        let r = serde_json::from_str::<Vec<CellValue>>(it).unwrap();
+        assert_ne!(r[1], r[2]);
+        assert_eq!(serde_json::to_string(&r).unwrap(), it);
     }
 
     #[test]
@@ -485,7 +1158,7 @@ mod tests {
         let it = MgMvalMmol {
             mg: CellValue::Number(484.),
             mval: CellValue::Number(21.1),
-            mval_percent: CellValue::Text("微量".to_string()),
+            mval_percent: CellValue::Trace("微量".to_string()),
             mmol: CellValue::Null
         };
         let r = serde_json::to_string(&it);
@@ -503,7 +1176,7 @@ mod tests {
                    MgMvalMmol {
                        mg: CellValue::Number(484.),
                        mval: CellValue::Number(21.1),
-                       mval_percent: CellValue::Text("微量".to_string()),
+                       mval_percent: CellValue::Trace("微量".to_string()),
                        mmol: CellValue::Null
                    });
     }
@@ -589,9 +1262,10 @@ mod tests {
             mval_percent: CellValue::Null,
             mmol: CellValue::Null
         };
-        let mut meta = HashMap::new();
-        meta.insert("quality".to_string(),
-                    "含硫黄ーナトリウムー塩化物泉".to_string());
+        let meta = Metadata {
+            quality: Some("含硫黄ーナトリウムー塩化物泉".to_string()),
+            ..Default::default()
+        };
         let it = Analysis {
             id: None,
             name: "雨中温泉".to_string(),
@@ -608,12 +1282,17 @@ mod tests {
             total_undissociated: MgMvalMmol::new(),
             total_gas: MgMvalMmol::new(),
             total_minor: MgMvalMmol::new(),
-            meta: meta
+            total_melt: MgMvalMmol::new(),
+            total: MgMvalMmol::new(),
+            meta,
+            computed: Computed::default(),
+            last_modified: None,
+            created_at: None
         };
         let r = serde_json::to_string(&it);
         assert!(r.is_ok());
         assert_eq!(r.unwrap(),
-                   r#"{"id":null,"name":"雨中温泉","yield":1080.0,"temperature":44.6,"pH":7.9,"positiveIon":{"Na":{"mg":484.0,"mval":21.1,"mvalPercent":97.01,"mmol":""}},"negativeIon":{"Cl":{"mg":152.0,"mval":4.29,"mvalPercent":18.76,"mmol":null}},"undissociated":{},"gas":{},"minor":{},"totalPositiveIon":{"mg":484.0,"mval":21.1,"mvalPercent":null,"mmol":null},"totalNegativeIon":{"mg":152.0,"mval":4.29,"mvalPercent":null,"mmol":null},"totalUndissociated":{"mg":null,"mval":null,"mvalPercent":null,"mmol":null},"totalGas":{"mg":null,"mval":null,"mvalPercent":null,"mmol":null},"totalMinor":{"mg":null,"mval":null,"mvalPercent":null,"mmol":null},"quality":"含硫黄ーナトリウムー塩化物泉"}"#);
+                   r#"{"id":null,"name":"雨中温泉","yield":1080.0,"temperature":44.6,"pH":7.9,"positiveIon":{"Na":{"mg":484.0,"mval":21.1,"mvalPercent":97.01,"mmol":""}},"negativeIon":{"Cl":{"mg":152.0,"mval":4.29,"mvalPercent":18.76,"mmol":null}},"undissociated":{},"gas":{},"minor":{},"totalPositiveIon":{"mg":484.0,"mval":21.1,"mvalPercent":null,"mmol":null},"totalNegativeIon":{"mg":152.0,"mval":4.29,"mvalPercent":null,"mmol":null},"totalUndissociated":{"mg":null,"mval":null,"mvalPercent":null,"mmol":null},"totalGas":{"mg":null,"mval":null,"mvalPercent":null,"mmol":null},"totalMinor":{"mg":null,"mval":null,"mvalPercent":null,"mmol":null},"totalMelt":{"mg":null,"mval":null,"mvalPercent":null,"mmol":null},"total":{"mg":null,"mval":null,"mvalPercent":null,"mmol":null},"quality":"含硫黄ーナトリウムー塩化物泉","computed":{"quality":null,"qualityMismatch":null,"tonicity":null,"phClass":null,"temperatureClass":null},"lastModified":null,"createdAt":null}"#);
     }
 
     #[test]
@@ -654,6 +1333,12 @@ mod tests {
             "totalMinor": {
                 "mg": null,"mval": null,"mvalPercent": null,"mmol": null
             },
+            "totalMelt": {
+                "mg": null,"mval": null,"mvalPercent": null,"mmol": null
+            },
+            "total": {
+                "mg": null,"mval": null,"mvalPercent": null,"mmol": null
+            },
             "quality": "含硫黄ーナトリウムー塩化物泉"
         }"#;
         let r = serde_json::from_str::<Analysis>(it);
@@ -685,9 +1370,10 @@ mod tests {
             mval_percent: CellValue::Null,
             mmol: CellValue::Null
         };
-        let mut meta = HashMap::new();
-        meta.insert("quality".to_string(),
-                    "含硫黄ーナトリウムー塩化物泉".to_string());
+        let meta = Metadata {
+            quality: Some("含硫黄ーナトリウムー塩化物泉".to_string()),
+            ..Default::default()
+        };
         let analysis = Analysis {
             id: None,
             name: "雨中温泉".to_string(),
@@ -704,9 +1390,219 @@ mod tests {
             total_undissociated: MgMvalMmol::new(),
             total_gas: MgMvalMmol::new(),
             total_minor: MgMvalMmol::new(),
-            meta: meta
+            total_melt: MgMvalMmol::new(),
+            total: MgMvalMmol::new(),
+            meta,
+            computed: Computed::default(),
+            last_modified: None,
+            created_at: None
         };
         assert_eq!(r.unwrap(), analysis);
     }
 
+    #[test]
+    fn it_derive_componenttable() {
+        let mut components = HashMap::new();
+        components.insert("Na".to_string(), MgMvalMmol {
+            mg: CellValue::Number(22.99),
+            mval: CellValue::Null,
+            mval_percent: CellValue::Null,
+            mmol: CellValue::Text("".to_string())
+        });
+        components.insert("Ca".to_string(), MgMvalMmol {
+            mg: CellValue::Number(40.078),
+            mval: CellValue::Number(3.0),
+            mval_percent: CellValue::Null,
+            mmol: CellValue::Null
+        });
+        let mut it = ComponentTable { components };
+        it.derive(false, true);
+        let na = it.components.get("Na").unwrap();
+        assert_eq!(na.mval, CellValue::Number(1.0));
+        assert_eq!(na.mmol, CellValue::Number(1.0));
+        assert_eq!(na.mval_percent, CellValue::Number(25.0));
+        // Hand-entered value is kept
+        let ca = it.components.get("Ca").unwrap();
+        assert_eq!(ca.mval, CellValue::Number(3.0));
+        assert_eq!(ca.mval_percent, CellValue::Number(75.0));
+
+        it.derive(true, true);
+        let ca = it.components.get("Ca").unwrap();
+        assert_eq!(ca.mval, CellValue::Number(2.0));
+        let p = ca.mval_percent.as_f64().unwrap();
+        assert!((p - 200.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_validate_analysis() {
+        let mut positive_ion = HashMap::new();
+        positive_ion.insert("Na".to_string(), MgMvalMmol {
+            mg: CellValue::Number(484.),
+            mval: CellValue::Number(21.1),
+            mval_percent: CellValue::Number(97.01),
+            mmol: CellValue::Null
+        });
+        let mut negative_ion = HashMap::new();
+        negative_ion.insert("Cl".to_string(), MgMvalMmol {
+            mg: CellValue::Number(-152.0),
+            mval: CellValue::Number(4.29),
+            mval_percent: CellValue::Number(18.76),
+            mmol: CellValue::Null
+        });
+        let mut total_positive_ion = MgMvalMmol::new();
+        total_positive_ion.mg = CellValue::Number(500.0);
+        total_positive_ion.mval = CellValue::Number(21.1);
+        let mut total_negative_ion = MgMvalMmol::new();
+        total_negative_ion.mval = CellValue::Number(4.29);
+        let it = Analysis {
+            id: None,
+            name: "雨中温泉".to_string(),
+            gensen_yield: CellValue::Null,
+            temperature: Some(44.6),
+            ph: CellValue::Number(15.0),
+            positive_ion: ComponentTable { components: positive_ion },
+            negative_ion: ComponentTable { components: negative_ion },
+            undissociated: ComponentTable::new(),
+            gas: ComponentTable::new(),
+            minor: ComponentTable::new(),
+            total_positive_ion,
+            total_negative_ion,
+            total_undissociated: MgMvalMmol::new(),
+            total_gas: MgMvalMmol::new(),
+            total_minor: MgMvalMmol::new(),
+            total_melt: MgMvalMmol::new(),
+            total: MgMvalMmol::new(),
+            meta: Metadata::default(),
+            computed: Computed::default(),
+            last_modified: None,
+            created_at: None
+        };
+        let r = it.validate();
+        let e = r.ion_balance_error.unwrap();
+        assert!((e - 66.2).abs() < 0.1);
+        assert!(r.warnings.contains(&Warning::IonBalance { error_percent: e }));
+        assert!(r.warnings.contains(&Warning::TotalMismatch {
+            field: "totalPositiveIon.mg".to_string(), sum: 484.0, total: 500.0
+        }));
+        assert!(r.warnings.contains(&Warning::NegativeValue {
+            field: "negativeIon.Cl.mg".to_string(), value: -152.0
+        }));
+        assert!(r.warnings.contains(&Warning::OutOfRange {
+            field: "pH".to_string(), value: 15.0, min: 0.0, max: 14.0
+        }));
+        assert_eq!(r.warnings.len(), 4);
+    }
+
+    #[test]
+    fn it_recompute_totals() {
+        let mut positive_ion = HashMap::new();
+        positive_ion.insert("Na".to_string(), MgMvalMmol {
+            mg: CellValue::Number(484.),
+            mval: CellValue::Number(21.1),
+            mval_percent: CellValue::Number(100.0),
+            mmol: CellValue::Number(21.1)
+        });
+        let mut negative_ion = HashMap::new();
+        negative_ion.insert("Cl".to_string(), MgMvalMmol {
+            mg: CellValue::Number(152.0),
+            mval: CellValue::Number(4.29),
+            mval_percent: CellValue::Number(100.0),
+            mmol: CellValue::Number(4.29)
+        });
+        let mut gas = HashMap::new();
+        gas.insert("CO2".to_string(), MgMvalMmol {
+            mg: CellValue::Number(44.0),
+            mval: CellValue::Null,
+            mval_percent: CellValue::Null,
+            mmol: CellValue::Number(1.0)
+        });
+        let mut total_gas = MgMvalMmol::new();
+        total_gas.mg = CellValue::Text("-".to_string());
+        let submitted = Analysis {
+            id: None,
+            name: "雨中温泉".to_string(),
+            gensen_yield: CellValue::Null,
+            temperature: Some(44.6),
+            ph: CellValue::Number(7.9),
+            positive_ion: ComponentTable { components: positive_ion },
+            negative_ion: ComponentTable { components: negative_ion },
+            undissociated: ComponentTable::new(),
+            gas: ComponentTable { components: gas },
+            minor: ComponentTable::new(),
+            total_positive_ion: MgMvalMmol::new(),
+            total_negative_ion: MgMvalMmol::new(),
+            total_undissociated: MgMvalMmol::new(),
+            total_gas,
+            total_minor: MgMvalMmol::new(),
+            total_melt: MgMvalMmol::new(),
+            total: MgMvalMmol::new(),
+            meta: Metadata::default(),
+            computed: Computed::default(),
+            last_modified: None,
+            created_at: None
+        };
+        let mut it = submitted.clone();
+        it.recompute_totals();
+        assert_eq!(it.total_positive_ion.mval, CellValue::Number(21.1));
+        assert_eq!(it.total_melt.mg, CellValue::Number(636.0));
+        assert_eq!(it.total_melt.mval, CellValue::Null);
+        assert_eq!(it.total.mg, CellValue::Number(680.0));
+        assert_eq!(it.total_undissociated.mg, CellValue::Null);
+
+        let d = it.total_differences(&submitted);
+        assert!(d.contains(&TotalDifference {
+            field: "totalGas.mg".to_string(),
+            submitted: CellValue::Text("-".to_string()),
+            computed: CellValue::Number(44.0)
+        }));
+        assert!(d.iter().all(|d| !d.field.starts_with("totalUndissociated")));
+    }
+
+    #[test]
+    fn it_compute_fingerprint() {
+        let mut positive_ion = HashMap::new();
+        positive_ion.insert("Na".to_string(), MgMvalMmol {
+            mg: CellValue::Number(484.),
+            mval: CellValue::Number(21.0),
+            mval_percent: CellValue::Null,
+            mmol: CellValue::Null
+        });
+        positive_ion.insert("Ca".to_string(), MgMvalMmol {
+            mg: CellValue::Number(140.),
+            mval: CellValue::Number(7.0),
+            mval_percent: CellValue::Null,
+            mmol: CellValue::Null
+        });
+        let mut it = Analysis {
+            id: None,
+            name: "雨中温泉".to_string(),
+            gensen_yield: CellValue::Null,
+            temperature: Some(50.0),
+            ph: CellValue::Number(7.0),
+            positive_ion: ComponentTable { components: positive_ion },
+            negative_ion: ComponentTable::new(),
+            undissociated: ComponentTable::new(),
+            gas: ComponentTable::new(),
+            minor: ComponentTable::new(),
+            total_positive_ion: MgMvalMmol::new(),
+            total_negative_ion: MgMvalMmol::new(),
+            total_undissociated: MgMvalMmol::new(),
+            total_gas: MgMvalMmol::new(),
+            total_minor: MgMvalMmol::new(),
+            total_melt: MgMvalMmol::new(),
+            total: MgMvalMmol::new(),
+            meta: Metadata::default(),
+            computed: Computed::default(),
+            last_modified: None,
+            created_at: None
+        };
+        let v = it.fingerprint().unwrap();
+        assert_eq!(v.len(), FINGERPRINT_DIMENSIONS);
+        assert!((v[0] - 0.75).abs() < 1e-9);
+        assert!((v[2] - 0.25).abs() < 1e-9);
+        assert_eq!(v[FINGERPRINT_DIMENSIONS - 2], 0.5);
+        assert_eq!(v[FINGERPRINT_DIMENSIONS - 1], 0.5);
+        it.temperature = None;
+        assert!(it.fingerprint().is_none());
+    }
 }
@@ -1,23 +1,48 @@
 use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
 
 use crate::comment_cli;
 use crate::analysis::Analysis;
+use crate::quality::{Tonicity, PhClass, TemperatureClass};
 use crate::comment::Comment;
+use crate::geo::{GeoPoint, BoundingBox, Distance};
 use crate::models::{
     Models,
-    analyses
+    analyses,
+    analyses_history
 };
+use crate::tabular;
 use crate::utils::elasticsearch::{self, Scroll};
 use crate::utils::scrub::scrub;
 
+/// Recorded in the history as who saved analyses.
+const SAVED_BY: &str = "cli";
+const REVISIONS_LIMIT: u32 = 1000;
+
 #[derive(StructOpt, Debug)]
 pub enum Action {
     /// Delete analysis and comments on the analysis
     Delete(DeleteArgs),
     /// Try scrub
     Scrub(ScrubArgs),
+    /// Import analyses from a file
+    Import(ImportArgs),
+    /// Export analyses as CSV, TSV or JSON lines
+    Export(ExportArgs),
+    /// List previous versions of an analysis
+    Revisions(RevisionsArgs),
+    /// Save a previous version of an analysis as the latest
+    Restore(RestoreArgs),
+    /// Add analyzers and fields missing from the index of analyses
+    Migrate,
+    /// Rewrite stored analyses to fill fields added to the index
+    Backfill,
+    /// Back up analyses, then recreate their index and import them again
+    Reindex(ReindexArgs),
     All
 }
 
@@ -28,6 +53,31 @@ pub struct DeleteArgs {
     pub id: String
 }
 
+#[derive(StructOpt, Debug)]
+pub struct RevisionsArgs {
+    /// Analysis ID
+    #[structopt(short, long)]
+    pub id: String
+}
+
+#[derive(StructOpt, Debug)]
+pub struct RestoreArgs {
+    /// Analysis ID
+    #[structopt(short, long)]
+    pub id: String,
+    /// Revision number as listed by `analysis revisions`
+    #[structopt(short, long)]
+    pub revision: u32
+}
+
+#[derive(StructOpt, Debug)]
+pub struct ReindexArgs {
+    /// JSON lines file to back up analyses into, importable by
+    /// `analysis import --jsonl`
+    #[structopt(long, parse(from_os_str))]
+    pub backup: PathBuf
+}
+
 #[derive(StructOpt, Debug)]
 pub struct ScrubArgs {
     /// Name
@@ -35,6 +85,76 @@ pub struct ScrubArgs {
     pub name: String
 }
 
+#[derive(StructOpt, Debug)]
+pub struct ImportArgs {
+    /// CSV file having one analysis per row, with columns such as
+    /// "name", "temperature" and "positiveIon.Na.mg"
+    #[structopt(long, parse(from_os_str))]
+    pub csv: Option<PathBuf>,
+    /// JSON lines file as written by `analysis all`
+    #[structopt(long, parse(from_os_str))]
+    pub jsonl: Option<PathBuf>,
+    /// How to treat analyses in JSON lines whose id already exists:
+    /// upsert, insert-only or skip-existing
+    #[structopt(long, default_value = "insert-only")]
+    pub mode: analyses::ImportMode,
+    /// Check rows without saving
+    #[structopt(long)]
+    pub dry_run: bool
+}
+
+#[derive(StructOpt, Debug)]
+pub struct ExportArgs {
+    /// csv, tsv or jsonl
+    #[structopt(long, default_value = "csv")]
+    pub format: tabular::ExportFormat,
+    /// Comma separated column names, e.g. "name,positiveIon.Na.mg"
+    #[structopt(long, use_delimiter = true)]
+    pub columns: Option<Vec<String>>,
+    /// Search text
+    #[structopt(short, long)]
+    pub query: Option<String>,
+    /// 低張性, 等張性 or 高張性
+    #[structopt(long)]
+    pub tonicity: Option<Tonicity>,
+    /// e.g. 中性, アルカリ性
+    #[structopt(long)]
+    pub ph_class: Option<PhClass>,
+    /// e.g. 冷鉱泉, 高温泉
+    #[structopt(long)]
+    pub temperature_class: Option<TemperatureClass>,
+    /// Lowest 泉温 in ℃
+    #[structopt(long)]
+    pub temperature_min: Option<f64>,
+    /// Highest 泉温 in ℃
+    #[structopt(long)]
+    pub temperature_max: Option<f64>,
+    /// Lowest pH
+    #[structopt(long)]
+    pub ph_min: Option<f64>,
+    /// Highest pH
+    #[structopt(long)]
+    pub ph_max: Option<f64>,
+    /// Text contained in 泉質
+    #[structopt(long)]
+    pub quality: Option<String>,
+    /// Minimum mg of components, e.g. "CO2:250,FeII:20"
+    #[structopt(long, use_delimiter = true)]
+    pub mg: Vec<analyses::ComponentFilter>,
+    /// "lat,lon"
+    #[structopt(long)]
+    pub near: Option<GeoPoint>,
+    /// e.g. "10km", effective with --near
+    #[structopt(long, default_value = "10km")]
+    pub radius: Distance,
+    /// "west,south,east,north"
+    #[structopt(long)]
+    pub bbox: Option<BoundingBox>,
+    /// Output file. Written to stdout if omitted.
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: Option<PathBuf>
+}
+
 async fn analysis_delete(args: &DeleteArgs) {
     let db = elasticsearch::get_unpooled_connection();
     if db.is_err() {
@@ -65,6 +185,168 @@ async fn analysis_delete(args: &DeleteArgs) {
     }
 }
 
+async fn analysis_revisions(args: &RevisionsArgs) {
+    let db = match elasticsearch::get_unpooled_connection() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to get connection, error: {}", e);
+            return;
+        }
+    };
+    let models = Models::new(&db);
+    match analyses_history::select(&models, &args.id, REVISIONS_LIMIT).await {
+        Ok((total, revisions)) => {
+            for r in revisions {
+                println!("{}\t{}\t{}", r.revision,
+                         r.saved_at.map_or(String::new(), |t| t.to_string()),
+                         r.saved_by.unwrap_or_default());
+            }
+            println!("{} revisions", total);
+        },
+        Err(e) => error!("Failed to list revisions: {}, e: {}", &args.id, &e)
+    }
+}
+
+async fn analysis_restore(args: &RestoreArgs) {
+    let db = match elasticsearch::get_unpooled_connection() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to get connection, error: {}", e);
+            return;
+        }
+    };
+    let models = Models::new(&db);
+    let options = analyses::SaveOptions {
+        saved_by: Some(SAVED_BY.to_string()),
+        ..Default::default()
+    };
+    match analyses::restore(&models, &args.id, args.revision, &options).await {
+        Ok(Some((_, version))) =>
+            println!("Restored revision {} of {}, version: {}",
+                     args.revision, &args.id, version),
+        Ok(None) => println!("No revision {} of {}", args.revision, &args.id),
+        Err(e) => error!("Failed to restore analysis: {}, e: {}",
+                         &args.id, &e)
+    }
+}
+
+async fn analysis_migrate() {
+    let db = match elasticsearch::get_unpooled_connection() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to get connection, error: {}", e);
+            return;
+        }
+    };
+    let models = Models::new(&db);
+    match analyses::migrate(&models).await {
+        Ok(result) => println!("{}", result),
+        Err(e) => error!("Failed to migrate index of analyses, error: {}", e)
+    }
+}
+
+/// All stored analyses.
+async fn scroll_analyses<'a>(models: &Models<'a>)
+                             -> Result<Vec<Analysis>, String> {
+    let mut items = vec![];
+    let mut result = models.analyses.scroll(None).await
+        .map_err(|e| format!("{}", e))?;
+    while !result.hits.hits.is_empty() {
+        for row in &result.hits.hits {
+            items.push(Analysis::try_from(row)?);
+        }
+        let scroll_id = result._scroll_id.ok_or("missing scroll id")?;
+        result = models.analyses.scroll(Some(scroll_id.as_str())).await
+            .map_err(|e| format!("{}", e))?;
+    }
+    Ok(items)
+}
+
+async fn analysis_backfill() {
+    let db = match elasticsearch::get_unpooled_connection() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to get connection, error: {}", e);
+            return;
+        }
+    };
+    let models = Models::new(&db);
+    let items = match scroll_analyses(&models).await {
+        Ok(items) => items,
+        Err(e) => {
+            error!("Failed to read analyses, error: {}", e);
+            return;
+        }
+    };
+    let (mut succeeded, mut failed) = (0, 0);
+    for id in items.iter().filter_map(|a| a.id.as_ref()) {
+        match analyses::backfill(&models, id).await {
+            Ok(_) => succeeded += 1,
+            Err(e) => {
+                println!("{}: error: {}", id, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("Rewrote {} analyses, {} failed", succeeded, failed);
+}
+
+async fn analysis_reindex(args: &ReindexArgs) {
+    let db = match elasticsearch::get_unpooled_connection() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to get connection, error: {}", e);
+            return;
+        }
+    };
+    let models = Models::new(&db);
+    let items = match scroll_analyses(&models).await {
+        Ok(items) => items,
+        Err(e) => {
+            error!("Failed to read analyses, error: {}", e);
+            return;
+        }
+    };
+    let lines = items.iter()
+        .map(|a| serde_json::to_string(a).map(|l| l + "\n"))
+        .collect::<Result<String, serde_json::Error>>();
+    let written = lines.map_err(|e| format!("{}", e))
+        .and_then(|text| {
+            std::fs::write(&args.backup, text).map_err(|e| format!("{}", e))
+        });
+    if let Err(e) = written {
+        error!("Failed to back up analyses to {:?}, error: {}",
+               &args.backup, e);
+        return;
+    }
+    println!("Backed up {} analyses to {:?}", items.len(), &args.backup);
+    if let Err(e) = analyses::teardown(&models).await {
+        error!("Failed to delete index of analyses, error: {}", e);
+        return;
+    }
+    match analyses::setup(&models).await {
+        Ok(result) => info!("Set up index of analyses, result: {}", result),
+        Err(e) => {
+            error!("Failed to set up index of analyses, error: {}, \
+                    import {:?} after fixing it", e, &args.backup);
+            return;
+        }
+    }
+    let (mut succeeded, mut failed) = (0, 0);
+    for a in &items {
+        match analyses::import(&models, a, analyses::ImportMode::InsertOnly)
+            .await
+        {
+            Ok(_) => succeeded += 1,
+            Err(e) => {
+                println!("{}: error: {}", a.id.as_deref().unwrap_or(""), e);
+                failed += 1;
+            }
+        }
+    }
+    println!("Reindexed {} analyses, {} failed", succeeded, failed);
+}
+
 fn analysis_scrub(args: &ScrubArgs) {
     let s = scrub(&args.name);
     println!("name: {n}, scrub: {s}", n=&args.name, s=&s);
@@ -101,6 +383,208 @@ fn analysis_all() {
     })
 }
 
+/// Analysis read from a line of CSV, or why it could not be read.
+type CsvRow = (u64, Result<Analysis, String>);
+
+/// Read analyses from CSV together with their line numbers.
+fn read_csv(path: &PathBuf) -> Result<Vec<CsvRow>, String> {
+    let mut reader = csv::Reader::from_path(path)
+        .map_err(|e| format!("{}", e))?;
+    let headers = reader.headers().map_err(|e| format!("{}", e))?.clone();
+    let mut rows = vec![];
+    for record in reader.records() {
+        let row = match record {
+            Ok(record) => {
+                let line = record.position().map_or(0, |p| p.line());
+                (line, tabular::from_row(headers.iter().zip(record.iter())))
+            },
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                (line, Err(format!("{}", e)))
+            }
+        };
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+async fn analysis_import_csv(args: &ImportArgs, path: &PathBuf) {
+    let rows = match read_csv(path) {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("Failed to read {:?}, error: {}", path, e);
+            return;
+        }
+    };
+    let db = if args.dry_run {
+        None
+    } else {
+        match elasticsearch::get_unpooled_connection() {
+            Ok(db) => Some(db),
+            Err(e) => {
+                println!("Failed to get connection, error: {}", e);
+                return;
+            }
+        }
+    };
+    let models = db.as_ref().map(Models::new);
+    let options = analyses::SaveOptions {
+        saved_by: Some(SAVED_BY.to_string()),
+        ..Default::default()
+    };
+    let (mut succeeded, mut failed) = (0, 0);
+    for (line, row) in rows {
+        let a = match row {
+            Ok(a) => a,
+            Err(e) => {
+                println!("line {}: error: {}", line, e);
+                failed += 1;
+                continue;
+            }
+        };
+        match &models {
+            None => {
+                println!("line {}: ok: {}", line, &a.name);
+                succeeded += 1;
+            },
+            Some(models) => match analyses::save(models, &a, &options).await {
+                Ok((a, _)) => {
+                    println!("line {}: saved: {}", line,
+                             a.id.as_ref().map_or("", |id| id.as_str()));
+                    succeeded += 1;
+                },
+                Err(e) => {
+                    println!("line {}: error: {}", line, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    println!("{} {} rows, {} failed",
+             if args.dry_run { "Checked" } else { "Imported" },
+             succeeded, failed);
+}
+
+async fn analysis_import_jsonl(args: &ImportArgs, path: &PathBuf) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Failed to read {:?}, error: {}", path, e);
+            return;
+        }
+    };
+    // Dry runs read the index too, to tell which ids exist
+    let db = match elasticsearch::get_unpooled_connection() {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Failed to get connection, error: {}", e);
+            return;
+        }
+    };
+    let models = Models::new(&db);
+    let (mut created, mut updated, mut skipped, mut failed) = (0, 0, 0, 0);
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line_no = i + 1;
+        let a = line
+            .map_err(|e| format!("{}", e))
+            .and_then(|l| {
+                serde_json::from_str::<Analysis>(&l)
+                    .map_err(|e| format!("{}", e))
+            });
+        let a = match a {
+            Ok(a) => a,
+            Err(e) => {
+                println!("line {}: error: {}", line_no, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let result = if args.dry_run {
+            analyses::check_import(&models, &a, args.mode).await
+        } else {
+            analyses::import(&models, &a, args.mode).await
+        };
+        match result {
+            Ok(analyses::ImportResult::Created(id)) => {
+                println!("line {}: created: {}", line_no, id);
+                created += 1;
+            },
+            Ok(analyses::ImportResult::Updated(id)) => {
+                println!("line {}: updated: {}", line_no, id);
+                updated += 1;
+            },
+            Ok(analyses::ImportResult::Skipped(id)) => {
+                println!("line {}: skipped: {}", line_no, id);
+                skipped += 1;
+            },
+            Err(e) => {
+                println!("line {}: error: {}", line_no, e);
+                failed += 1;
+            }
+        }
+    }
+    if args.dry_run {
+        print!("Dry run, nothing written. ");
+    }
+    println!("Created {}, updated {}, skipped {}, failed {}",
+             created, updated, skipped, failed);
+}
+
+async fn analysis_import(args: &ImportArgs) {
+    match (&args.csv, &args.jsonl) {
+        (Some(path), None) => analysis_import_csv(args, path).await,
+        (None, Some(path)) => analysis_import_jsonl(args, path).await,
+        _ => println!("Specify either --csv or --jsonl")
+    }
+}
+
+async fn analysis_export(args: &ExportArgs) {
+    let db = match elasticsearch::get_unpooled_connection() {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Failed to get connection, error: {}", e);
+            return;
+        }
+    };
+    let models = Models::new(&db);
+    let options = analyses::SelectOptions {
+        query: args.query.clone(),
+        tonicity: args.tonicity,
+        ph_class: args.ph_class,
+        temperature_class: args.temperature_class,
+        temperature: analyses::Range::new(args.temperature_min,
+                                          args.temperature_max),
+        ph: analyses::Range::new(args.ph_min, args.ph_max),
+        quality: args.quality.clone(),
+        components: args.mg.clone(),
+        near: args.near,
+        radius: args.radius,
+        bbox: args.bbox,
+        skip: 0,
+        limit: 0,
+        after: None,
+        order_by: analyses::SortKey::Id,
+        direction: 1
+    };
+    let result = analyses::select_all(&models, &options).await
+        .and_then(|items| {
+            tabular::export(&items, args.format, args.columns.as_deref())
+        });
+    let text = match result {
+        Ok(text) => text,
+        Err(e) => {
+            error!("Failed to export analyses, error: {}", e);
+            return;
+        }
+    };
+    match &args.output {
+        Some(path) => if let Err(e) = std::fs::write(path, text) {
+            error!("Failed to write {:?}, error: {}", path, e);
+        },
+        None => print!("{}", text)
+    }
+}
+
 pub fn run(args: &Action) {
     // TODO Use setup_logger
     env_logger::init();
@@ -111,6 +595,13 @@ pub fn run(args: &Action) {
     match args {
         Action::Delete(args) => rt.block_on(analysis_delete(&args)),
         Action::Scrub(args) => analysis_scrub(&args),
+        Action::Import(args) => rt.block_on(analysis_import(args)),
+        Action::Export(args) => rt.block_on(analysis_export(args)),
+        Action::Revisions(args) => rt.block_on(analysis_revisions(args)),
+        Action::Restore(args) => rt.block_on(analysis_restore(args)),
+        Action::Migrate => rt.block_on(analysis_migrate()),
+        Action::Backfill => rt.block_on(analysis_backfill()),
+        Action::Reindex(args) => rt.block_on(analysis_reindex(args)),
         Action::All => analysis_all()
     }
 }
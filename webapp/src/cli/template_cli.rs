@@ -113,8 +113,8 @@ async fn template_add<'a>(models: &Models<'a>, args: &AddArgs) {
         name: name.to_string(),
         body: body.unwrap()
     };
-    match models::templates::save(models, &t).await {
-        Ok(t) => println!("Successfully save template: {:?}", t),
+    match models::templates::save(models, &t, None).await {
+        Ok((t, _)) => println!("Successfully save template: {:?}", t),
         Err(e) => println!("Failed to save template, error: {}", e)
     }
 }
@@ -0,0 +1,288 @@
+/**
+ * Registry of chemical components which may appear in ComponentTable.
+ *
+ * Resources:
+ * - 鉱泉分析法指針 (平成26年改訂) 環境省
+ */
+use serde::{Deserialize, Serialize};
+
+/// Table of an analysis in which a component is listed.
+#[derive(Copy, Clone, PartialEq, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Category {
+    PositiveIon,
+    NegativeIon,
+    Undissociated,
+    Gas,
+    Minor
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Component {
+    /// Key used in ComponentTable, e.g. "Na", "HCO3", "FeII".
+    pub key: &'static str,
+    /// Chemical formula without charge, e.g. "Fe" for "FeII".
+    pub formula: &'static str,
+    /// Formula for HTML, e.g. "SO<sub>4</sub><sup>2-</sup>".
+    pub html: String,
+    /// Formula in plain text, e.g. "SO₄²⁻".
+    pub plain: String,
+    /// Ionic charge. Zero for undissociated components.
+    pub charge: i32,
+    /// Molar mass [g/mol]
+    pub molar_mass: f64,
+    pub category: Category,
+    pub name_ja: &'static str,
+    pub name_en: &'static str
+}
+
+pub fn charge_text(charge: i32) -> String {
+    let sign = if charge > 0 { "+" } else { "-" };
+    match charge.abs() {
+        0 => String::new(),
+        1 => sign.to_string(),
+        n => format!("{}{}", n, sign)
+    }
+}
+
+fn html_formula(formula: &str, charge: i32) -> String {
+    let mut html = String::new();
+    let mut digits = String::new();
+    for c in formula.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        if !digits.is_empty() {
+            html.push_str(&format!("<sub>{}</sub>", &digits));
+            digits.clear();
+        }
+        html.push(c);
+    }
+    if !digits.is_empty() {
+        html.push_str(&format!("<sub>{}</sub>", &digits));
+    }
+    if charge != 0 {
+        html.push_str(&format!("<sup>{}</sup>", charge_text(charge)));
+    }
+    html
+}
+
+fn plain_formula(formula: &str, charge: i32) -> String {
+    const SUB: [char; 10] = ['₀', '₁', '₂', '₃', '₄', '₅', '₆', '₇', '₈', '₉'];
+    const SUP: [char; 10] = ['⁰', '¹', '²', '³', '⁴', '⁵', '⁶', '⁷', '⁸', '⁹'];
+    let mut plain: String = formula.chars().map(|c| match c.to_digit(10) {
+        Some(d) => SUB[d as usize],
+        None => c
+    }).collect();
+    plain.extend(charge_text(charge).chars().map(|c| match c {
+        '+' => '⁺',
+        '-' => '⁻',
+        c => c.to_digit(10).map_or(c, |d| SUP[d as usize])
+    }));
+    plain
+}
+
+impl Component {
+    pub fn new(key: &'static str, formula: &'static str, charge: i32,
+               molar_mass: f64, category: Category,
+               name_ja: &'static str, name_en: &'static str) -> Self {
+        Component {
+            key,
+            formula,
+            html: html_formula(formula, charge),
+            plain: plain_formula(formula, charge),
+            charge,
+            molar_mass,
+            category,
+            name_ja,
+            name_en
+        }
+    }
+
+    /// mmol from mg.
+    pub fn mmol(&self, mg: f64) -> f64 {
+        mg / self.molar_mass
+    }
+
+    /// mval from mg. None for undissociated components.
+    pub fn mval(&self, mg: f64) -> Option<f64> {
+        match self.charge {
+            0 => None,
+            z => Some(self.mmol(mg) * z.abs() as f64)
+        }
+    }
+}
+
+macro_rules! component {
+    ($key:expr, $formula:expr, $charge:expr, $molar_mass:expr,
+     $category:ident, $name_ja:expr, $name_en:expr) => {
+        Component::new($key, $formula, $charge, $molar_mass,
+                       Category::$category, $name_ja, $name_en)
+    }
+}
+
+lazy_static! {
+    static ref COMPONENTS: Vec<Component> = vec![
+        component!("H", "H", 1, 1.008, PositiveIon,
+                   "水素イオン", "Hydrogen ion"),
+        component!("Li", "Li", 1, 6.941, PositiveIon,
+                   "リチウムイオン", "Lithium ion"),
+        component!("B", "B", 3, 10.81, PositiveIon,
+                   "ホウ素イオン", "Boron ion"),
+        component!("C", "C", 4, 12.011, PositiveIon,
+                   "炭素イオン", "Carbon ion"),
+        component!("N", "N", -3, 14.007, NegativeIon,
+                   "窒化物イオン", "Nitride ion"),
+        component!("O", "O", -2, 15.999, NegativeIon,
+                   "酸化物イオン", "Oxide ion"),
+        component!("F", "F", -1, 18.998, NegativeIon,
+                   "フッ化物イオン", "Fluoride ion"),
+        component!("Na", "Na", 1, 22.990, PositiveIon,
+                   "ナトリウムイオン", "Sodium ion"),
+        component!("Mg", "Mg", 2, 24.305, PositiveIon,
+                   "マグネシウムイオン", "Magnesium ion"),
+        component!("Al", "Al", 3, 26.982, PositiveIon,
+                   "アルミニウムイオン", "Aluminium ion"),
+        component!("Si", "Si", -4, 28.086, NegativeIon,
+                   "ケイ化物イオン", "Silicide ion"),
+        component!("P", "P", -3, 30.974, NegativeIon,
+                   "リン化物イオン", "Phosphide ion"),
+        component!("S", "S", -2, 32.06, NegativeIon,
+                   "硫化物イオン", "Sulfide ion"),
+        component!("Cl", "Cl", -1, 35.45, NegativeIon,
+                   "塩化物イオン", "Chloride ion"),
+        component!("K", "K", 1, 39.098, PositiveIon,
+                   "カリウムイオン", "Potassium ion"),
+        component!("Ca", "Ca", 2, 40.078, PositiveIon,
+                   "カルシウムイオン", "Calcium ion"),
+        component!("Cr", "Cr", 0, 51.996, Minor,
+                   "総クロム", "Total chromium"),
+        component!("MnII", "Mn", 2, 54.938, PositiveIon,
+                   "マンガン(II)イオン", "Manganese(II) ion"),
+        component!("FeII", "Fe", 2, 55.845, PositiveIon,
+                   "鉄(II)イオン", "Iron(II) ion"),
+        component!("FeIII", "Fe", 3, 55.845, PositiveIon,
+                   "鉄(III)イオン", "Iron(III) ion"),
+        component!("Cu", "Cu", 0, 63.546, Minor,
+                   "総銅", "Total copper"),
+        component!("CuII", "Cu", 2, 63.546, PositiveIon,
+                   "銅(II)イオン", "Copper(II) ion"),
+        component!("Zn", "Zn", 0, 65.38, Minor,
+                   "総亜鉛", "Total zinc"),
+        component!("ZnII", "Zn", 2, 65.38, PositiveIon,
+                   "亜鉛イオン", "Zinc ion"),
+        component!("As", "As", 0, 74.922, Minor,
+                   "総ヒ素", "Total arsenic"),
+        component!("Br", "Br", -1, 79.904, NegativeIon,
+                   "臭化物イオン", "Bromide ion"),
+        component!("Sr", "Sr", 2, 87.62, PositiveIon,
+                   "ストロンチウムイオン", "Strontium ion"),
+        component!("Ag", "Ag", 0, 107.87, Minor,
+                   "総銀", "Total silver"),
+        component!("Cd", "Cd", 0, 112.41, Minor,
+                   "総カドミウム", "Total cadmium"),
+        component!("I", "I", -1, 126.90, NegativeIon,
+                   "ヨウ化物イオン", "Iodide ion"),
+        component!("Ba", "Ba", 2, 137.33, PositiveIon,
+                   "バリウムイオン", "Barium ion"),
+        component!("Hg", "Hg", 0, 200.59, Minor,
+                   "総水銀", "Total mercury"),
+        component!("Pb", "Pb", 0, 207.2, Minor,
+                   "総鉛", "Total lead"),
+        component!("H2SiO3", "H2SiO3", 0, 78.10, Undissociated,
+                   "メタケイ酸", "Metasilicic acid"),
+        component!("H2S", "H2S", 0, 34.08, Gas,
+                   "遊離硫化水素", "Free hydrogen sulfide"),
+        component!("HBO2", "HBO2", 0, 43.82, Undissociated,
+                   "メタホウ酸", "Metaboric acid"),
+        component!("HCO3", "HCO3", -1, 61.017, NegativeIon,
+                   "炭酸水素イオン", "Hydrogen carbonate ion"),
+        component!("HSiO3", "HSiO3", -1, 77.09, NegativeIon,
+                   "メタケイ酸水素イオン", "Hydrogen metasilicate ion"),
+        component!("HPO4", "HPO4", -2, 95.98, NegativeIon,
+                   "リン酸水素イオン", "Hydrogen phosphate ion"),
+        component!("HS", "HS", -1, 33.07, NegativeIon,
+                   "硫化水素イオン", "Hydrosulfide ion"),
+        component!("HSO4", "HSO4", -1, 97.07, NegativeIon,
+                   "硫酸水素イオン", "Hydrogen sulfate ion"),
+        component!("HAsO2", "HAsO2", 0, 107.93, Undissociated,
+                   "メタ亜ヒ酸", "Metaarsenous acid"),
+        component!("BO2", "BO2", -1, 42.81, NegativeIon,
+                   "メタホウ酸イオン", "Metaborate ion"),
+        component!("CO2", "CO2", 0, 44.01, Gas,
+                   "遊離二酸化炭素", "Free carbon dioxide"),
+        component!("CO3", "CO3", -2, 60.009, NegativeIon,
+                   "炭酸イオン", "Carbonate ion"),
+        component!("NH4", "NH4", 1, 18.038, PositiveIon,
+                   "アンモニウムイオン", "Ammonium ion"),
+        component!("NO2", "NO2", -1, 46.006, NegativeIon,
+                   "亜硝酸イオン", "Nitrite ion"),
+        component!("NO3", "NO3", -1, 62.004, NegativeIon,
+                   "硝酸イオン", "Nitrate ion"),
+        component!("OH", "OH", -1, 17.007, NegativeIon,
+                   "水酸化物イオン", "Hydroxide ion"),
+        component!("PO4", "PO4", -3, 94.97, NegativeIon,
+                   "リン酸イオン", "Phosphate ion"),
+        component!("SO4", "SO4", -2, 96.06, NegativeIon,
+                   "硫酸イオン", "Sulfate ion"),
+        component!("S2O3", "S2O3", -2, 112.13, NegativeIon,
+                   "チオ硫酸イオン", "Thiosulfate ion"),
+        component!("AsO2", "AsO2", -1, 106.92, NegativeIon,
+                   "メタ亜ヒ酸イオン", "Metaarsenite ion")
+    ];
+}
+
+/// Keys once used for components, stored in older analyses.
+const ALIASES: &[(&str, &str)] = &[
+    ("HNO2", "NO2")
+];
+
+pub fn lookup(key: &str) -> Option<&'static Component> {
+    let key = ALIASES.iter()
+        .find(|(alias, _)| *alias == key)
+        .map_or(key, |(_, k)| k);
+    COMPONENTS.iter().find(|c| c.key == key)
+}
+
+pub fn all() -> &'static [Component] {
+    &COMPONENTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_lookup_component() {
+        let c = lookup("SO4");
+        assert!(c.is_some());
+        assert_eq!(c.unwrap().charge, -2);
+        assert!(lookup("Unknown").is_none());
+        let c = lookup("HNO2").unwrap();
+        assert_eq!(c.key, "NO2");
+        assert_eq!(c.plain, "NO₂⁻");
+        assert!((c.molar_mass - 46.006).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_compute_mval_and_mmol() {
+        let c = lookup("Ca").unwrap();
+        assert!((c.mmol(40.078) - 1.0).abs() < 1e-9);
+        assert!((c.mval(40.078).unwrap() - 2.0).abs() < 1e-9);
+        assert!(lookup("H2SiO3").unwrap().mval(78.10).is_none());
+    }
+
+    #[test]
+    fn it_format_formula() {
+        let c = lookup("SO4").unwrap();
+        assert_eq!(c.html, "SO<sub>4</sub><sup>2-</sup>");
+        assert_eq!(c.plain, "SO₄²⁻");
+        let c = lookup("FeII").unwrap();
+        assert_eq!(c.html, "Fe<sup>2+</sup>");
+        assert_eq!(c.plain, "Fe²⁺");
+        assert_eq!(lookup("Hg").unwrap().html, "Hg");
+        assert_eq!(lookup("Cl").unwrap().plain, "Cl⁻");
+    }
+}
@@ -0,0 +1,48 @@
+/**
+ * Diagrams of analyses drawn as SVG.
+ */
+pub mod piper;
+pub mod stiff;
+
+use crate::analysis::ComponentTable;
+
+/// Sum of mval of the components. Missing components count as 0.
+fn sum_mval(table: &ComponentTable, keys: &[&str]) -> f64 {
+    keys.iter()
+        .filter_map(|k| table.components.get(*k))
+        .filter_map(|v| v.mval.amount())
+        .sum()
+}
+
+/// Smallest of 1, 2, 5, 10, 20, ... not less than `x`.
+fn nice_ceiling(x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let base = 10f64.powf(x.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].iter()
+        .map(|m| m * base)
+        .find(|v| *v >= x)
+        .unwrap_or(10.0 * base)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_up_scale() {
+        assert_eq!(nice_ceiling(0.0), 1.0);
+        assert_eq!(nice_ceiling(1.0), 1.0);
+        assert_eq!(nice_ceiling(3.2), 5.0);
+        assert_eq!(nice_ceiling(21.1), 50.0);
+        assert_eq!(nice_ceiling(0.15), 0.2);
+    }
+}
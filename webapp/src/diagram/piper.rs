@@ -0,0 +1,220 @@
+use crate::analysis::{Analysis, ComponentTable};
+use super::escape;
+
+/*
+ * Piper trilinear diagram of cations and anions in mval%.
+ * The cation triangle is on the left, the anion triangle on the right and
+ * the diamond between them above.
+ */
+
+/// Side of triangles
+const SIDE: f64 = 200.0;
+/// Gap between triangles
+const GAP: f64 = 40.0;
+const MARGIN: f64 = 30.0;
+const GRID: usize = 5;
+const COLORS: [&str; 6] =
+    ["#e63946", "#1d3557", "#2a9d8f", "#f4a261", "#6a4c93", "#8d99ae"];
+
+/// Ratios of major ion groups, each summing up to 1.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Ratios {
+    pub ca: f64,
+    pub mg: f64,
+    pub na_k: f64,
+    pub hco3_co3: f64,
+    pub so4: f64,
+    pub cl: f64
+}
+
+fn sum_percent(table: &ComponentTable, keys: &[&str]) -> f64 {
+    keys.iter().filter_map(|k| table.mval_percent(k)).sum()
+}
+
+/// Normalize values to sum up to 1. None if all of them are 0.
+fn normalize(values: [f64; 3]) -> Option<[f64; 3]> {
+    let total: f64 = values.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    Some([values[0] / total, values[1] / total, values[2] / total])
+}
+
+impl Ratios {
+    pub fn of(a: &Analysis) -> Option<Self> {
+        let [ca, mg, na_k] = normalize([
+            sum_percent(&a.positive_ion, &["Ca"]),
+            sum_percent(&a.positive_ion, &["Mg"]),
+            sum_percent(&a.positive_ion, &["Na", "K"])
+        ])?;
+        let [hco3_co3, so4, cl] = normalize([
+            sum_percent(&a.negative_ion, &["HCO3", "CO3"]),
+            sum_percent(&a.negative_ion, &["SO4"]),
+            sum_percent(&a.negative_ion, &["Cl"])
+        ])?;
+        Some(Ratios {
+            ca, mg, na_k,
+            hco3_co3, so4, cl
+        })
+    }
+}
+
+fn height() -> f64 {
+    SIDE * 3f64.sqrt() / 2.0
+}
+
+/// Point in a triangle whose left bottom is at `left`, with ratio `right`
+/// toward the right bottom vertex and `top` toward the top vertex.
+fn triangle(left: f64, right: f64, top: f64) -> (f64, f64) {
+    (left + right * SIDE + top * SIDE / 2.0, top * height())
+}
+
+fn cation(right: f64, top: f64) -> (f64, f64) {
+    triangle(0.0, right, top)
+}
+
+fn anion(right: f64, top: f64) -> (f64, f64) {
+    triangle(SIDE + GAP, right, top)
+}
+
+/// Point in the diamond, where a line of constant Na+K from the cation
+/// triangle meets a line of constant SO4+Cl from the anion triangle.
+fn diamond(na_k: f64, so4_cl: f64) -> (f64, f64) {
+    let (x1, _) = cation(na_k, 0.0);
+    let (x2, _) = anion(so4_cl, 0.0);
+    let t = x2 - x1;
+    (x1 + t / 2.0, t * 3f64.sqrt() / 2.0)
+}
+
+/// Convert into SVG coordinates, where y goes down.
+fn to_svg((x, y): (f64, f64)) -> (f64, f64) {
+    let total_height = (2.0 * SIDE + GAP) * 3f64.sqrt() / 2.0;
+    (MARGIN + x, MARGIN + total_height - y)
+}
+
+fn line(svg: &mut String, from: (f64, f64), to: (f64, f64), stroke: &str) {
+    let (x1, y1) = to_svg(from);
+    let (x2, y2) = to_svg(to);
+    svg.push_str(&format!(
+        "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" \
+         stroke=\"{}\"/>", x1, y1, x2, y2, stroke));
+}
+
+fn text(svg: &mut String, at: (f64, f64), anchor: &str, t: &str) {
+    let (x, y) = to_svg(at);
+    svg.push_str(&format!(
+        "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"{}\">{}</text>",
+        x, y, anchor, t));
+}
+
+fn grid(svg: &mut String) {
+    for i in 0..=GRID {
+        let f = i as f64 / GRID as f64;
+        let stroke = if i == 0 || i == GRID { "#444" } else { "#ddd" };
+        for tri in [cation as fn(f64, f64) -> (f64, f64), anion].iter() {
+            line(svg, tri(0.0, f), tri(1.0 - f, f), stroke);
+            line(svg, tri(f, 0.0), tri(f, 1.0 - f), stroke);
+            line(svg, tri(1.0 - f, 0.0), tri(0.0, 1.0 - f), stroke);
+        }
+        line(svg, diamond(f, 0.0), diamond(f, 1.0), stroke);
+        line(svg, diamond(0.0, f), diamond(1.0, f), stroke);
+    }
+}
+
+fn plot(svg: &mut String, a: &Analysis, r: &Ratios, color: &str) {
+    let points = [cation(r.na_k, r.mg), anion(r.cl, r.so4),
+                  diamond(r.na_k, 1.0 - r.hco3_co3)];
+    svg.push_str(&format!("<g fill=\"{}\"><title>{}</title>",
+                          color, escape(&a.name)));
+    for p in points.iter() {
+        let (x, y) = to_svg(*p);
+        svg.push_str(&format!(
+            "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"4\"/>", x, y));
+    }
+    svg.push_str("</g>");
+}
+
+/// Draw analyses. Analyses lacking either cations or anions are skipped.
+pub fn render(analyses: &[Analysis]) -> String {
+    let width = 2.0 * SIDE + GAP + MARGIN * 2.0;
+    let total_height = (2.0 * SIDE + GAP) * 3f64.sqrt() / 2.0 + MARGIN * 2.0;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" class=\"piper-diagram\" \
+         width=\"{w:.0}\" height=\"{h:.0}\" viewBox=\"0 0 {w:.0} {h:.0}\" \
+         font-size=\"10\">",
+        w=width, h=total_height);
+    grid(&mut svg);
+    let below = -14.0;
+    text(&mut svg, (0.0, below), "start", "Ca²⁺");
+    text(&mut svg, (SIDE, below), "end", "Na⁺+K⁺");
+    text(&mut svg, (SIDE / 2.0 - 6.0, height() - 4.0), "end", "Mg²⁺");
+    text(&mut svg, (SIDE + GAP, below), "start", "HCO₃⁻+CO₃²⁻");
+    text(&mut svg, (2.0 * SIDE + GAP, below), "end", "Cl⁻");
+    text(&mut svg, (SIDE * 1.5 + GAP + 6.0, height() - 4.0), "start",
+         "SO₄²⁻");
+    let mut i = 0;
+    for a in analyses {
+        if let Some(r) = Ratios::of(a) {
+            plot(&mut svg, a, &r, COLORS[i % COLORS.len()]);
+            i += 1;
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{CellValue, MgMvalMmol};
+
+    fn cell(mval: f64) -> MgMvalMmol {
+        let mut v = MgMvalMmol::new();
+        v.mval = CellValue::Number(mval);
+        v
+    }
+
+    #[test]
+    fn it_compute_piper_ratios() {
+        let mut a = Analysis::new("雨中温泉");
+        a.positive_ion.components.insert("Na".to_string(), cell(6.0));
+        a.positive_ion.components.insert("K".to_string(), cell(2.0));
+        a.positive_ion.components.insert("Ca".to_string(), cell(2.0));
+        assert!(Ratios::of(&a).is_none());
+        a.negative_ion.components.insert("Cl".to_string(), cell(5.0));
+        a.negative_ion.components.insert("SO4".to_string(), cell(5.0));
+        let r = Ratios::of(&a).unwrap();
+        assert!((r.na_k - 0.8).abs() < 1e-9);
+        assert!((r.ca - 0.2).abs() < 1e-9);
+        assert_eq!(r.mg, 0.0);
+        assert!((r.cl - 0.5).abs() < 1e-9);
+        let svg = render(&[a]);
+        assert_eq!(svg.matches("<circle").count(), 3);
+    }
+
+    #[test]
+    fn it_locate_diamond_corners() {
+        let (x, y) = diamond(1.0, 0.0);
+        assert!((x - (SIDE + GAP / 2.0)).abs() < 1e-9);
+        assert!((y - GAP * 3f64.sqrt() / 2.0).abs() < 1e-9);
+        let (x, _) = diamond(0.0, 1.0);
+        assert!((x - (SIDE + GAP / 2.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_project_into_diamond() {
+        let r = Ratios {
+            ca: 0.3, mg: 0.2, na_k: 0.5,
+            hco3_co3: 0.4, so4: 0.35, cl: 0.25
+        };
+        let (cx, cy) = cation(r.na_k, r.mg);
+        let (ax, ay) = anion(r.cl, r.so4);
+        let (dx, dy) = diamond(r.na_k, 1.0 - r.hco3_co3);
+        // Cations go up parallel to the left side of their triangle, and
+        // anions parallel to the right side of theirs.
+        let sqrt3 = 3f64.sqrt();
+        assert!(((dx - cx) * sqrt3 - (dy - cy)).abs() < 1e-9);
+        assert!(((ax - dx) * sqrt3 - (dy - ay)).abs() < 1e-9);
+        assert!(dy > cy && dy > ay);
+    }
+}
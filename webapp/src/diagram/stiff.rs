@@ -0,0 +1,127 @@
+use crate::analysis::Analysis;
+use super::{escape, nice_ceiling, sum_mval};
+
+/*
+ * Stiff diagram, or hexadiagram, of major ions in mval.
+ * Cations extend to the left and anions to the right of the center axis.
+ */
+
+const WIDTH: f64 = 320.0;
+const HEIGHT: f64 = 200.0;
+const MARGIN_X: f64 = 60.0;
+const MARGIN_Y: f64 = 30.0;
+
+/// (label, keys) of cations, from top to bottom.
+const CATIONS: [(&str, &[&str]); 3] =
+    [("Na⁺+K⁺", &["Na", "K"]), ("Ca²⁺", &["Ca"]), ("Mg²⁺", &["Mg"])];
+/// (label, keys) of anions, from top to bottom.
+const ANIONS: [(&str, &[&str]); 3] =
+    [("Cl⁻", &["Cl"]),
+     ("HCO₃⁻+CO₃²⁻", &["HCO3", "CO3"]),
+     ("SO₄²⁻", &["SO4"])];
+
+/// mval of cations and anions, from top to bottom.
+pub fn values(a: &Analysis) -> ([f64; 3], [f64; 3]) {
+    let mut cations = [0.0; 3];
+    let mut anions = [0.0; 3];
+    for (i, (_, keys)) in CATIONS.iter().enumerate() {
+        cations[i] = sum_mval(&a.positive_ion, keys);
+    }
+    for (i, (_, keys)) in ANIONS.iter().enumerate() {
+        anions[i] = sum_mval(&a.negative_ion, keys);
+    }
+    (cations, anions)
+}
+
+pub fn render(a: &Analysis) -> String {
+    let (cations, anions) = values(a);
+    let max = cations.iter().chain(anions.iter()).cloned()
+        .fold(0.0, f64::max);
+    let scale = nice_ceiling(max);
+    let center = WIDTH / 2.0;
+    let unit = (center - MARGIN_X) / scale;
+    let row = (HEIGHT - MARGIN_Y * 2.0) / 2.0;
+    let y = |i: usize| MARGIN_Y + row * i as f64;
+    let mut points = vec![];
+    for (i, v) in cations.iter().enumerate() {
+        points.push(format!("{:.1},{:.1}", center - v * unit, y(i)));
+    }
+    for (i, v) in anions.iter().enumerate().rev() {
+        points.push(format!("{:.1},{:.1}", center + v * unit, y(i)));
+    }
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" class=\"stiff-diagram\" \
+         width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" \
+         font-size=\"10\">",
+        w=WIDTH, h=HEIGHT);
+    svg.push_str(&format!("<title>{}</title>", escape(&a.name)));
+    // Axes
+    for i in 0..3 {
+        svg.push_str(&format!(
+            "<line x1=\"{x1}\" y1=\"{y:.1}\" x2=\"{x2}\" y2=\"{y:.1}\" \
+             stroke=\"#ccc\"/>",
+            x1=MARGIN_X, x2=WIDTH - MARGIN_X, y=y(i)));
+    }
+    svg.push_str(&format!(
+        "<line x1=\"{x}\" y1=\"{y1}\" x2=\"{x}\" y2=\"{y2}\" stroke=\"#888\"/>",
+        x=center, y1=MARGIN_Y, y2=HEIGHT - MARGIN_Y));
+    svg.push_str(&format!(
+        "<polygon points=\"{}\" fill=\"#8ecae6\" fill-opacity=\"0.6\" \
+         stroke=\"#023047\"/>",
+        points.join(" ")));
+    // Labels
+    for i in 0..3 {
+        svg.push_str(&format!(
+            "<text x=\"{x}\" y=\"{y:.1}\" text-anchor=\"end\" \
+             dominant-baseline=\"middle\">{t}</text>",
+            x=MARGIN_X - 4.0, y=y(i), t=CATIONS[i].0));
+        svg.push_str(&format!(
+            "<text x=\"{x}\" y=\"{y:.1}\" text-anchor=\"start\" \
+             dominant-baseline=\"middle\">{t}</text>",
+            x=WIDTH - MARGIN_X + 4.0, y=y(i), t=ANIONS[i].0));
+    }
+    // Scale
+    for (x, v) in [(MARGIN_X, scale), (center, 0.0),
+                   (WIDTH - MARGIN_X, scale)].iter() {
+        svg.push_str(&format!(
+            "<text x=\"{x}\" y=\"{y}\" text-anchor=\"middle\">{v}</text>",
+            x=x, y=HEIGHT - MARGIN_Y + 14.0, v=v));
+    }
+    svg.push_str(&format!(
+        "<text x=\"{x}\" y=\"{y}\" text-anchor=\"middle\">mval</text>",
+        x=center, y=HEIGHT - 4.0));
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{CellValue, MgMvalMmol};
+
+    fn cell(mval: f64) -> MgMvalMmol {
+        let mut v = MgMvalMmol::new();
+        v.mval = CellValue::Number(mval);
+        v
+    }
+
+    #[test]
+    fn it_render_stiff_diagram() {
+        let mut a = Analysis::new("雨中温泉");
+        a.positive_ion.components.insert("Na".to_string(), cell(15.0));
+        a.positive_ion.components.insert("K".to_string(), cell(5.0));
+        a.positive_ion.components.insert("Ca".to_string(), cell(1.0));
+        a.negative_ion.components.insert("Cl".to_string(), cell(10.0));
+        let mut co3 = MgMvalMmol::new();
+        co3.mval = CellValue::Trace("微量".to_string());
+        a.negative_ion.components.insert("CO3".to_string(), co3);
+        let (cations, anions) = values(&a);
+        assert_eq!(cations, [20.0, 1.0, 0.0]);
+        assert_eq!(anions, [10.0, 0.0, 0.0]);
+        let svg = render(&a);
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>"));
+        // 20 mval on the scale of 20 reaches the left margin
+        assert!(svg.contains("points=\"60.0,30.0 "));
+    }
+}
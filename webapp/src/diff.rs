@@ -0,0 +1,258 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::analysis::{Analysis, CellValue, ComponentTable, MgMvalMmol};
+use crate::component;
+use crate::template::{Template, Render, render_template};
+
+/*
+ * Differences between two analyses, typically of the same spring analysed
+ * at different times.
+ */
+
+/// Change of a value from `before` to `after`.
+#[derive(Clone, PartialEq, Serialize, Debug)]
+pub struct Change {
+    pub before: CellValue,
+    pub after: CellValue,
+    /// after - before
+    pub absolute: Option<f64>,
+    /// (after - before) / before
+    pub relative: Option<f64>,
+    pub changed: bool
+}
+
+impl Change {
+    pub fn new(before: &CellValue, after: &CellValue) -> Self {
+        let absolute = match (before.amount(), after.amount()) {
+            (Some(b), Some(a)) => Some(a - b),
+            _ => None
+        };
+        let relative = match (before.amount(), absolute) {
+            (Some(b), Some(d)) if b != 0.0 => Some(d / b),
+            _ => None
+        };
+        Change {
+            before: before.clone(),
+            after: after.clone(),
+            absolute,
+            relative,
+            changed: before != after
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Debug)]
+pub struct PropertyDiff {
+    /// e.g. "temperature"
+    pub key: &'static str,
+    #[serde(flatten)]
+    pub change: Change
+}
+
+#[derive(Clone, PartialEq, Serialize, Debug)]
+pub struct ComponentDiff {
+    /// e.g. "positiveIon", or "totals" for total rows
+    pub table: &'static str,
+    /// e.g. "Na", or "totalPositiveIon" for total rows
+    pub key: String,
+    pub mg: Change,
+    pub mval: Change,
+    pub changed: bool
+}
+
+impl ComponentDiff {
+    fn new(table: &'static str, key: &str,
+           before: &MgMvalMmol, after: &MgMvalMmol) -> Self {
+        let mg = Change::new(&before.mg, &after.mg);
+        let mval = Change::new(&before.mval, &after.mval);
+        let changed = mg.changed || mval.changed;
+        ComponentDiff {
+            table,
+            key: key.to_string(),
+            mg,
+            mval,
+            changed
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Debug)]
+pub struct MetadataDiff {
+    pub key: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub changed: bool
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct AnalysisDiff {
+    pub before: Analysis,
+    pub after: Analysis,
+    pub properties: Vec<PropertyDiff>,
+    pub components: Vec<ComponentDiff>,
+    pub totals: Vec<ComponentDiff>,
+    pub meta: Vec<MetadataDiff>
+}
+
+fn temperature_cell(a: &Analysis) -> CellValue {
+    a.temperature.map_or(CellValue::Null, CellValue::Number)
+}
+
+/// Keys of both tables, in the order of the component registry.
+fn union_keys<'a>(before: &'a ComponentTable, after: &'a ComponentTable)
+                  -> Vec<&'a String> {
+    let mut keys: Vec<&String> = before.components.keys()
+        .chain(after.components.keys().filter(|k| {
+            !before.components.contains_key(*k)
+        }))
+        .collect();
+    keys.sort_by_key(|k| {
+        let order = component::all().iter().position(|c| c.key == k.as_str());
+        (order.unwrap_or(usize::MAX), k.to_string())
+    });
+    keys
+}
+
+fn diff_table(table: &'static str, before: &ComponentTable,
+              after: &ComponentTable) -> Vec<ComponentDiff> {
+    let empty = MgMvalMmol::new();
+    union_keys(before, after).into_iter().map(|key| {
+        ComponentDiff::new(table, key,
+                           before.components.get(key).unwrap_or(&empty),
+                           after.components.get(key).unwrap_or(&empty))
+    }).collect()
+}
+
+fn diff_meta(before: &Analysis, after: &Analysis) -> Vec<MetadataDiff> {
+    let to_map = |a: &Analysis| match serde_json::to_value(&a.meta) {
+        Ok(Value::Object(m)) => m,
+        _ => serde_json::Map::new()
+    };
+    let (before, after) = (to_map(before), to_map(after));
+    let mut keys: Vec<&String> = before.keys()
+        .chain(after.keys().filter(|k| !before.contains_key(*k)))
+        .collect();
+    keys.sort();
+    keys.into_iter().map(|key| {
+        let (b, a) = (before.get(key).cloned(), after.get(key).cloned());
+        MetadataDiff {
+            key: key.to_string(),
+            changed: b != a,
+            before: b,
+            after: a
+        }
+    }).collect()
+}
+
+impl AnalysisDiff {
+    pub fn new(before: Analysis, after: Analysis) -> Self {
+        let properties = vec![
+            PropertyDiff {
+                key: "yield",
+                change: Change::new(&before.gensen_yield, &after.gensen_yield)
+            },
+            PropertyDiff {
+                key: "temperature",
+                change: Change::new(&temperature_cell(&before),
+                                    &temperature_cell(&after))
+            },
+            PropertyDiff {
+                key: "pH",
+                change: Change::new(&before.ph, &after.ph)
+            }
+        ];
+        let components = before.tables().iter().zip(after.tables().iter())
+            .flat_map(|((name, b), (_, a))| diff_table(name, b, a))
+            .collect();
+        let totals = before.totals().iter().zip(after.totals().iter())
+            .map(|((name, b), (_, a))| {
+                ComponentDiff::new("totals", name, b, a)
+            })
+            .collect();
+        let meta = diff_meta(&before, &after);
+        AnalysisDiff {
+            before,
+            after,
+            properties,
+            components,
+            totals,
+            meta
+        }
+    }
+}
+
+impl Render for AnalysisDiff {
+    fn render(&self, t: &Template) -> Result<String, String> {
+        match render_template(self, t) {
+            Ok(t) => Ok(t),
+            Err(e) => Err(format!("Template error, {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::analysis::Computed;
+    use crate::metadata::Metadata;
+
+    fn analysis(na: f64, cl: Option<f64>, location: &str) -> Analysis {
+        let mut positive_ion = HashMap::new();
+        let mut na_cell = MgMvalMmol::new();
+        na_cell.mg = CellValue::Number(na);
+        positive_ion.insert("Na".to_string(), na_cell);
+        let mut negative_ion = HashMap::new();
+        if let Some(cl) = cl {
+            let mut cl_cell = MgMvalMmol::new();
+            cl_cell.mg = CellValue::Number(cl);
+            negative_ion.insert("Cl".to_string(), cl_cell);
+        }
+        Analysis {
+            id: None,
+            name: "雨中温泉".to_string(),
+            gensen_yield: CellValue::Null,
+            temperature: Some(48.8),
+            ph: CellValue::Number(7.9),
+            positive_ion: ComponentTable { components: positive_ion },
+            negative_ion: ComponentTable { components: negative_ion },
+            undissociated: ComponentTable::new(),
+            gas: ComponentTable::new(),
+            minor: ComponentTable::new(),
+            total_positive_ion: MgMvalMmol::new(),
+            total_negative_ion: MgMvalMmol::new(),
+            total_undissociated: MgMvalMmol::new(),
+            total_gas: MgMvalMmol::new(),
+            total_minor: MgMvalMmol::new(),
+            total_melt: MgMvalMmol::new(),
+            total: MgMvalMmol::new(),
+            meta: Metadata {
+                location: Some(location.to_string()),
+                ..Default::default()
+            },
+            computed: Computed::default(),
+            last_modified: None,
+            created_at: None
+        }
+    }
+
+    #[test]
+    fn it_diff_analyses() {
+        let d = AnalysisDiff::new(analysis(400.0, None, "群馬県"),
+                                  analysis(500.0, Some(120.0), "群馬県吾妻郡"));
+        let na = d.components.iter().find(|c| c.key == "Na").unwrap();
+        assert_eq!(na.table, "positiveIon");
+        assert_eq!(na.mg.absolute, Some(100.0));
+        assert_eq!(na.mg.relative, Some(0.25));
+        let cl = d.components.iter().find(|c| c.key == "Cl").unwrap();
+        assert_eq!(cl.mg.before, CellValue::Null);
+        assert_eq!(cl.mg.absolute, None);
+        assert!(cl.changed);
+        let t = d.properties.iter().find(|p| p.key == "temperature").unwrap();
+        assert!(!t.change.changed);
+        assert_eq!(d.meta.len(), 1);
+        assert_eq!(d.meta[0].key, "location");
+        assert!(d.meta[0].changed);
+    }
+}
@@ -0,0 +1,221 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+
+use crate::analysis::Analysis;
+
+/**
+ * Geolocation of springs and filters on it.
+ */
+
+#[derive(Copy, Clone, PartialEq, Serialize, Debug)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64
+}
+
+/// Bounding box given as "west,south,east,north", the order of GeoJSON.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64
+}
+
+/// Distance in meters, given as e.g. "500m", "10km" or "10" in km.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Distance {
+    pub meters: f64
+}
+
+fn parse_numbers(s: &str, count: usize) -> Result<Vec<f64>, String> {
+    let values = s.split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|e| format!("{}: {}", s, e))?;
+    if values.len() != count {
+        return Err(format!("{}: expected {} numbers", s, count));
+    }
+    Ok(values)
+}
+
+fn check_range(name: &str, v: f64, max: f64) -> Result<f64, String> {
+    if v.abs() > max {
+        Err(format!("{} out of range: {}", name, v))
+    } else {
+        Ok(v)
+    }
+}
+
+impl GeoPoint {
+    pub fn new(lat: f64, lon: f64) -> Result<Self, String> {
+        Ok(GeoPoint {
+            lat: check_range("latitude", lat, 90.0)?,
+            lon: check_range("longitude", lon, 180.0)?
+        })
+    }
+}
+
+/// "lat,lon"
+impl FromStr for GeoPoint {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = parse_numbers(s, 2)?;
+        GeoPoint::new(v[0], v[1])
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = parse_numbers(s, 4)?;
+        let b = BoundingBox {
+            west: check_range("west", v[0], 180.0)?,
+            south: check_range("south", v[1], 90.0)?,
+            east: check_range("east", v[2], 180.0)?,
+            north: check_range("north", v[3], 90.0)?
+        };
+        if b.south > b.north {
+            return Err(format!("{}: south is greater than north", s));
+        }
+        Ok(b)
+    }
+}
+
+impl FromStr for Distance {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = if let Some(n) = s.strip_suffix("km") {
+            (n, 1000.0)
+        } else if let Some(n) = s.strip_suffix('m') {
+            (n, 1.0)
+        } else {
+            (s, 1000.0)
+        };
+        match number.trim().parse::<f64>() {
+            Ok(n) if n > 0.0 => Ok(Distance { meters: n * unit }),
+            _ => Err(format!("invalid distance: {}", s))
+        }
+    }
+}
+
+/// Deserialize a value from text by FromStr, as given in query strings.
+macro_rules! impl_deserialize_by_from_str {
+    ($t:ty) => {
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+                where D: Deserializer<'de>
+            {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    }
+}
+
+impl_deserialize_by_from_str!(GeoPoint);
+impl_deserialize_by_from_str!(BoundingBox);
+impl_deserialize_by_from_str!(Distance);
+
+impl Default for Distance {
+    fn default() -> Self {
+        Distance { meters: 10_000.0 }
+    }
+}
+
+/// Filter clause of Elasticsearch for analyses within `distance` from
+/// `center`.
+pub fn distance_filter(key: &str, center: &GeoPoint, distance: &Distance)
+                       -> Value {
+    json!({
+        "geo_distance": {
+            "distance": format!("{}m", distance.meters),
+            key: center
+        }
+    })
+}
+
+/// Filter clause of Elasticsearch for analyses in `b`.
+pub fn bounding_box_filter(key: &str, b: &BoundingBox) -> Value {
+    json!({
+        "geo_bounding_box": {
+            key: {
+                "top_left": {"lat": b.north, "lon": b.west},
+                "bottom_right": {"lat": b.south, "lon": b.east}
+            }
+        }
+    })
+}
+
+/// GeoJSON FeatureCollection of analyses. Analyses without geolocation are
+/// left out.
+pub fn feature_collection(analyses: &[Analysis]) -> Value {
+    let features: Vec<Value> = analyses.iter().filter_map(|a| {
+        let p = a.meta.geo_point()?;
+        Some(json!({
+            "type": "Feature",
+            "id": a.id,
+            "geometry": {
+                "type": "Point",
+                "coordinates": [p.lon, p.lat]
+            },
+            "properties": {
+                "name": a.name,
+                "location": a.meta.location,
+                "facilityName": a.meta.facility_name,
+                "quality": a.computed.quality.as_ref()
+                    .or(a.meta.quality.as_ref()),
+                "temperature": a.temperature,
+                "pH": a.ph.as_f64(),
+                "tonicity": a.computed.tonicity,
+                "phClass": a.computed.ph_class,
+                "temperatureClass": a.computed.temperature_class
+            }
+        }))
+    }).collect();
+    json!({
+        "type": "FeatureCollection",
+        "features": features
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parse_geo_filters() {
+        assert_eq!("36.62, 138.59".parse::<GeoPoint>(),
+                   Ok(GeoPoint { lat: 36.62, lon: 138.59 }));
+        assert!("95,138".parse::<GeoPoint>().is_err());
+        assert!("36.62".parse::<GeoPoint>().is_err());
+        assert_eq!("138,36,139,37".parse::<BoundingBox>(),
+                   Ok(BoundingBox {
+                       west: 138.0, south: 36.0, east: 139.0, north: 37.0
+                   }));
+        assert!("138,37,139,36".parse::<BoundingBox>().is_err());
+        assert_eq!("500m".parse::<Distance>(), Ok(Distance { meters: 500.0 }));
+        assert_eq!("2.5km".parse::<Distance>(),
+                   Ok(Distance { meters: 2500.0 }));
+        assert_eq!("3".parse::<Distance>(), Ok(Distance { meters: 3000.0 }));
+        assert!("-1km".parse::<Distance>().is_err());
+    }
+
+    #[test]
+    fn it_build_feature_collection() {
+        let mut a = Analysis::new("草津温泉");
+        a.id = Some("kusatsu".to_string());
+        let b = Analysis::new("雨中温泉");
+        a.meta.latitude = Some(36.62);
+        a.meta.longitude = Some(138.59);
+        let c = feature_collection(&[a, b]);
+        let features = c["features"].as_array().unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0]["id"], json!("kusatsu"));
+        assert_eq!(features[0]["geometry"]["coordinates"],
+                   json!([138.59, 36.62]));
+    }
+}
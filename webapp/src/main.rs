@@ -1,3 +1,5 @@
+#![recursion_limit = "256"]
+
 #[macro_use]
 extern crate lazy_static;
 
@@ -12,6 +14,14 @@ use structopt::StructOpt;
 mod utils;
 mod template;
 mod analysis;
+mod component;
+mod quality;
+mod metadata;
+mod geo;
+mod diff;
+mod tabular;
+mod diagram;
+mod pdf;
 mod comment;
 mod photo;
 mod token;
@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
+use serde_json::{Map, Value};
+
+use crate::geo::GeoPoint;
+
+/**
+ * Items of an analysis sheet other than components.
+ * Unknown items are kept in `extra` as text.
+ */
+#[derive(Clone, PartialEq, Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Metadata {
+    /// 分析書番号
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no: Option<String>,
+
+    /// 源泉湧出地
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+
+    /// 緯度 [deg]
+    #[serde(deserialize_with = "deserialize_number",
+            skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+
+    /// 経度 [deg]
+    #[serde(deserialize_with = "deserialize_number",
+            skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+
+    /// 施設名
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facility_name: Option<String>,
+
+    /// 浴室名
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_name: Option<String>,
+
+    /// 分析申請者 住所
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub applicant_address: Option<String>,
+
+    /// 分析申請者 氏名
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub applicant_name: Option<String>,
+
+    /// 泉質
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+
+    /// 調査及び試験者
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub investigator: Option<String>,
+
+    /// 調査及び試験年月日
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub investigated_date: Option<SheetDate>,
+
+    /// 知覚的試験
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub perception: Option<String>,
+
+    /// 電気伝導率 [mS/m]
+    #[serde(deserialize_with = "deserialize_conductivity",
+            skip_serializing_if = "Option::is_none")]
+    pub conductivity: Option<f64>,
+
+    /// ラドン (Rn) [Bq/kg]
+    #[serde(deserialize_with = "deserialize_radon",
+            skip_serializing_if = "Option::is_none")]
+    pub radon: Option<f64>,
+
+    /// 試験者
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tester: Option<String>,
+
+    /// 試験年月日
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tested_date: Option<SheetDate>,
+
+    /// 試験室における知覚的試験
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tested_perception: Option<String>,
+
+    /// 密度 [g/cm3]
+    #[serde(deserialize_with = "deserialize_density",
+            skip_serializing_if = "Option::is_none")]
+    pub tested_dencity: Option<f64>,
+
+    /// 試験室における pH値
+    #[serde(rename = "testedPH", deserialize_with = "deserialize_number",
+            skip_serializing_if = "Option::is_none")]
+    pub tested_ph: Option<f64>,
+
+    /// 蒸発残留物 [mg/kg]
+    #[serde(rename = "testedER", deserialize_with = "deserialize_residue",
+            skip_serializing_if = "Option::is_none")]
+    pub tested_er: Option<f64>,
+
+    /// 泉温の補足
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_extra: Option<String>,
+
+    /// 湧出量の補足
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub yield_extra: Option<String>,
+
+    /// pH値の補足
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ph_extra: Option<String>,
+
+    /// 加温
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heating: Option<Treatment>,
+
+    /// 加水
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub water: Option<Treatment>,
+
+    /// 循環・ろ過
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circulation: Option<Treatment>,
+
+    /// 塩素系薬剤の使用
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chlorination: Option<Treatment>,
+
+    /// 入浴剤の使用
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub additive: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub footer: Option<String>,
+
+    /// Items not listed above
+    #[serde(flatten)]
+    pub extra: HashMap<String, String>
+}
+
+impl Metadata {
+    /// Geolocation if both latitude and longitude are given and valid.
+    pub fn geo_point(&self) -> Option<GeoPoint> {
+        GeoPoint::new(self.latitude?, self.longitude?).ok()
+    }
+
+    /// Read metadata from stored fields. Fields failed to be parsed are kept
+    /// in `extra` as text so that no data written before is lost.
+    pub fn from_fields(fields: Map<String, Value>) -> Self {
+        let mut valid = Map::new();
+        let mut invalid = HashMap::new();
+        for (key, value) in fields {
+            let mut single = Map::new();
+            single.insert(key.clone(), value.clone());
+            match serde_json::from_value::<Metadata>(Value::Object(single)) {
+                Ok(_) => { valid.insert(key, value); },
+                Err(e) => {
+                    debug!("Metadata::from_fields, key: {}, e: {}", &key, &e);
+                    invalid.insert(key, match value.as_str() {
+                        Some(v) => v.to_string(),
+                        None => value.to_string()
+                    });
+                }
+            }
+        }
+        let mut meta = serde_json::from_value::<Metadata>(Value::Object(valid))
+            .unwrap_or_default();
+        meta.extra.extend(invalid);
+        meta
+    }
+}
+
+fn normalize_digits(s: &str) -> String {
+    s.trim().chars().map(|c| match c {
+        '０'..='９' =>
+            std::char::from_u32(c as u32 - '０' as u32 + '0' as u32)
+            .unwrap_or(c),
+        '．' => '.',
+        '，' => ',',
+        '－' | '−' => '-',
+        c => c
+    }).collect()
+}
+
+/// Number written as `text`, optionally followed by one of `units` such as
+/// "14.6 mS/m". Digits may be grouped by commas as in "1,234". Text having
+/// anything else is not taken as a number.
+fn parse_number(text: &str, units: &[&str]) -> Option<f64> {
+    let text = normalize_digits(text);
+    let number = units.iter()
+        .find_map(|u| text.strip_suffix(u))
+        .unwrap_or(&text)
+        .trim();
+    let integer = number.split('.').next().unwrap_or_default();
+    let mut groups = integer.trim_start_matches('-').split(',');
+    let first = groups.next().unwrap_or_default();
+    let grouped = integer.contains(',');
+    if grouped && (first.is_empty() || first.len() > 3 ||
+                   groups.any(|g| g.len() != 3)) {
+        return None;
+    }
+    number.replace(',', "").parse::<f64>().ok()
+        .filter(|v| v.is_finite())
+}
+
+/// Accept a number, or text of a number followed by `units`.
+struct NumberVisitor {
+    units: &'static [&'static str]
+}
+
+impl<'de> Visitor<'de> for NumberVisitor {
+    type Value = Option<f64>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number")
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Some(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Some(v as f64))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Some(v as f64))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        if v.trim().is_empty() {
+            return Ok(None);
+        }
+        parse_number(v, self.units).map(Some)
+            .ok_or_else(|| E::custom(format!("expected a number: {}", v)))
+    }
+}
+
+fn deserialize_number<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+    where D: Deserializer<'de>
+{
+    deserializer.deserialize_any(NumberVisitor { units: &[] })
+}
+
+fn deserialize_conductivity<'de, D>(deserializer: D)
+                                    -> Result<Option<f64>, D::Error>
+    where D: Deserializer<'de>
+{
+    deserializer.deserialize_any(NumberVisitor { units: &["mS/m"] })
+}
+
+fn deserialize_radon<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+    where D: Deserializer<'de>
+{
+    deserializer.deserialize_any(NumberVisitor { units: &["Bq/kg"] })
+}
+
+fn deserialize_density<'de, D>(deserializer: D)
+                               -> Result<Option<f64>, D::Error>
+    where D: Deserializer<'de>
+{
+    deserializer.deserialize_any(NumberVisitor { units: &["g/cm3", "g/cm³"] })
+}
+
+fn deserialize_residue<'de, D>(deserializer: D)
+                               -> Result<Option<f64>, D::Error>
+    where D: Deserializer<'de>
+{
+    deserializer.deserialize_any(NumberVisitor { units: &["mg/kg"] })
+}
+
+/**
+ * Date written in an analysis sheet.
+ * Accepts "2020-03-04", "2020/3/4", "2020年3月4日" and Japanese eras such as
+ * "令和2年3月4日" or "R2.3.4". Serialized as "2020-03-04".
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SheetDate {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32
+}
+
+const ERAS: &[(&str, i32)] =
+    &[("令和", 2018), ("平成", 1988), ("昭和", 1925),
+      ("R", 2018), ("H", 1988), ("S", 1925)];
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+impl FromStr for SheetDate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = normalize_digits(s).replace("元年", "1年")
+            .to_ascii_uppercase();
+        let (base, rest) = ERAS.iter()
+            .find_map(|(era, base)| {
+                text.strip_prefix(era).map(|rest| (Some(*base), rest))
+            })
+            .unwrap_or((None, text.as_str()));
+        let numbers: Vec<u32> = rest
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|t| !t.is_empty())
+            .filter_map(|t| t.parse::<u32>().ok())
+            .collect();
+        if numbers.len() != 3 {
+            return Err(format!("invalid date: {}", s));
+        }
+        let year = match base {
+            Some(base) => base + numbers[0] as i32,
+            None if numbers[0] >= 1000 => numbers[0] as i32,
+            None => return Err(format!("invalid year: {}", s))
+        };
+        let (month, day) = (numbers[1], numbers[2]);
+        if !(1..=12).contains(&month) ||
+            day < 1 || day > days_in_month(year, month) {
+            return Err(format!("invalid date: {}", s));
+        }
+        Ok(SheetDate { year, month, day })
+    }
+}
+
+impl fmt::Display for SheetDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl Serialize for SheetDate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for SheetDate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse::<SheetDate>().map_err(de::Error::custom)
+    }
+}
+
+/**
+ * Whether a treatment such as 加温 or 加水 is applied, with the text written
+ * in the sheet. Accepts booleans and texts like "あり", "なし", "使用",
+ * "不使用". Serialized as the text, or "あり" or "なし" if given a boolean.
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct Treatment {
+    pub applied: bool,
+    pub text: Option<String>
+}
+
+const WORDS_NOT_APPLIED: &[&str] =
+    &["なし", "無し", "無", "不使用", "しない", "していない", "no", "false"];
+const WORDS_APPLIED: &[&str] =
+    &["あり", "有り", "有", "使用", "する", "している", "yes", "true"];
+
+impl Treatment {
+    pub fn new(applied: bool) -> Self {
+        Treatment { applied, text: None }
+    }
+}
+
+impl FromStr for Treatment {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let t = s.trim().to_lowercase();
+        // Check negative words first as "不使用" contains "使用"
+        let applied = if WORDS_NOT_APPLIED.iter().any(|w| t.contains(w)) {
+            false
+        } else if WORDS_APPLIED.iter().any(|w| t.contains(w)) {
+            true
+        } else {
+            return Err(format!("expected あり or なし: {}", s));
+        };
+        Ok(Treatment { applied, text: Some(s.to_string()) })
+    }
+}
+
+impl Serialize for Treatment {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(match (&self.text, self.applied) {
+            (Some(text), _) => text,
+            (None, true) => "あり",
+            (None, false) => "なし"
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Treatment {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        match Value::deserialize(deserializer)? {
+            Value::Bool(b) => Ok(Treatment::new(b)),
+            Value::String(s) => s.parse().map_err(de::Error::custom),
+            v => Err(de::Error::custom(format!("expected あり or なし: {}", v)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn it_parse_sheet_date() {
+        let d = SheetDate { year: 2020, month: 3, day: 4 };
+        assert_eq!("2020-03-04".parse::<SheetDate>(), Ok(d));
+        assert_eq!("2020年3月4日".parse::<SheetDate>(), Ok(d));
+        assert_eq!("令和2年3月4日".parse::<SheetDate>(), Ok(d));
+        assert_eq!("R2.3.4".parse::<SheetDate>(), Ok(d));
+        assert_eq!("平成元年１月８日".parse::<SheetDate>(),
+                   Ok(SheetDate { year: 1989, month: 1, day: 8 }));
+        assert!("2019-02-29".parse::<SheetDate>().is_err());
+        assert!("昨日".parse::<SheetDate>().is_err());
+        assert_eq!("r2.3.4".parse::<SheetDate>(), Ok(d));
+        assert!("ſ2.3.4".parse::<SheetDate>().is_err());
+    }
+
+    #[test]
+    fn it_deserialize_metadata() {
+        let it = json!({
+            "investigatedDate": "令和2年3月4日",
+            "conductivity": "14.6 mS/m",
+            "radon": "120 Bq/kg",
+            "testedPH": 7.9,
+            "heating": "加温なし",
+            "quality": "単純温泉",
+            "roomFee": "500円"
+        });
+        let r = serde_json::from_value::<Metadata>(it);
+        assert!(r.is_ok());
+        let r = r.unwrap();
+        assert_eq!(r.investigated_date,
+                   Some(SheetDate { year: 2020, month: 3, day: 4 }));
+        assert_eq!(r.conductivity, Some(14.6));
+        assert_eq!(r.radon, Some(120.0));
+        assert_eq!(r.tested_ph, Some(7.9));
+        assert_eq!(r.heating.as_ref().map(|t| t.applied), Some(false));
+        assert_eq!(serde_json::to_value(&r.heating).unwrap(),
+                   json!("加温なし"));
+        assert_eq!(r.quality, Some("単純温泉".to_string()));
+        assert_eq!(r.extra.get("roomFee"), Some(&"500円".to_string()));
+        assert_eq!(serde_json::to_value(&r).unwrap().get("investigatedDate"),
+                   Some(&json!("2020-03-04")));
+    }
+
+    #[test]
+    fn it_keep_text_of_treatment() {
+        let t = "循環ろ過を使用（衛生管理のため）".parse::<Treatment>().unwrap();
+        assert!(t.applied);
+        assert_eq!(serde_json::to_value(&t).unwrap(),
+                   json!("循環ろ過を使用（衛生管理のため）"));
+        let t = serde_json::from_value::<Treatment>(json!(false)).unwrap();
+        assert_eq!(t, Treatment::new(false));
+        assert_eq!(serde_json::to_value(&t).unwrap(), json!("なし"));
+    }
+
+    #[test]
+    fn it_reject_invalid_metadata() {
+        let it = json!({ "testedDate": "2020年13月1日" });
+        assert!(serde_json::from_value::<Metadata>(it).is_err());
+        let it = json!({ "conductivity": "不明" });
+        assert!(serde_json::from_value::<Metadata>(it).is_err());
+        let it = json!({ "conductivity": "146 μS/cm" });
+        assert!(serde_json::from_value::<Metadata>(it).is_err());
+        let it = json!({ "testedPH": "7.9 (20℃)" });
+        assert!(serde_json::from_value::<Metadata>(it).is_err());
+    }
+
+    #[test]
+    fn it_parse_number() {
+        assert_eq!(parse_number("14.6 mS/m", &["mS/m"]), Some(14.6));
+        assert_eq!(parse_number("１，２３４", &[]), Some(1234.0));
+        assert_eq!(parse_number("1,234.5 mg/kg", &["mg/kg"]), Some(1234.5));
+        assert_eq!(parse_number("-0.5", &[]), Some(-0.5));
+        assert_eq!(parse_number("1,234", &["mS/m"]), Some(1234.0));
+        assert_eq!(parse_number("1,23", &[]), None);
+        assert_eq!(parse_number("1234,567", &[]), None);
+        assert_eq!(parse_number("146 μS/cm", &["mS/m"]), None);
+        assert_eq!(parse_number("12abc", &[]), None);
+    }
+
+    #[test]
+    fn it_read_metadata_from_fields() {
+        let it = json!({ "testedDate": "不明", "location": "群馬県" });
+        let r = Metadata::from_fields(it.as_object().unwrap().clone());
+        assert_eq!(r.tested_date, None);
+        assert_eq!(r.location, Some("群馬県".to_string()));
+        assert_eq!(r.extra.get("testedDate"), Some(&"不明".to_string()));
+    }
+}
@@ -1,23 +1,35 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use crate::analysis::{Analysis, ComponentTable, CellValue, MgMvalMmol};
-use crate::models::{Models};
+use crate::analysis::{
+    Analysis, ComponentTable, CellValue, MgMvalMmol, Computed,
+    FINGERPRINT_DIMENSIONS
+};
+use crate::component::{self, Category};
+use crate::metadata::Metadata;
+use crate::geo::{self, GeoPoint, BoundingBox, Distance};
+use crate::quality::{Tonicity, PhClass, TemperatureClass};
+use crate::models::{Models, SaveError, analyses_history::{
+    self, KEY_DOCUMENT_SAVED_BY as KEY_SAVED_BY
+}};
 // use crate::utils::mongodb::{document_str, document_number};
 use crate::utils::elasticsearch::{
-    GetResult, SearchResultItem, OperationResultType,
+    self as es, GetResult, SearchResultItem, OperationResultType, Highlights,
+    Cursor,
     Setup, SetupOptions,
     Operations, GetOptions, SearchOptions, InsertOptions, UpdateOptions,
-    DeleteOptions
+    UpdateResult, DeleteOptions, Version
 };
 use crate::utils::scrub;
 use crate::utils::json::from_value;
 
 const KEY_ID: &str = "id";
+const KEY_ID_KEYWORD: &str = "id.keyword";
 const KEY_NAME: &str = "name";
 const KEY_YIELD: &str = "yild";
 const KEY_TEMPERATURE: &str = "temp";
@@ -40,6 +52,18 @@ const KEY_MVAL_PERCENT: &str = "mvp";
 const KEY_MMOL: &str = "mm";
 const KEY_LAST_MODIFIED: &str = "_lamo";
 const KEY_CREATED_AT: &str = "_crat";
+const KEY_TONICITY: &str = "_tnct";
+const KEY_PH_CLASS: &str = "_phcl";
+const KEY_TEMPERATURE_CLASS: &str = "_tmcl";
+const KEY_FINGERPRINT: &str = "_fgpr";
+const KEY_GEO_POINT: &str = "_geop";
+const KEY_SUGGEST: &str = "_sugg";
+const KEY_YOMI: &str = "_yomi";
+const KEY_ROMAJI: &str = "_rmji";
+const KEY_NAME_SORT: &str = "_nmso";
+const KEY_QUALITY_KEYWORD: &str = "quality.keyword";
+
+const SELECT_ALL_PAGE_SIZE: u32 = 100;
 
 const KEY_NUMBER: &str = "n";
 const KEY_TEXT: &str = "t";
@@ -51,7 +75,13 @@ const KEY_FIELDS_SEARCHABLE: &'static [&'static str] =
       "investigator", "perception",
       "tester", "testedPerception",
       "heating", "water", "circulation", "chlorination", "additive",
-      "header", "footer"];
+      "header", "footer",
+      KEY_YOMI, KEY_ROMAJI];
+
+fn from_value_opt<T: serde::de::DeserializeOwned>(value: Option<&Value>)
+                                                  -> Option<T> {
+    value.and_then(|v| serde_json::from_value(v.clone()).ok())
+}
 
 /**
  * Conversions from MongoDB object to Analysis.
@@ -64,7 +94,7 @@ impl TryFrom<&Value> for CellValue {
                 return Ok(CellValue::Number(v.as_f64().unwrap()))
             }
             if let Some(Value::String(v)) = obj.get(KEY_TEXT) {
-                return Ok(CellValue::Text(v.to_string()))
+                return Ok(CellValue::parse_text(v))
             }
         }
         Ok(CellValue::Null)
@@ -231,7 +261,7 @@ impl TryFrom<&Value> for Analysis {
         let total =
             from_value::<MgMvalMmol>(obj.get(KEY_TOTAL))?;
         // Metadata
-        let mut meta = HashMap::new();
+        let mut fields = serde_json::Map::new();
         for (key, value) in obj.iter() {
             match key.as_str() {
                 KEY_ID | KEY_NAME | KEY_YIELD | KEY_TEMPERATURE | KEY_PH | 
@@ -240,15 +270,16 @@ impl TryFrom<&Value> for Analysis {
                 KEY_TOTAL_POSITIVE_ION | KEY_TOTAL_NEGATIVE_ION |
                 KEY_TOTAL_UNDISSOCIATED | KEY_TOTAL_GAS | KEY_TOTAL_MINOR |
                 KEY_TOTAL_MELT | KEY_TOTAL |
-                KEY_LAST_MODIFIED | KEY_CREATED_AT => {},
+                KEY_LAST_MODIFIED | KEY_CREATED_AT |
+                KEY_TONICITY | KEY_PH_CLASS | KEY_TEMPERATURE_CLASS |
+                KEY_FINGERPRINT | KEY_GEO_POINT | KEY_SUGGEST |
+                KEY_YOMI | KEY_ROMAJI | KEY_NAME_SORT | KEY_SAVED_BY => {},
                 _ => {
-                    meta.insert(key.to_string(), match value.as_str() {
-                        Some(v) => v.to_string(),
-                        None => value.to_string()
-                    });
+                    fields.insert(key.to_string(), value.clone());
                 }
             }
         }
+        let meta = Metadata::from_fields(fields);
         let last_modified = obj.get(KEY_LAST_MODIFIED).and_then(|v| v.as_f64());
         let created_at = obj.get(KEY_CREATED_AT).and_then(|v| v.as_f64());
         Ok(Analysis {
@@ -270,6 +301,13 @@ impl TryFrom<&Value> for Analysis {
             total_melt: total_melt,
             total: total,
             meta: meta,
+            computed: Computed {
+                tonicity: from_value_opt(obj.get(KEY_TONICITY)),
+                ph_class: from_value_opt(obj.get(KEY_PH_CLASS)),
+                temperature_class:
+                    from_value_opt(obj.get(KEY_TEMPERATURE_CLASS)),
+                ..Default::default()
+            },
             last_modified: last_modified,
             created_at: created_at
         })
@@ -413,12 +451,23 @@ impl From<&Analysis> for Value {
             KEY_TOTAL: Value::from(&item.total),
             KEY_CREATED_AT: item.created_at.map_or(Value::Null, Value::from),
             KEY_LAST_MODIFIED: item
-                .last_modified.map_or(Value::Null, Value::from)
+                .last_modified.map_or(Value::Null, Value::from),
+            KEY_TONICITY: json!(item.computed.tonicity),
+            KEY_PH_CLASS: json!(item.computed.ph_class),
+            KEY_TEMPERATURE_CLASS: json!(item.computed.temperature_class)
         });
         let obj = d.as_object_mut().unwrap();
-        for (key, value) in &item.meta {
-            if !obj.contains_key(key) {
-                obj.insert(key.to_string(), Value::from(value.as_str()));
+        if let Some(v) = item.fingerprint() {
+            obj.insert(KEY_FINGERPRINT.to_string(), json!(v));
+        }
+        if let Some(p) = item.meta.geo_point() {
+            obj.insert(KEY_GEO_POINT.to_string(), json!(p));
+        }
+        if let Value::Object(meta) = json!(item.meta) {
+            for (key, value) in meta {
+                if !obj.contains_key(&key) {
+                    obj.insert(key, value);
+                }
             }
         }
         d
@@ -499,8 +548,8 @@ impl From<&CellValue> for Value {
     fn from(item: &CellValue) -> Self {
         match item {
             CellValue::Number(x) => json!({KEY_NUMBER: *x}),
-            CellValue::Text(x) => json!({KEY_TEXT: x}),
-            CellValue::Null => Value::Null
+            CellValue::Null => Value::Null,
+            x => json!({KEY_TEXT: x.to_string()})
         }
     }
 }
@@ -562,63 +611,466 @@ pub enum SortKey {
     #[serde(rename = "i")]
     Id,
     #[serde(rename = "l")]
-    LastModified
+    LastModified,
+    /// Reading of name in the gojūon order
+    #[serde(rename = "n")]
+    Name,
+    #[serde(rename = "c")]
+    CreatedAt,
+    #[serde(rename = "t")]
+    Temperature,
+    #[serde(rename = "h")]
+    Ph,
+    /// Total dissolved solids, 溶存物質 (ガス性のものを除く)
+    #[serde(rename = "m")]
+    TotalMelt,
+    /// Relevance to the search text
+    #[serde(rename = "s")]
+    Score
 }
 
-#[derive(Debug)]
+impl SortKey {
+    fn to_sort(self, direction: &str) -> Value {
+        let key = match self {
+            SortKey::Id => KEY_ID_KEYWORD.to_string(),
+            SortKey::LastModified => KEY_LAST_MODIFIED.to_string(),
+            SortKey::Name => KEY_NAME_SORT.to_string(),
+            SortKey::CreatedAt => KEY_CREATED_AT.to_string(),
+            SortKey::Temperature => KEY_TEMPERATURE.to_string(),
+            SortKey::Ph => format!("{}.{}", KEY_PH, KEY_NUMBER),
+            SortKey::TotalMelt =>
+                format!("{}.{}.{}", KEY_TOTAL_MELT, KEY_MG, KEY_NUMBER),
+            SortKey::Score => return json!({"_score": direction})
+        };
+        // Analyses lacking the value come last in either direction
+        json!({key: {"order": direction, "missing": "_last"}})
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct SelectOptions {
     pub query: Option<String>,
+    pub tonicity: Option<Tonicity>,
+    pub ph_class: Option<PhClass>,
+    pub temperature_class: Option<TemperatureClass>,
+    pub temperature: Range,
+    pub ph: Range,
+    /// Text contained in stored 泉質
+    pub quality: Option<String>,
+    /// Analyses containing at least given mg of components
+    pub components: Vec<ComponentFilter>,
+    /// Analyses within `radius` from `near`
+    pub near: Option<GeoPoint>,
+    pub radius: Distance,
+    pub bbox: Option<BoundingBox>,
     pub skip: u32,
     pub limit: u32,
+    /// Select the page after the cursor instead of skipping `skip`
+    pub after: Option<Cursor>,
     pub order_by: SortKey,
     pub direction: i32
 }
 
+impl SelectOptions {
+    /// Sort clause of `order_by` and `direction`, which cursors are made in.
+    pub fn sort(&self) -> Value {
+        let direction = match self.direction {
+            1 => "asc",
+            _ => "desc"
+        };
+        // Id breaks ties so that cursors point at a unique position
+        json!([
+            self.order_by.to_sort(direction),
+            {KEY_ID_KEYWORD: direction}
+        ])
+    }
+}
+
+/// Inclusive range of a number. Either end may be open.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Range {
+    pub min: Option<f64>,
+    pub max: Option<f64>
+}
+
+impl Range {
+    pub fn new(min: Option<f64>, max: Option<f64>) -> Self {
+        Range { min, max }
+    }
+
+    /// Range query of Elasticsearch, None if both ends are open.
+    fn to_query(self, field: &str) -> Option<Value> {
+        if self.min.is_none() && self.max.is_none() {
+            return None;
+        }
+        let mut range = serde_json::Map::new();
+        if let Some(min) = self.min {
+            range.insert("gte".to_string(), json!(min));
+        }
+        if let Some(max) = self.max {
+            range.insert("lte".to_string(), json!(max));
+        }
+        Some(json!({"range": {field: range}}))
+    }
+}
+
+/// Component whose mg is not less than `min_mg`, given as "CO2:250".
+#[derive(Clone, PartialEq, Debug)]
+pub struct ComponentFilter {
+    pub key: String,
+    pub min_mg: f64
+}
+
+impl FromStr for ComponentFilter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let key = parts.next().unwrap_or("").trim();
+        let min_mg = parts.next()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .ok_or(format!("invalid component filter: {}, \
+                            expected e.g. CO2:250", s))?;
+        if component::lookup(key).is_none() {
+            return Err(format!("unknown component: {}", key));
+        }
+        Ok(ComponentFilter { key: key.to_string(), min_mg })
+    }
+}
+
+impl ComponentFilter {
+    /// Comma separated filters, e.g. "CO2:250,FeII:20".
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        s.split(',')
+            .filter(|f| !f.trim().is_empty())
+            .map(ComponentFilter::from_str)
+            .collect()
+    }
+
+    fn to_query(&self) -> Option<Value> {
+        let table = match component::lookup(&self.key)?.category {
+            Category::PositiveIon => KEY_POSITIVE_ION,
+            Category::NegativeIon => KEY_NEGATIVE_ION,
+            Category::Undissociated => KEY_UNDISSOCIATED,
+            Category::Gas => KEY_GAS,
+            Category::Minor => KEY_MINOR
+        };
+        let field = format!("{}.{}.{}.{}", table, self.key, KEY_MG, KEY_NUMBER);
+        Range::new(Some(self.min_mg), None).to_query(&field)
+    }
+}
+
+/// Analysis name suggested by `suggest`.
+#[derive(Clone, PartialEq, Serialize, Debug)]
+pub struct Suggestion {
+    pub id: String,
+    pub name: String
+}
+
+/// Number of analyses for a value of a facet.
+#[derive(Clone, PartialEq, Serialize, Debug)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: u64
+}
+
+/// Count of matched analyses from `min` up to but not including `max`.
+#[derive(Clone, PartialEq, Serialize, Debug)]
+pub struct RangeCount {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    pub count: u64
+}
+
+/// Counts of matched analyses by classification, stored 泉質, and ranges of
+/// 泉温 and pH. Component filters have no counts. Analyses stored before the
+/// index had keywords of 泉質 are counted once rewritten by `backfill`.
+#[derive(Clone, PartialEq, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Facets {
+    pub tonicity: Vec<FacetCount>,
+    pub ph_class: Vec<FacetCount>,
+    pub temperature_class: Vec<FacetCount>,
+    pub quality: Vec<FacetCount>,
+    pub temperature: Vec<RangeCount>,
+    pub ph: Vec<RangeCount>
+}
+
+const FACET_KEYS: [&str; 3] =
+    [KEY_TONICITY, KEY_PH_CLASS, KEY_TEMPERATURE_CLASS];
+/// Names of aggregations other than by FACET_KEYS
+const FACET_QUALITY: &str = "quality";
+const FACET_TEMPERATURE: &str = "temperature";
+const FACET_PH: &str = "ph";
+/// Most values of 泉質 counted
+const FACET_QUALITY_SIZE: u32 = 50;
+/// Bounds of ranges counted, following the classes of 泉温 and 液性
+const FACET_TEMPERATURE_BOUNDS: &[f64] = &[25.0, 34.0, 42.0, 50.0, 60.0];
+const FACET_PH_BOUNDS: &[f64] = &[3.0, 6.0, 7.5, 8.5];
+
+/// Range aggregation of `field` split at `bounds`, open at both ends.
+fn range_aggregation(field: &str, bounds: &[f64]) -> Value {
+    let mut ranges = vec![json!({"to": bounds[0]})];
+    ranges.extend(bounds.windows(2)
+                  .map(|w| json!({"from": w[0], "to": w[1]})));
+    ranges.push(json!({"from": bounds[bounds.len() - 1]}));
+    json!({"range": {"field": field, "ranges": ranges}})
+}
+
+impl Facets {
+    fn aggs() -> Value {
+        let mut aggs = serde_json::Map::new();
+        for key in FACET_KEYS.iter() {
+            aggs.insert(key.to_string(),
+                        json!({"terms": {"field": key, "size": 20}}));
+        }
+        aggs.insert(FACET_QUALITY.to_string(), json!({
+            "terms": {"field": KEY_QUALITY_KEYWORD, "size": FACET_QUALITY_SIZE}
+        }));
+        aggs.insert(FACET_TEMPERATURE.to_string(),
+                    range_aggregation(KEY_TEMPERATURE,
+                                      FACET_TEMPERATURE_BOUNDS));
+        aggs.insert(FACET_PH.to_string(),
+                    range_aggregation(&format!("{}.{}", KEY_PH, KEY_NUMBER),
+                                      FACET_PH_BOUNDS));
+        Value::Object(aggs)
+    }
+
+    fn from_aggregations(aggregations: Option<&Value>) -> Self {
+        let counts = |key: &str| -> Vec<FacetCount> {
+            aggregations
+                .and_then(|a| a[key]["buckets"].as_array())
+                .map(|buckets| buckets.iter().filter_map(|b| {
+                    Some(FacetCount {
+                        value: b["key"].as_str()?.to_string(),
+                        count: b["doc_count"].as_u64()?
+                    })
+                }).collect())
+                .unwrap_or_default()
+        };
+        let ranges = |key: &str| -> Vec<RangeCount> {
+            aggregations
+                .and_then(|a| a[key]["buckets"].as_array())
+                .map(|buckets| buckets.iter().filter_map(|b| {
+                    Some(RangeCount {
+                        min: b["from"].as_f64(),
+                        max: b["to"].as_f64(),
+                        count: b["doc_count"].as_u64()?
+                    })
+                }).collect())
+                .unwrap_or_default()
+        };
+        Facets {
+            tonicity: counts(KEY_TONICITY),
+            ph_class: counts(KEY_PH_CLASS),
+            temperature_class: counts(KEY_TEMPERATURE_CLASS),
+            quality: counts(FACET_QUALITY),
+            temperature: ranges(FACET_TEMPERATURE),
+            ph: ranges(FACET_PH)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SaveOptions {
+    /// Overwrite mval, mmol and mval% even if they were entered by hand.
+    pub overwrite_derived: bool,
+    /// Rebuild total rows from component tables.
+    pub recompute_totals: bool,
+    /// Save only if the stored analysis is still at this version.
+    pub version: Option<Version>,
+    /// Who saves, recorded in the history of the analysis
+    pub saved_by: Option<String>
+}
+
+#[derive(Debug)]
+pub struct SimilarOptions {
+    pub size: u32
+}
+
+/// Analysis found by `similar` and its closeness in (0, 1].
+#[derive(Serialize, Debug)]
+pub struct SimilarItem {
+    pub analysis: Analysis,
+    pub score: f64
+}
+
+/// How `import` treats an analysis whose id already exists.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ImportMode {
+    Upsert,
+    InsertOnly,
+    SkipExisting
+}
+
+impl FromStr for ImportMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upsert" => Ok(ImportMode::Upsert),
+            "insert-only" => Ok(ImportMode::InsertOnly),
+            "skip-existing" => Ok(ImportMode::SkipExisting),
+            _ => Err(format!("unknown import mode: {}, expected upsert, \
+                              insert-only or skip-existing", s))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportResult {
+    Created(String),
+    Updated(String),
+    Skipped(String)
+}
+
 pub struct SelectResult {
     pub total: u32,
     //pub items: impl Iterator<Item = Analysis>
-    pub items: Box<dyn Iterator<Item = Analysis>>
+    pub items: Box<dyn Iterator<Item = Analysis>>,
+    pub facets: Facets,
+    /// Snippets matching the search text, by analysis id
+    pub highlights: Highlights,
+    /// Cursor to the next page, unless this is the last
+    pub next: Option<Cursor>
 }
 
-pub async fn setup<'a>(models: &Models<'a>) -> Result<String, String> {
-    models.analyses
-        .setup(SetupOptions::new(json!({
-            "settings": {
-                "index": {
-                    "sort.field": "_lamo",
-                    "sort.order": "desc",
-                    "mapping.total_fields.limit": 2048
-                },
+/// Analyzers of readings and romaji. Words are indexed by their prefixes so
+/// that e.g. "kusatsu" and "くさつ" find "草津温泉".
+fn reading_analysis() -> Value {
+    json!({
+        "tokenizer": {
+            "reading_prefix": {
+                "type": "edge_ngram",
+                "min_gram": 1,
+                "max_gram": 20,
+                "token_chars": ["letter", "digit"]
+            }
+        },
+        "analyzer": {
+            "reading_prefix": {
+                "type": "custom",
+                "tokenizer": "reading_prefix",
+                "filter": ["lowercase"]
             },
-            "mappings": {
-                "properties": {
-                    "_lamo": {"type": "float"},
-                    "no": {"type": "text", "analyzer": "kuromoji"},
-                    "name": {"type": "text", "analyzer": "kuromoji"},
-                    "location": {"type": "text", "analyzer": "kuromoji"},
-                    "facilityName": {"type": "text", "analyzer": "kuromoji"},
-                    "roomName": {"type": "text", "analyzer": "kuromoji"},
-                    "applicantAddress": {"type": "text", "analyzer": "kuromoji"},
-                    "applicantName": {"type": "text", "analyzer": "kuromoji"},
-                    "quality": {"type": "text", "analyzer": "kuromoji"},
-                    "investigator": {"type": "text", "analyzer": "kuromoji"},
-                    "perception": {"type": "text", "analyzer": "kuromoji"},
-                    "tester": {"type": "text", "analyzer": "kuromoji"},
-                    "testedPerception": {"type": "text", "analyzer": "kuromoji"},
-                    "heating": {"type": "text", "analyzer": "kuromoji"},
-                    "water": {"type": "text", "analyzer": "kuromoji"},
-                    "circulation": {"type": "text", "analyzer": "kuromoji"},
-                    "chlorination": {"type": "text", "analyzer": "kuromoji"},
-                    "additive": {"type": "text", "analyzer": "kuromoji"},
-                    "header": {"type": "text", "analyzer": "kuromoji"},
-                    "footer": {"type": "text", "analyzer": "kuromoji"},
-                }
+            "reading": {
+                "type": "custom",
+                "tokenizer": "whitespace",
+                "filter": ["lowercase"]
+            }
+        }
+    })
+}
+
+fn reading_mapping() -> Value {
+    json!({
+        "type": "text",
+        "analyzer": "reading_prefix",
+        "search_analyzer": "reading"
+    })
+}
+
+/// Settings and mappings of the index of analyses.
+fn setup_options() -> SetupOptions {
+    SetupOptions::new(json!({
+        "settings": {
+            "index": {
+                "sort.field": "_lamo",
+                "sort.order": "desc",
+                "mapping.total_fields.limit": 2048
+            },
+            "analysis": reading_analysis()
+        },
+        "mappings": {
+            "properties": {
+                "_lamo": {"type": "float"},
+                KEY_TONICITY: {"type": "keyword"},
+                KEY_PH_CLASS: {"type": "keyword"},
+                KEY_TEMPERATURE_CLASS: {"type": "keyword"},
+                KEY_FINGERPRINT: {
+                    "type": "dense_vector",
+                    "dims": FINGERPRINT_DIMENSIONS
+                },
+                KEY_GEO_POINT: {"type": "geo_point"},
+                KEY_SUGGEST: {"type": "completion"},
+                KEY_YOMI: reading_mapping(),
+                KEY_ROMAJI: reading_mapping(),
+                "no": {"type": "text", "analyzer": "kuromoji"},
+                "name": {"type": "text", "analyzer": "kuromoji"},
+                "location": {"type": "text", "analyzer": "kuromoji"},
+                "latitude": {"type": "float"},
+                "longitude": {"type": "float"},
+                "facilityName": {"type": "text", "analyzer": "kuromoji"},
+                "roomName": {"type": "text", "analyzer": "kuromoji"},
+                "applicantAddress": {"type": "text", "analyzer": "kuromoji"},
+                "applicantName": {"type": "text", "analyzer": "kuromoji"},
+                "quality": {
+                    "type": "text",
+                    "analyzer": "kuromoji",
+                    "fields": {
+                        "keyword": {"type": "keyword", "ignore_above": 256}
+                    }
+                },
+                "investigator": {"type": "text", "analyzer": "kuromoji"},
+                "investigatedDate": {"type": "date",
+                                     "format": "yyyy-MM-dd"},
+                "perception": {"type": "text", "analyzer": "kuromoji"},
+                "conductivity": {"type": "float"},
+                "radon": {"type": "float"},
+                "tester": {"type": "text", "analyzer": "kuromoji"},
+                "testedDate": {"type": "date", "format": "yyyy-MM-dd"},
+                "testedPerception": {"type": "text", "analyzer": "kuromoji"},
+                "testedDencity": {"type": "float"},
+                "testedPH": {"type": "float"},
+                "testedER": {"type": "float"},
+                "heating": {"type": "text", "analyzer": "kuromoji"},
+                "water": {"type": "text", "analyzer": "kuromoji"},
+                "circulation": {"type": "text", "analyzer": "kuromoji"},
+                "chlorination": {"type": "text", "analyzer": "kuromoji"},
+                "additive": {"type": "text", "analyzer": "kuromoji"},
+                "header": {"type": "text", "analyzer": "kuromoji"},
+                "footer": {"type": "text", "analyzer": "kuromoji"},
+                KEY_NAME_SORT: {"type": "keyword"},
+                KEY_CREATED_AT: {"type": "double"},
+                KEY_TEMPERATURE: {"type": "float"},
+                KEY_PH: {
+                    "properties": {KEY_NUMBER: {"type": "float"}}
+                },
+                KEY_TOTAL_MELT: {
+                    "properties": {
+                        KEY_MG: {
+                            "properties": {KEY_NUMBER: {"type": "float"}}
+                        }
+                    }
+                },
             }
-        })))
+        }
+    }))
+}
+
+pub async fn setup<'a>(models: &Models<'a>) -> Result<String, String> {
+    models.analyses
+        .setup(setup_options())
         .await
         .map_err(|e| String::from(format!("{}", e)))
 }
 
+/// Add analyzers and fields missing from the index of analyses.
+pub async fn migrate<'a>(models: &Models<'a>) -> Result<String, String> {
+    models.analyses
+        .migrate(setup_options())
+        .await
+        .map_err(|e| format!("{}", e))
+}
+
+/// What the index of analyses lacks, empty if it is up to date.
+pub async fn check<'a>(models: &Models<'a>) -> Result<Vec<String>, String> {
+    models.analyses
+        .check(setup_options())
+        .await
+        .map_err(|e| format!("{}", e))
+}
+
 pub async fn count_total<'a>(models: &Models<'a>) -> Result<u64, String> {
     models.analyses
         .count()
@@ -626,37 +1078,89 @@ pub async fn count_total<'a>(models: &Models<'a>) -> Result<u64, String> {
         .map_err(|e| String::from(format!("{}", e)))
 }
 
+/// Query of analyses matching `options` and `extra` filters.
+fn select_query(options: &SelectOptions, extra: Vec<Value>) -> Option<Value> {
+    let mut must = vec![];
+    if let Some(t) = &options.query {
+        must.push(json!({
+            "multi_match": {
+                "query": t.as_str(),
+                "fields": KEY_FIELDS_SEARCHABLE
+            }
+        }));
+    }
+    let mut filter = extra;
+    for (key, value) in [
+        (KEY_TONICITY, json!(options.tonicity)),
+        (KEY_PH_CLASS, json!(options.ph_class)),
+        (KEY_TEMPERATURE_CLASS, json!(options.temperature_class))
+    ] {
+        if !value.is_null() {
+            filter.push(json!({"term": {key: value}}));
+        }
+    }
+    let ranges = vec![
+        options.temperature.to_query(KEY_TEMPERATURE),
+        options.ph.to_query(&format!("{}.{}", KEY_PH, KEY_NUMBER))
+    ];
+    filter.extend(ranges.into_iter().flatten());
+    filter.extend(options.components.iter().filter_map(|c| c.to_query()));
+    if let Some(q) = &options.quality {
+        filter.push(json!({"match_phrase": {"quality": q}}));
+    }
+    if let Some(p) = &options.near {
+        filter.push(geo::distance_filter(KEY_GEO_POINT, p,
+                                         &options.radius));
+    }
+    if let Some(b) = &options.bbox {
+        filter.push(geo::bounding_box_filter(KEY_GEO_POINT, b));
+    }
+    if must.is_empty() && filter.is_empty() {
+        None
+    } else {
+        Some(json!({
+            "bool": {
+                "must": must,
+                "filter": filter
+            }
+        }))
+    }
+}
+
 pub async fn select<'a>(models: &Models<'a>, options: &SelectOptions) ->
     Result<SelectResult, String> {
-        let query = options.query.as_ref().map(|t| {
-            json!({
-                "multi_match": {
-                    "query": t.as_str(),
-                    "fields": KEY_FIELDS_SEARCHABLE
-                }
-            })
-        });
-        let key = match &options.order_by {
-            SortKey::Id => KEY_ID,
-            SortKey::LastModified => KEY_LAST_MODIFIED
-        };
-        let direction = match options.direction {
-            1 => "asc",
-            _ => "desc"
+        let query = select_query(options, vec![]);
+        let sort = options.sort();
+        let search_after = match &options.after {
+            Some(c) => Some(c.search_after(&sort)?),
+            None => None
         };
         let result = models.analyses.select(SearchOptions {
             query: query,
-            sort: Some(json!([{
-                key: direction
-            }])),
-            from: Some(options.skip),
+            sort: Some(sort.clone()),
+            from: match &options.after {
+                Some(_) => None,
+                None => Some(options.skip)
+            },
             size: Some(options.limit),
+            search_after,
+            aggs: Some(Facets::aggs()),
+            highlight: options.query.as_ref()
+                .map(|_| es::highlight(KEY_FIELDS_SEARCHABLE)),
             ..Default::default()
         }).await;
         debug!("analyses::select, result: {:?}", &result);
         match result {
             Ok(result) => Ok(SelectResult {
                 total: result.hits.total.value as u32,
+                next: result.next_cursor(&sort, options.limit),
+                facets: Facets::from_aggregations(
+                    result.aggregations.as_ref()),
+                // Readings and romaji are not shown as they are
+                highlights: result.highlights(|key| match key {
+                    KEY_YOMI | KEY_ROMAJI => None,
+                    key => Some(key)
+                }),
                 items: Box::new(result.hits.hits
                                 .into_iter()
                                 .filter_map(|row| {
@@ -667,8 +1171,147 @@ pub async fn select<'a>(models: &Models<'a>, options: &SelectOptions) ->
         }
 }
 
+/// Every analysis matching `options`, read page by page regardless of
+/// `skip` and `limit`.
+pub async fn select_all<'a>(models: &Models<'a>, options: &SelectOptions)
+    -> Result<Vec<Analysis>, String>
+{
+    let mut items = vec![];
+    let mut after = None;
+    loop {
+        let page = SelectOptions {
+            skip: 0,
+            limit: SELECT_ALL_PAGE_SIZE,
+            after,
+            ..options.clone()
+        };
+        let result = select(models, &page).await?;
+        let count = items.len();
+        items.extend(result.items);
+        match result.next {
+            Some(next) if items.len() > count => after = Some(next),
+            _ => break Ok(items)
+        }
+    }
+}
+
+/// Fields read for GeoJSON features.
+const KEY_FIELDS_FEATURE: &[&str] = &[
+    KEY_NAME, KEY_TEMPERATURE, KEY_PH,
+    KEY_TONICITY, KEY_PH_CLASS, KEY_TEMPERATURE_CLASS
+];
+/// Fields of metadata read for GeoJSON features.
+const KEY_FIELDS_FEATURE_META: &[&str] =
+    &["location", "latitude", "longitude", "facilityName", "quality"];
+
+/// Analysis with only the fields read for GeoJSON features.
+fn feature_analysis(row: &SearchResultItem) -> Option<Analysis> {
+    let obj = row._source.as_object()?;
+    let mut a = Analysis::new(obj.get(KEY_NAME)?.as_str()?);
+    a.id = Some(row._id.to_string());
+    a.temperature = obj.get(KEY_TEMPERATURE).and_then(|v| v.as_f64());
+    a.ph = obj.get(KEY_PH)
+        .and_then(|v| CellValue::try_from(v).ok())
+        .unwrap_or(CellValue::Null);
+    a.meta = Metadata::from_fields(obj.iter()
+        .filter(|(key, _)| KEY_FIELDS_FEATURE_META.contains(&key.as_str()))
+        .map(|(key, value)| (key.to_string(), value.clone()))
+        .collect());
+    a.computed.tonicity = from_value_opt(obj.get(KEY_TONICITY));
+    a.computed.ph_class = from_value_opt(obj.get(KEY_PH_CLASS));
+    a.computed.temperature_class =
+        from_value_opt(obj.get(KEY_TEMPERATURE_CLASS));
+    Some(a)
+}
+
+/// Every geolocated analysis matching `options`, regardless of `skip` and
+/// `limit`, read with only the fields shown in GeoJSON features.
+pub async fn select_features<'a>(models: &Models<'a>, options: &SelectOptions)
+    -> Result<Vec<Analysis>, String>
+{
+    let query = select_query(options, vec![
+        json!({"exists": {"field": KEY_GEO_POINT}})
+    ]);
+    let sort = json!([{KEY_ID_KEYWORD: "asc"}]);
+    let mut items = vec![];
+    let mut after = None;
+    loop {
+        let result = models.analyses.select(SearchOptions {
+            query: query.clone(),
+            sort: Some(sort.clone()),
+            size: Some(SELECT_ALL_PAGE_SIZE),
+            search_after: after,
+            source: Some(json!(KEY_FIELDS_FEATURE.iter()
+                               .chain(KEY_FIELDS_FEATURE_META)
+                               .collect::<Vec<_>>())),
+            ..Default::default()
+        }).await.map_err(|e| format!("{}", e))?;
+        debug!("analyses::select_features, hits: {}", result.hits.hits.len());
+        items.extend(result.hits.hits.iter().filter_map(feature_analysis));
+        match result.next_cursor(&sort, SELECT_ALL_PAGE_SIZE) {
+            Some(next) => after = Some(next.search_after(&sort)?),
+            None => break Ok(items)
+        }
+    }
+}
+
+/// Analyses whose fingerprints are closest to the one of `id`.
+/// Returns None if `id` does not exist.
+pub async fn similar<'a>(models: &Models<'a>, id: &String,
+                         options: &SimilarOptions)
+    -> Result<Option<Vec<SimilarItem>>, String>
+{
+    let fingerprint = match by_id(models, id).await? {
+        Some(a) => a.fingerprint(),
+        None => return Ok(None)
+    };
+    let fingerprint = match fingerprint {
+        Some(v) => v,
+        None => return Ok(Some(vec![]))
+    };
+    let result = models.analyses.select(SearchOptions {
+        query: Some(json!({
+            "script_score": {
+                "query": {
+                    "bool": {
+                        "filter": {"exists": {"field": KEY_FINGERPRINT}},
+                        "must_not": {"ids": {"values": [id]}}
+                    }
+                },
+                "script": {
+                    "source": format!("1 / (1 + l2norm(params.v, '{}'))",
+                                      KEY_FINGERPRINT),
+                    "params": {"v": fingerprint}
+                }
+            }
+        })),
+        size: Some(options.size),
+        ..Default::default()
+    }).await;
+    debug!("analyses::similar, result: {:?}", &result);
+    match result {
+        Ok(result) => Ok(Some(result.hits.hits.iter()
+                              .filter_map(|row| {
+                                  Analysis::try_from(row).ok()
+                                      .map(|a| SimilarItem {
+                                          analysis: a,
+                                          score: row._score.unwrap_or(0.0)
+                                      })
+                              })
+                              .collect())),
+        Err(e) => Err(format!("{}", e))
+    }
+}
+
 pub async fn by_id<'a>(models: &Models<'a>, id: &String)
     -> Result<Option<Analysis>, String>
+{
+    by_id_with_version(models, id).await.map(|a| a.map(|(a, _)| a))
+}
+
+/// Analysis and its version, to be given to `SaveOptions::version`.
+pub async fn by_id_with_version<'a>(models: &Models<'a>, id: &String)
+    -> Result<Option<(Analysis, Version)>, String>
 {
     debug!("analyses::by_id, id: {}", &id);
     let result = models.analyses
@@ -676,25 +1319,35 @@ pub async fn by_id<'a>(models: &Models<'a>, id: &String)
         .await;
     debug!("analyses::by_id, result: {:?}", &result);
     match result {
-        Ok(row) => Analysis::try_from(&row).map(|a| Some(a)),
+        Ok(Some(row)) =>
+            Analysis::try_from(&row).map(|a| Some((a, row.version()))),
+        Ok(None) => Ok(None),
         Err(e) => Err(String::from(format!("{}", e)))
     }
 }
 
-pub async fn save<'a>(models: &Models<'a>, a: &Analysis)
-    -> Result<Analysis, String> 
+/// Save analysis and return it with the version saved. Fails with
+/// `SaveError::Conflict` if `options.version` is given and is stale, or if
+/// the analysis is changed by someone else while saving. The analysis
+/// replaced is appended to its history.
+pub async fn save<'a>(models: &Models<'a>, a: &Analysis,
+                      options: &SaveOptions)
+    -> Result<(Analysis, Version), SaveError>
 {
     let (id, is_new) = match &a.id {
         Some(id) => (id.to_string(), false),
-        None => match create_unique_id(models, a).await {
-            Ok(id) => (id, true),
-            Err(e) => return Err(e)
-        }
+        None => (create_unique_id(models, a).await?, true)
     };
     // Clone analysis
     let mut a: Analysis = a.clone();
     // Update id
     a.id = Some(id.to_string());
+    // Fill values computable from mg
+    a.derive_values(options.overwrite_derived);
+    if options.recompute_totals {
+        a.recompute_totals();
+    }
+    a.compute();
     // Update created_at if needed
     let epoch = SystemTime::now().duration_since(UNIX_EPOCH)
         .map(|d| d.as_secs_f64())
@@ -705,7 +1358,11 @@ pub async fn save<'a>(models: &Models<'a>, a: &Analysis)
     a.created_at = a.created_at.or(Some(epoch));
     // Update last_modified
     a.last_modified = Some(epoch);
-    let value = Value::from(&a);
+    let mut value = to_document(&a);
+    if let Some(user) = &options.saved_by {
+        value.as_object_mut().unwrap()
+            .insert(KEY_SAVED_BY.to_string(), json!(user));
+    }
     // Write to DB
     debug!("analyses::save, is_new: {}, value: {}", &is_new, &value);
     let result = if is_new {
@@ -715,27 +1372,105 @@ pub async fn save<'a>(models: &Models<'a>, a: &Analysis)
             .map(|r| {
                 debug!("analyses::save, created, result: {:?}", &r);
                 match r.result {
-                    OperationResultType::Created => Some(a),
+                    OperationResultType::Created => Some(r.version()),
                     _ => None
                 }
             })
+            .map_err(|e| format!("{}", e))
     } else {
-        models.analyses
-            .update(&value, UpdateOptions::new(id.as_str()))
-            .await
-            .map(|r| {
-                debug!("analyses::save, updated, result: {:?}", &r);
-                match r.result {
-                    OperationResultType::Updated => Some(a),
-                    _ => None
+        // An analysis changed meanwhile is read again and replaced, unless
+        // the caller expects a version to replace
+        let mut tries = 0;
+        loop {
+            let previous = models.analyses.get(GetOptions::new(id.as_str()))
+                .await
+                .map_err(|e| SaveError::Failed(format!("{}", e)))?;
+            let current = previous.as_ref().map(|p| p.version());
+            if options.version.is_some() && current.is_some() &&
+                options.version != current
+            {
+                return Err(SaveError::Conflict);
+            }
+            let result = match &previous {
+                Some(p) => replace(models, &id, p, &value).await,
+                None => {
+                    let update = UpdateOptions::new(id.as_str())
+                        .if_version(options.version);
+                    models.analyses.update(&value, update).await
+                        .map_err(|e| format!("{}", e))
                 }
-            })
+            };
+            tries += 1;
+            break match result {
+                Ok(UpdateResult::Conflict)
+                    if options.version.is_none() && tries < MAX_SAVE_TRIES =>
+                    continue,
+                Ok(UpdateResult::Conflict) => return Err(SaveError::Conflict),
+                Ok(UpdateResult::Updated(r)) => {
+                    debug!("analyses::save, updated, result: {:?}", &r);
+                    match r.result {
+                        OperationResultType::Updated => Ok(Some(r.version())),
+                        _ => Ok(None)
+                    }
+                },
+                Err(e) => Err(e)
+            };
+        }
     };
     match result {
-        Ok(Some(a)) => Ok(a),
-        Ok(None) => Err(String::from(format!("unexpected result in
-                                             analyses::save"))),
-        Err(e) => Err(String::from(format!("{}", e)))
+        Ok(Some(version)) => Ok((a, version)),
+        Ok(None) => Err(SaveError::Failed(
+            String::from("unexpected result in analyses::save"))),
+        Err(e) => Err(SaveError::Failed(e))
+    }
+}
+
+/// Replace stored analysis `previous` by `value`, appending `previous` to the
+/// history first so that no version is replaced without it. The revision is
+/// removed again unless `previous` is replaced, e.g. if it is changed
+/// meanwhile.
+async fn replace<'a>(models: &Models<'a>, id: &str, previous: &GetResult,
+                     value: &Value) -> Result<UpdateResult, String>
+{
+    let revision = analyses_history::append(models, id, &previous._source)
+        .await
+        .map_err(|e| format!("history of analysis {} is not appended, e: {}",
+                             id, &e))?;
+    let update = UpdateOptions::new(id).if_version(Some(previous.version()));
+    let result = models.analyses.update(value, update).await;
+    let replaced = match &result {
+        Ok(UpdateResult::Updated(r)) =>
+            matches!(r.result, OperationResultType::Updated),
+        _ => false
+    };
+    if !replaced {
+        if let Err(e) = analyses_history::remove(models, id,
+                                                 revision.revision).await {
+            warn!("Failed to remove revision {} of analysis {} which is not \
+                   replaced, e: {}", revision.revision, id, &e);
+        }
+    }
+    result.map_err(|e| format!("{}", e))
+}
+
+/// Save revision `revision` of analysis `id` as the latest, or None if there
+/// is no such revision. The analysis replaced is appended to the history.
+pub async fn restore<'a>(models: &Models<'a>, id: &str, revision: u32,
+                         options: &SaveOptions)
+    -> Result<Option<(Analysis, Version)>, SaveError>
+{
+    match analyses_history::by_revision(models, id, revision).await? {
+        Some((_, a)) => {
+            // Derived values and totals are restored as they were saved
+            let options = SaveOptions {
+                overwrite_derived: false,
+                recompute_totals: false,
+                version: options.version,
+                saved_by: options.saved_by.clone()
+            };
+            save(models, &a, &options).await.map(Some)
+        },
+        None => Ok(None)
     }
 }
 
@@ -759,6 +1494,172 @@ pub async fn delete<'a>(models: &Models<'a>, id: &str) -> Result<String, String>
 }
 
 const MAX_ID_SERIAL: usize = 99;
+/// How many times `save` reads and replaces an analysis changed meanwhile.
+const MAX_SAVE_TRIES: u32 = 5;
+
+/// Write an analysis read from a dump. Unlike `save`, the id and
+/// timestamps are kept as they are; only computed values are refreshed.
+/// The analysis replaced is appended to its history.
+pub async fn import<'a>(models: &Models<'a>, a: &Analysis, mode: ImportMode)
+    -> Result<ImportResult, String>
+{
+    let mut a: Analysis = a.clone();
+    a.compute();
+    let id = match &a.id {
+        Some(id) => id.to_string(),
+        None => create_unique_id(models, &a).await?
+    };
+    a.id = Some(id.to_string());
+    let previous = models.analyses.get(GetOptions::new(id.as_str())).await
+        .map_err(|e| format!("{}", e))?;
+    if let ImportResult::Skipped(id) =
+        import_result(id.clone(), previous.is_some(), mode)?
+    {
+        return Ok(ImportResult::Skipped(id));
+    }
+    let value = to_document(&a);
+    debug!("analyses::import, exists: {}, value: {}",
+           previous.is_some(), &value);
+    let result = if let Some(p) = &previous {
+        // Overwritten analyses go to the history as in `save`
+        match replace(models, &id, p, &value).await {
+            Ok(UpdateResult::Updated(r)) => Ok(Some(r.result)),
+            Ok(UpdateResult::Conflict) =>
+                return Err(format!("analysis is changed while importing: {}",
+                                   &id)),
+            Err(e) => Err(e)
+        }
+    } else {
+        models.analyses
+            .insert(&value, InsertOptions::new(Some(id.as_str())))
+            .await
+            .map(|r| Some(r.result))
+            .map_err(|e| format!("{}", e))
+    };
+    match result {
+        Ok(Some(OperationResultType::Created)) =>
+            Ok(ImportResult::Created(id)),
+        Ok(Some(OperationResultType::Updated)) =>
+            Ok(ImportResult::Updated(id)),
+        Ok(r) => Err(format!("unexpected result in analyses::import: {:?}", r)),
+        Err(e) => Err(e)
+    }
+}
+
+/// What `import` does with analysis `id` in `mode`, whether it `exists`.
+fn import_result(id: String, exists: bool, mode: ImportMode)
+    -> Result<ImportResult, String>
+{
+    match (exists, mode) {
+        (true, ImportMode::SkipExisting) => Ok(ImportResult::Skipped(id)),
+        (true, ImportMode::InsertOnly) =>
+            Err(format!("analysis already exists: {}", &id)),
+        (true, ImportMode::Upsert) => Ok(ImportResult::Updated(id)),
+        (false, _) => Ok(ImportResult::Created(id))
+    }
+}
+
+/// Tell what `import` would do with an analysis, without writing it.
+pub async fn check_import<'a>(models: &Models<'a>, a: &Analysis,
+                              mode: ImportMode)
+    -> Result<ImportResult, String>
+{
+    let id = match &a.id {
+        Some(id) => id.to_string(),
+        None => create_unique_id(models, a).await?
+    };
+    let previous = models.analyses.get(GetOptions::new(id.as_str())).await
+        .map_err(|e| format!("{}", e))?;
+    import_result(id, previous.is_some(), mode)
+}
+
+/// Rewrite stored analysis `id` through `to_document`, filling fields added
+/// to the index since it was saved. Unlike `save`, timestamps are kept and
+/// no revision is appended.
+pub async fn backfill<'a>(models: &Models<'a>, id: &str)
+    -> Result<Version, SaveError>
+{
+    let row = models.analyses.get(GetOptions::new(id)).await
+        .map_err(|e| SaveError::Failed(format!("{}", e)))?
+        .ok_or_else(|| SaveError::Failed(format!("no analysis: {}", id)))?;
+    let mut a = Analysis::try_from(&row)?;
+    a.compute();
+    let mut value = to_document(&a);
+    if let Some(user) = row._source.get(KEY_SAVED_BY) {
+        value[KEY_SAVED_BY] = user.clone();
+    }
+    let update = UpdateOptions::new(id).if_version(Some(row.version()));
+    match models.analyses.update(&value, update).await {
+        Ok(UpdateResult::Updated(r)) => Ok(r.version()),
+        Ok(UpdateResult::Conflict) => Err(SaveError::Conflict),
+        Err(e) => Err(SaveError::Failed(format!("{}", e)))
+    }
+}
+
+/// Delete the index of analyses with all analyses, to be set up again.
+pub async fn teardown<'a>(models: &Models<'a>) -> Result<String, String> {
+    models.analyses
+        .teardown()
+        .await
+        .map_err(|e| format!("{}", e))
+}
+
+/// Document to store, with the reading and romaji of the name for search,
+/// sort and suggestions in kanji, kana and romaji.
+fn to_document(a: &Analysis) -> Value {
+    let mut value = Value::from(a);
+    let words = scrub::yomi_words(&a.name);
+    let yomi = words.join("");
+    let romaji = scrub::romaji(&yomi);
+    let yomi_words = words.join(" ");
+    let romaji_words = words.iter().map(scrub::romaji)
+        .collect::<Vec<String>>().join(" ");
+    let hiragana = scrub::hiragana(&yomi);
+    let mut inputs = vec![a.name.clone(), hiragana.clone(), yomi,
+                          romaji.clone()];
+    inputs.retain(|s| !s.is_empty());
+    inputs.dedup();
+    let obj = value.as_object_mut().unwrap();
+    obj.insert(KEY_SUGGEST.to_string(), json!({"input": inputs}));
+    obj.insert(KEY_YOMI.to_string(),
+               json!([scrub::hiragana(&yomi_words), yomi_words]));
+    obj.insert(KEY_ROMAJI.to_string(), json!([romaji_words, romaji]));
+    obj.insert(KEY_NAME_SORT.to_string(), json!(hiragana));
+    value
+}
+
+/// Names of analyses starting with `prefix`. Analyses stored before the
+/// index had suggestions are found once rewritten by `backfill`.
+pub async fn suggest<'a>(models: &Models<'a>, prefix: &str, size: u32)
+    -> Result<Vec<Suggestion>, String>
+{
+    let result = models.analyses.select(SearchOptions {
+        size: Some(0),
+        suggest: Some(json!({
+            "name": {
+                "prefix": prefix,
+                "completion": {
+                    "field": KEY_SUGGEST,
+                    "size": size,
+                    "skip_duplicates": true
+                }
+            }
+        })),
+        source: Some(json!([KEY_ID, KEY_NAME])),
+        ..Default::default()
+    }).await;
+    debug!("analyses::suggest, result: {:?}", &result);
+    let result = result.map_err(|e| format!("{}", e))?;
+    let options = result.suggest.as_ref()
+        .and_then(|s| s["name"][0]["options"].as_array().cloned())
+        .unwrap_or_default();
+    Ok(options.iter().filter_map(|o| {
+        Some(Suggestion {
+            id: o["_id"].as_str()?.to_string(),
+            name: o["_source"][KEY_NAME].as_str()?.to_string()
+        })
+    }).collect())
+}
 
 pub async fn create_unique_id<'a>(models: &Models<'a>, a: &Analysis)
                         -> Result<String, String> {
@@ -775,13 +1676,9 @@ pub async fn create_unique_id<'a>(models: &Models<'a>, a: &Analysis)
             format!("{}-{}", &base, i)
         };
         match models.analyses.get(GetOptions::new(tryid.as_str())).await {
-            Ok(_) => i += 1,
-            Err(e) => {
-                info!("Cannot create unique id, e: {}", &e);
-                break Ok(tryid)    
-            }
-            // Ok(None) => break Ok(tryid),
-            // Err(e) => break Err(format!("Cannot create uniquid id, e: {}", e))
+            Ok(Some(_)) => i += 1,
+            Ok(None) => break Ok(tryid),
+            Err(e) => break Err(format!("Cannot create uniquid id, e: {}", e))
         }
     }
 }
@@ -796,25 +1693,19 @@ mod tests {
 
     #[test]
     fn test_document_from_mg_mval_mmol_empty() {
-        let it = doc! {};
+        let it = json!({});
         let r = MgMvalMmol::try_from(&it);
-        assert!(r.is_ok());
-        assert_eq!(r.unwrap(), MgMvalMmol {
-            mg: CellValue::Null,
-            mval: CellValue::Null,
-            mmol: CellValue::Null,
-            mval_percent: CellValue::Null
-        });
+        assert!(r.is_err());
     }
 
     #[test]
     fn test_document_from_mg_mval_mmol_with_null() {
-        let it = doc! {
-            KEY_MG: Bson::Null,
-            KEY_MVAL: Bson::Null,
-            KEY_MMOL: Bson::Null,
-            KEY_MVAL_PERCENT: Bson::Null
-        };
+        let it = json!({
+            KEY_MG: null,
+            KEY_MVAL: null,
+            KEY_MMOL: null,
+            KEY_MVAL_PERCENT: null
+        });
         let r = MgMvalMmol::try_from(&it);
         assert!(r.is_ok());
         assert_eq!(r.unwrap(), MgMvalMmol {
@@ -827,12 +1718,12 @@ mod tests {
 
     #[test]
     fn test_document_from_mg_mval_mmol_with_number() {
-        let it = doc! {
-            KEY_MG: 4078.0,
-            KEY_MVAL: 177.4,
-            KEY_MMOL: 177.4,
-            KEY_MVAL_PERCENT: 87.65
-        };
+        let it = json!({
+            KEY_MG: {KEY_NUMBER: 4078.0},
+            KEY_MVAL: {KEY_NUMBER: 177.4},
+            KEY_MMOL: {KEY_NUMBER: 177.4},
+            KEY_MVAL_PERCENT: {KEY_NUMBER: 87.65}
+        });
         let r = MgMvalMmol::try_from(&it);
         assert!(r.is_ok());
         assert_eq!(r.unwrap(), MgMvalMmol {
@@ -845,206 +1736,197 @@ mod tests {
 
     #[test]
     fn test_document_from_mg_mval_mmol_with_text() {
-        let it = doc! {
-            KEY_MG: ">0.01",
-            KEY_MVAL: ">0.01",
-            KEY_MMOL: "--",
-            KEY_MVAL_PERCENT: ""
-        };
+        let it = json!({
+            KEY_MG: {KEY_TEXT: "微量"},
+            KEY_MVAL: {KEY_TEXT: "--"},
+            KEY_MMOL: {KEY_TEXT: "--"},
+            KEY_MVAL_PERCENT: {KEY_TEXT: ""}
+        });
         let r = MgMvalMmol::try_from(&it);
         assert!(r.is_ok());
         assert_eq!(r.unwrap(), MgMvalMmol {
-            mg: CellValue::Text(">0.01".to_string()),
-            mval: CellValue::Text(">0.01".to_string()),
+            mg: CellValue::Trace("微量".to_string()),
+            mval: CellValue::Text("--".to_string()),
             mmol: CellValue::Text("--".to_string()),
             mval_percent: CellValue::Text("".to_string())
         });
     }
 
-    #[test]
-    fn test_document_from_analysis() {
-        let mut meta = HashMap::new();
-        meta.insert("quality".to_string(),
-                    "含硫黄－ナトリウム－塩化物温泉 (硫化水素型)".to_string());
-        let it = Analysis {
-            id: Some("amenakaonsen".to_string()),
-            name: "雨中温泉".to_string(),
-            gensen_yield: CellValue::Number(1220.0),
-            temperature: Some(48.8),
-            ph: CellValue::Number(7.5),
-            positive_ion: ComponentTable::new(),
-            negative_ion: ComponentTable::new(),
-            undissociated: ComponentTable::new(),
-            gas: ComponentTable::new(),
-            minor: ComponentTable::new(),
-            total_positive_ion: MgMvalMmol {
-                mg: CellValue::Number(4583.0),
-                mval: CellValue::Number(202.3),
-                mmol: CellValue::Text("--".to_string()),
-                mval_percent: CellValue::Null
-            },
-            total_negative_ion: MgMvalMmol {
-                mg: CellValue::Number(7881.0),
-                mval: CellValue::Number(216.6),
-                mmol: CellValue::Text("--".to_string()),
-                mval_percent: CellValue::Null
-            },
-            total_undissociated: MgMvalMmol {
-                mg: CellValue::Number(164.3),
-                mval: CellValue::Number(0.0),
-                mmol: CellValue::Number(2.63),
-                mval_percent: CellValue::Null,
-            },
-            total_gas: MgMvalMmol {
-                mg: CellValue::Number(29.0),
-                mval: CellValue::Number(0.0),
-                mmol: CellValue::Number(0.7),
-                mval_percent: CellValue::Null
-            },
-            total_minor: MgMvalMmol {
-                mg: CellValue::Null,
-                mval: CellValue::Null,
-                mmol: CellValue::Null,
-                mval_percent: CellValue::Null
-            },
-            meta: meta
+    fn amenaka() -> Analysis {
+        let mut a = Analysis::new("雨中温泉");
+        a.id = Some("amenakaonsen".to_string());
+        a.gensen_yield = CellValue::Number(1220.0);
+        a.temperature = Some(48.8);
+        a.ph = CellValue::Number(7.5);
+        a.total_positive_ion = MgMvalMmol {
+            mg: CellValue::Number(4583.0),
+            mval: CellValue::Number(202.3),
+            mmol: CellValue::Text("--".to_string()),
+            mval_percent: CellValue::Null
         };
-        let r = Document::try_from(&it);
-        if !r.is_ok() {
-            println!("Error {:?}", &r);
-        }
-        assert!(r.is_ok());
-        let d = doc! {
-            KEY_ID: "amenakaonsen",
-            KEY_NAME: "雨中温泉",
-            KEY_YIELD: 1220.0,
-            KEY_TEMPERATURE: 48.8,
-            KEY_PH: 7.5,
-            KEY_POSITIVE_ION: {},
-            KEY_NEGATIVE_ION: {},
-            KEY_UNDISSOCIATED: {},
-            KEY_GAS: {},
-            KEY_MINOR: {},
-            KEY_TOTAL_POSITIVE_ION: {
-                KEY_MG: 4583.0,
-                KEY_MVAL: 202.3,
-                KEY_MVAL_PERCENT: Bson::Null,
-                KEY_MMOL: "--"
-            },
-            KEY_TOTAL_NEGATIVE_ION: {
-                KEY_MG: 7881.0,
-                KEY_MVAL: 216.6,
-                KEY_MVAL_PERCENT: Bson::Null,
-                KEY_MMOL: "--"
-            },
-            KEY_TOTAL_UNDISSOCIATED: {
-                KEY_MG: 164.3,
-                KEY_MVAL: 0.0,
-                KEY_MVAL_PERCENT: Bson::Null,
-                KEY_MMOL: 2.63
-            },
-            KEY_TOTAL_GAS: {
-                KEY_MG: 29.0,
-                KEY_MVAL: 0.0,
-                KEY_MVAL_PERCENT: Bson::Null,
-                KEY_MMOL: 0.7
-            },
-            KEY_TOTAL_MINOR: {
-                KEY_MG: Bson::Null,
-                KEY_MVAL: Bson::Null,
-                KEY_MVAL_PERCENT: Bson::Null,
-                KEY_MMOL: Bson::Null
-            },
-            "quality": "含硫黄－ナトリウム－塩化物温泉 (硫化水素型)"
+        a.total_negative_ion = MgMvalMmol {
+            mg: CellValue::Number(7881.0),
+            mval: CellValue::Number(216.6),
+            mmol: CellValue::Text("--".to_string()),
+            mval_percent: CellValue::Null
         };
-        assert_eq!(r.unwrap(), d);
+        a.meta.quality =
+            Some("含硫黄－ナトリウム－塩化物温泉 (硫化水素型)".to_string());
+        a
+    }
+
+    #[test]
+    fn test_document_from_analysis() {
+        let d = Value::from(&amenaka());
+        assert_eq!(d[KEY_ID], json!("amenakaonsen"));
+        assert_eq!(d[KEY_NAME], json!("雨中温泉"));
+        assert_eq!(d[KEY_YIELD], json!({KEY_NUMBER: 1220.0}));
+        assert_eq!(d[KEY_TEMPERATURE], json!(48.8));
+        assert_eq!(d[KEY_PH], json!({KEY_NUMBER: 7.5}));
+        assert_eq!(d[KEY_POSITIVE_ION], json!({}));
+        assert_eq!(d[KEY_TOTAL_POSITIVE_ION], json!({
+            KEY_MG: {KEY_NUMBER: 4583.0},
+            KEY_MVAL: {KEY_NUMBER: 202.3},
+            KEY_MVAL_PERCENT: null,
+            KEY_MMOL: {KEY_TEXT: "--"}
+        }));
+        assert_eq!(d[KEY_TOTAL_MINOR], json!({
+            KEY_MG: null,
+            KEY_MVAL: null,
+            KEY_MVAL_PERCENT: null,
+            KEY_MMOL: null
+        }));
+        assert_eq!(d["quality"],
+                   json!("含硫黄－ナトリウム－塩化物温泉 (硫化水素型)"));
     }
 
     #[test]
     fn test_analysis_from_document() {
-        let it = doc! {
-            KEY_ID: "amenakaonsen",
-            KEY_POSITIVE_ION: {},
-            KEY_NEGATIVE_ION: {},
-            KEY_UNDISSOCIATED: {},
-            KEY_GAS: {},
-            KEY_MINOR: {},
-            KEY_TOTAL_POSITIVE_ION: {
-                KEY_MG: 4583.0,
-                KEY_MVAL: 202.3,
-                KEY_MMOL: "--"
-            },
-            KEY_TOTAL_NEGATIVE_ION: {
-                KEY_MG: 7881.0,
-                KEY_MVAL: 216.6,
-                KEY_MMOL: "--"
-            },
-            KEY_TOTAL_UNDISSOCIATED: {
-                KEY_MG: 164.3,
-                KEY_MVAL: 0.0,
-                KEY_MMOL: 2.63
-            },
-            KEY_TOTAL_GAS: {
-                KEY_MG: 29.0,
-                KEY_MVAL: 0.0,
-                KEY_MMOL: 0.7
-            },
-            KEY_NAME: "雨中温泉",
-            KEY_PH: 7.5,
-            KEY_YIELD: 1220.0,
-            KEY_TEMPERATURE: 48.8,
-            "quality": "含硫黄－ナトリウム－塩化物温泉 (硫化水素型)"
-        };
-        let r = Analysis::try_from(&it);
+        let it = amenaka();
+        let r = Analysis::try_from(&Value::from(&it));
         if !r.is_ok() {
             println!("Error {:?}", &r);
         }
         assert!(r.is_ok());
-        let mut meta = HashMap::new();
-        meta.insert("quality".to_string(),
-                    "含硫黄－ナトリウム－塩化物温泉 (硫化水素型)".to_string());
-        assert_eq!(r.unwrap(), Analysis {
-            id: Some("amenakaonsen".to_string()),
-            name: "雨中温泉".to_string(),
-            gensen_yield: CellValue::Number(1220.0),
-            temperature: Some(48.8),
-            ph: CellValue::Number(7.5),
-            positive_ion: ComponentTable::new(),
-            negative_ion: ComponentTable::new(),
-            undissociated: ComponentTable::new(),
-            gas: ComponentTable::new(),
-            minor: ComponentTable::new(),
-            total_positive_ion: MgMvalMmol {
-                mg: CellValue::Number(4583.0),
-                mval: CellValue::Number(202.3),
-                mmol: CellValue::Text("--".to_string()),
-                mval_percent: CellValue::Null
-            },
-            total_negative_ion: MgMvalMmol {
-                mg: CellValue::Number(7881.0),
-                mval: CellValue::Number(216.6),
-                mmol: CellValue::Text("--".to_string()),
-                mval_percent: CellValue::Null
-            },
-            total_undissociated: MgMvalMmol {
-                mg: CellValue::Number(164.3),
-                mval: CellValue::Number(0.0),
-                mmol: CellValue::Number(2.63),
-                mval_percent: CellValue::Null,
-            },
-            total_gas: MgMvalMmol {
-                mg: CellValue::Number(29.0),
-                mval: CellValue::Number(0.0),
-                mmol: CellValue::Number(0.7),
-                mval_percent: CellValue::Null
-            },
-            total_minor: MgMvalMmol {
-                mg: CellValue::Null,
-                mval: CellValue::Null,
-                mmol: CellValue::Null,
-                mval_percent: CellValue::Null
-            },
-            meta: meta
+        assert_eq!(r.unwrap(), it);
+    }
+
+    #[test]
+    fn it_sort_by_key() {
+        assert_eq!(SortKey::Id.to_sort("asc"), json!({
+            "id.keyword": {"order": "asc", "missing": "_last"}
+        }));
+        assert_eq!(SortKey::Name.to_sort("asc"), json!({
+            "_nmso": {"order": "asc", "missing": "_last"}
+        }));
+        assert_eq!(SortKey::Ph.to_sort("desc"), json!({
+            "ph.n": {"order": "desc", "missing": "_last"}
+        }));
+        assert_eq!(SortKey::TotalMelt.to_sort("desc"), json!({
+            "tome.mg.n": {"order": "desc", "missing": "_last"}
+        }));
+        assert_eq!(SortKey::Score.to_sort("desc"), json!({"_score": "desc"}));
+    }
+
+    #[test]
+    fn it_decide_import_result() {
+        let id = || "a".to_string();
+        assert!(matches!(import_result(id(), false, ImportMode::InsertOnly),
+                         Ok(ImportResult::Created(_))));
+        assert!(import_result(id(), true, ImportMode::InsertOnly).is_err());
+        assert!(matches!(import_result(id(), true, ImportMode::Upsert),
+                         Ok(ImportResult::Updated(_))));
+        assert!(matches!(import_result(id(), true, ImportMode::SkipExisting),
+                         Ok(ImportResult::Skipped(_))));
+    }
+
+    #[test]
+    fn it_make_range_query() {
+        assert_eq!(Range::default().to_query(KEY_TEMPERATURE), None);
+        assert_eq!(Range::new(Some(42.0), None).to_query(KEY_TEMPERATURE),
+                   Some(json!({"range": {"temp": {"gte": 42.0}}})));
+        assert_eq!(Range::new(Some(2.0), Some(3.0)).to_query("ph.n"),
+                   Some(json!({"range": {"ph.n": {"gte": 2.0, "lte": 3.0}}})));
+    }
+
+    #[test]
+    fn it_parse_component_filters() {
+        let it = ComponentFilter::parse_list("CO2:250, FeII:20,").unwrap();
+        assert_eq!(it, vec![
+            ComponentFilter { key: "CO2".to_string(), min_mg: 250.0 },
+            ComponentFilter { key: "FeII".to_string(), min_mg: 20.0 }
+        ]);
+        assert_eq!(it[0].to_query(),
+                   Some(json!({"range": {"gas.CO2.mg.n": {"gte": 250.0}}})));
+        assert!(ComponentFilter::parse_list("CO2").is_err());
+        assert!(ComponentFilter::parse_list("XX:1").is_err());
+        assert_eq!(ComponentFilter::parse_list("").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn it_read_facets() {
+        let aggregations = json!({
+            "_tnct": {"buckets": [
+                {"key": "低張性", "doc_count": 12},
+                {"key": "高張性", "doc_count": 3}
+            ]},
+            "_phcl": {"buckets": []},
+            "quality": {"buckets": [
+                {"key": "単純温泉", "doc_count": 7}
+            ]},
+            "temperature": {"buckets": [
+                {"key": "*-25.0", "to": 25.0, "doc_count": 2},
+                {"key": "60.0-*", "from": 60.0, "doc_count": 1}
+            ]}
         });
+        let it = Facets::from_aggregations(Some(&aggregations));
+        assert_eq!(it.tonicity, vec![
+            FacetCount { value: "低張性".to_string(), count: 12 },
+            FacetCount { value: "高張性".to_string(), count: 3 }
+        ]);
+        assert!(it.ph_class.is_empty());
+        assert!(it.temperature_class.is_empty());
+        assert_eq!(it.quality, vec![
+            FacetCount { value: "単純温泉".to_string(), count: 7 }
+        ]);
+        assert_eq!(it.temperature, vec![
+            RangeCount { min: None, max: Some(25.0), count: 2 },
+            RangeCount { min: Some(60.0), max: None, count: 1 }
+        ]);
+        assert!(it.ph.is_empty());
+        assert_eq!(Facets::from_aggregations(None), Facets::default());
+        assert_eq!(Facets::aggs()["ph"], json!({"range": {
+            "field": "ph.n",
+            "ranges": [{"to": 3.0}, {"from": 3.0, "to": 6.0},
+                       {"from": 6.0, "to": 7.5}, {"from": 7.5, "to": 8.5},
+                       {"from": 8.5}]
+        }}));
+    }
+
+    #[test]
+    fn it_read_feature_analysis() {
+        let row: SearchResultItem = serde_json::from_value(json!({
+            "_index": "analyses",
+            "_id": "kusatsu",
+            "_score": null,
+            "_source": {
+                "name": "草津温泉",
+                "temp": 51.3,
+                "ph": {"n": 2.1},
+                "_tnct": "低張性",
+                "location": "群馬県吾妻郡草津町",
+                "latitude": 36.62,
+                "longitude": 138.59
+            }
+        })).unwrap();
+        let a = feature_analysis(&row).unwrap();
+        assert_eq!(a.id.as_deref(), Some("kusatsu"));
+        assert_eq!(a.ph, CellValue::Number(2.1));
+        assert_eq!(a.computed.tonicity, Some(Tonicity::Hypotonic));
+        assert_eq!(a.meta.geo_point(),
+                   Some(GeoPoint { lat: 36.62, lon: 138.59 }));
+        let features = geo::feature_collection(&[a]);
+        assert_eq!(features["features"][0]["properties"]["name"],
+                   json!("草津温泉"));
     }
 }
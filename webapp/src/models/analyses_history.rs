@@ -0,0 +1,246 @@
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::analysis::Analysis;
+use crate::models::Models;
+use crate::utils::elasticsearch::{
+    GetResult, SearchResultItem, OperationResultType,
+    Setup, SetupOptions,
+    Operations, GetOptions, SearchOptions, InsertOptions, DeleteOptions
+};
+
+/*
+ * Previous versions of analyses, appended by `analyses::save` and
+ * `analyses::import` when they overwrite them.
+ */
+
+const KEY_ANALYSIS_ID: &str = "aid";
+const KEY_REVISION: &str = "rev";
+const KEY_SAVED_BY: &str = "svby";
+const KEY_SAVED_AT: &str = "svat";
+const KEY_ARCHIVED_AT: &str = "_arat";
+const KEY_DOCUMENT: &str = "doc";
+
+/// Keys in stored documents of analyses.
+const KEY_LAST_MODIFIED: &str = "_lamo";
+pub const KEY_DOCUMENT_SAVED_BY: &str = "_svby";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Revision {
+    /// 1 for the oldest
+    pub revision: u32,
+    /// Who saved the version, if known
+    #[serde(rename = "savedBy")]
+    pub saved_by: Option<String>,
+    /// When the version was saved
+    #[serde(rename = "savedAt")]
+    pub saved_at: Option<f64>,
+    /// When the version was overwritten
+    #[serde(rename = "archivedAt")]
+    pub archived_at: Option<f64>
+}
+
+impl TryFrom<&Value> for Revision {
+    type Error = String;
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let revision = value[KEY_REVISION].as_u64()
+            .ok_or(format!("Failed to get Revision from Value: {}", &value))?;
+        Ok(Revision {
+            revision: revision as u32,
+            saved_by: value[KEY_SAVED_BY].as_str().map(|s| s.to_string()),
+            saved_at: value[KEY_SAVED_AT].as_f64(),
+            archived_at: value[KEY_ARCHIVED_AT].as_f64()
+        })
+    }
+}
+
+impl TryFrom<&SearchResultItem> for Revision {
+    type Error = String;
+    fn try_from(value: &SearchResultItem) -> Result<Self, Self::Error> {
+        Revision::try_from(&value._source)
+    }
+}
+
+impl TryFrom<&GetResult> for (Revision, Analysis) {
+    type Error = String;
+    fn try_from(value: &GetResult) -> Result<Self, Self::Error> {
+        let revision = Revision::try_from(&value._source)?;
+        let mut a = Analysis::try_from(&value._source[KEY_DOCUMENT])?;
+        a.id = value._source[KEY_ANALYSIS_ID].as_str()
+            .map(|s| s.to_string());
+        Ok((revision, a))
+    }
+}
+
+fn document_id(id: &str, revision: u32) -> String {
+    format!("{}:{}", id, revision)
+}
+
+pub async fn setup<'a>(models: &Models<'a>) -> Result<String, String> {
+    models.analyses_history
+        .setup(SetupOptions::new(json!({
+            "mappings": {
+                "properties": {
+                    KEY_ANALYSIS_ID: {"type": "keyword"},
+                    KEY_REVISION: {"type": "integer"},
+                    KEY_SAVED_BY: {"type": "keyword"},
+                    KEY_SAVED_AT: {"type": "float"},
+                    KEY_ARCHIVED_AT: {"type": "float"},
+                    KEY_DOCUMENT: {"type": "object", "enabled": false}
+                }
+            }
+        })))
+        .await
+        .map_err(|e| format!("{}", &e))
+}
+
+/// Revisions of an analysis, newest first.
+pub async fn select<'a>(models: &Models<'a>, id: &str, limit: u32)
+    -> Result<(u32, Vec<Revision>), String>
+{
+    let result = models.analyses_history.select(SearchOptions {
+        query: Some(json!({"term": {KEY_ANALYSIS_ID: id}})),
+        sort: Some(json!([{KEY_REVISION: "desc"}])),
+        size: Some(limit),
+        source: Some(json!([KEY_REVISION, KEY_SAVED_BY, KEY_SAVED_AT,
+                            KEY_ARCHIVED_AT])),
+        ..Default::default()
+    }).await;
+    debug!("analyses_history::select, result: {:?}", &result);
+    match result {
+        Ok(result) => Ok((
+            result.hits.total.value as u32,
+            result.hits.hits.iter()
+                .filter_map(|row| Revision::try_from(row).ok())
+                .collect()
+        )),
+        Err(e) => Err(format!("{}", e))
+    }
+}
+
+/// Revision `revision` of an analysis, or None if there is no such one.
+pub async fn by_revision<'a>(models: &Models<'a>, id: &str, revision: u32)
+    -> Result<Option<(Revision, Analysis)>, String>
+{
+    let result = models.analyses_history
+        .get(GetOptions::new(&document_id(id, revision)))
+        .await;
+    debug!("analyses_history::by_revision, result: {:?}", &result);
+    match result {
+        Ok(Some(row)) => <(Revision, Analysis)>::try_from(&row).map(Some),
+        Ok(None) => Ok(None),
+        Err(e) => Err(format!("{}", e))
+    }
+}
+
+/// Tries to take the next revision before giving up.
+const MAX_APPEND_TRIES: u32 = 10;
+
+/// Append `document`, the stored analysis just overwritten, as the next
+/// revision of analysis `id`.
+pub async fn append<'a>(models: &Models<'a>, id: &str, document: &Value)
+    -> Result<Revision, String>
+{
+    // The count may lag behind recent appends; a revision taken already is
+    // found by the realtime get below and the next one is tried.
+    let (count, _) = select(models, id, 0).await?;
+    let epoch = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    let mut revision = Revision {
+        revision: count + 1,
+        saved_by: document[KEY_DOCUMENT_SAVED_BY].as_str()
+            .map(|s| s.to_string()),
+        saved_at: document[KEY_LAST_MODIFIED].as_f64(),
+        archived_at: Some(epoch)
+    };
+    for _ in 0..MAX_APPEND_TRIES {
+        let docid = document_id(id, revision.revision);
+        let value = json!({
+            KEY_ANALYSIS_ID: id,
+            KEY_REVISION: revision.revision,
+            KEY_SAVED_BY: revision.saved_by,
+            KEY_SAVED_AT: revision.saved_at,
+            KEY_ARCHIVED_AT: revision.archived_at,
+            KEY_DOCUMENT: document
+        });
+        // Creating by id fails rather than overwrites if two saves race
+        let result = models.analyses_history
+            .insert(&value, InsertOptions::new(Some(&docid)))
+            .await;
+        debug!("analyses_history::append, result: {:?}", &result);
+        match result {
+            Ok(r) => return match r.result {
+                OperationResultType::Created => Ok(revision),
+                r => Err(format!("unexpected result in \
+                                  analyses_history::append: {:?}", r))
+            },
+            Err(e) => {
+                let taken = models.analyses_history
+                    .get(GetOptions::new(&docid))
+                    .await
+                    .map_err(|e| format!("{}", e))?;
+                if taken.is_none() {
+                    return Err(format!("{}", e));
+                }
+                revision.revision += 1;
+            }
+        }
+    }
+    Err(format!("Cannot append history of analysis: {}, last tried: {}",
+                id, revision.revision))
+}
+
+/// Remove revision `revision` of analysis `id`, appended for an update that
+/// did not happen.
+pub async fn remove<'a>(models: &Models<'a>, id: &str, revision: u32)
+    -> Result<(), String>
+{
+    let result = models.analyses_history
+        .delete(DeleteOptions::new(&document_id(id, revision)))
+        .await
+        .map_err(|e| format!("{}", e))?;
+    match result.result {
+        OperationResultType::Deleted => Ok(()),
+        r => Err(format!("unexpected result in analyses_history::remove: {:?}",
+                         r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_read_revision() {
+        let mut a = Analysis::new("草津温泉");
+        a.temperature = Some(51.3);
+        let mut doc = Value::from(&a);
+        doc[KEY_DOCUMENT_SAVED_BY] = json!("user1");
+        let row: GetResult = serde_json::from_value(json!({
+            "_id": "kusatsu:2",
+            "_index": "analyses_history",
+            "_primary_term": 1,
+            "_seq_no": 5,
+            "_source": {
+                "aid": "kusatsu",
+                "rev": 2,
+                "svby": "user1",
+                "svat": 1592301234.5,
+                "_arat": 1592309999.0,
+                "doc": doc
+            }
+        })).unwrap();
+        let (revision, a) = <(Revision, Analysis)>::try_from(&row).unwrap();
+        assert_eq!(revision.revision, 2);
+        assert_eq!(revision.saved_by.as_deref(), Some("user1"));
+        assert_eq!(revision.saved_at, Some(1592301234.5));
+        assert_eq!(a.id.as_deref(), Some("kusatsu"));
+        assert_eq!(a.name, "草津温泉");
+        assert_eq!(a.temperature, Some(51.3));
+        assert_eq!(document_id("kusatsu", 2), "kusatsu:2");
+    }
+}
@@ -10,10 +10,11 @@ use crate::models::Models;
 use crate::utils::{
     identifier::{IdGenerator, Generate},
     elasticsearch::{
-        GetResult, SearchResultItem, OperationResultType,
+        self as es, GetResult, SearchResultItem, OperationResultType,
+        Highlights, Cursor,
         Setup, SetupOptions,
         Operations, GetOptions, SearchOptions, InsertOptions, UpdateOptions,
-        DeleteOptions
+        UpdateResult, DeleteOptions
     }
 };
 
@@ -28,6 +29,7 @@ const KEY_LAST_MODIFIED: &str = "_lamo";
 const KEY_CREATED_AT: &str = "_crat";
 const KEY_AUTH: &str = "auth";
 
+const KEY_ID_KEYWORD: &str = "id.keyword";
 const KEY_PARENT_ID_KEYWORD: &str = "pid.keyword";
 
 const KEY_AUTH_GUESTID: &str = "user";
@@ -36,7 +38,7 @@ const KEY_AUTH_TYPE: &str = "t";
 const VAL_AUTH_GUEST: &str = "gust";
 const VAL_AUTH_SIGNIN: &str = "sign";
 
-const KEY_FIELDS_SEARCHABLE: &'static [&'static str] = &["name", "comm"];
+const KEY_FIELDS_SEARCHABLE: &[&str] = &[KEY_USERNAME, KEY_BODY];
 
 /// Conversion from Comment to Database object
 impl From<&Comment> for Value {
@@ -204,7 +206,19 @@ pub enum SelectQuery {
 
 pub struct SelectOptions {
     pub query: Option<SelectQuery>,
-    pub limit: u32
+    pub limit: u32,
+    /// Select the page after the cursor
+    pub after: Option<Cursor>
+}
+
+impl SelectOptions {
+    /// Sort clause of comments, newest first, which cursors are made in.
+    pub fn sort(&self) -> Value {
+        json!([
+            {KEY_CREATED_AT: "desc"},
+            {KEY_ID_KEYWORD: "desc"}
+        ])
+    }
 }
 
 pub struct SelectResult {
@@ -212,7 +226,13 @@ pub struct SelectResult {
     pub total: u32,
 
     /// Comments hit
-    pub items: Box<dyn Iterator<Item = Comment>>
+    pub items: Box<dyn Iterator<Item = Comment>>,
+
+    /// Snippets matching the search text, by comment id
+    pub highlights: Highlights,
+
+    /// Cursor to the next page, unless this is the last
+    pub next: Option<Cursor>
 }
 
 pub struct DeleteCommentOptions {
@@ -259,17 +279,34 @@ pub async fn select<'a>(models: &Models<'a>, options: &SelectOptions) ->
             }
         }))
     };
+    let highlight = match &options.query {
+        Some(SelectQuery::Text(_)) =>
+            Some(es::highlight(KEY_FIELDS_SEARCHABLE)),
+        _ => None
+    };
+    let sort = options.sort();
+    let search_after = match &options.after {
+        Some(c) => Some(c.search_after(&sort)?),
+        None => None
+    };
     let result = models.comments.select(SearchOptions {
         query: query,
-        sort: Some(json!([{
-            KEY_CREATED_AT: "desc"
-        }])),
+        sort: Some(sort.clone()),
+        size: Some(options.limit),
+        search_after,
+        highlight,
         ..Default::default()
     }).await;
     debug!("comments::select, result: {:?}", &result);
     match result {
         Ok(result) => Ok(SelectResult {
             total: result.hits.total.value as u32,
+            next: result.next_cursor(&sort, options.limit),
+            highlights: result.highlights(|key| match key {
+                KEY_USERNAME => Some("username"),
+                KEY_BODY => Some("body"),
+                _ => None
+            }),
             items: Box::new(result.hits.hits
                             .into_iter()
                             .filter_map(|row| {
@@ -293,7 +330,8 @@ pub async fn by_id<'a>(models: &Models<'a>, id: &str)
     let result = models.comments.get(GetOptions::new(id)).await;
     debug!("comments::by_id, result: {:?}", &result);
     match result {
-        Ok(row) => Comment::try_from(row).map(|a| Some(a)),
+        Ok(Some(row)) => Comment::try_from(row).map(Some),
+        Ok(None) => Ok(None),
         Err(e) => Err(String::from(format!("{}", e)))
     }
 }
@@ -368,10 +406,13 @@ pub async fn save<'a>(models: &Models<'a>, a: &Comment)
                 .await
                 .map(|r| {
                     debug!("comments::save, updated, result: {:?}", &r);
-                    match r.result {
-                        OperationResultType::Updated => Some(a),
-                        OperationResultType::Created => Some(a),
-                        _ => None
+                    match r {
+                        UpdateResult::Updated(r) => match r.result {
+                            OperationResultType::Updated => Some(a),
+                            OperationResultType::Created => Some(a),
+                            _ => None
+                        },
+                        UpdateResult::Conflict => None
                     }
                 })
         }
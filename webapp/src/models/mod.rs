@@ -1,20 +1,49 @@
 pub mod analyses;
+pub mod analyses_history;
 pub mod templates;
 pub mod comments;
 pub mod comment_photos;
 
+use std::fmt;
+
 use elasticsearch::Elasticsearch;
 
 use crate::utils::elasticsearch::{Collection};
 
 static INDEX_ANALYSES: &str = "analyses";
+static INDEX_ANALYSES_HISTORY: &str = "analyses_history";
 static INDEX_TEMPLATES: &str = "templates";
 static INDEX_COMMENTS: &str = "comments";
 
 type Database = Elasticsearch;
 
+/// Failure of saving a document.
+#[derive(Debug)]
+pub enum SaveError {
+    /// The stored document is no longer at the expected version
+    Conflict,
+    Failed(String)
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Conflict =>
+                write!(f, "document has been changed by someone else"),
+            SaveError::Failed(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl From<String> for SaveError {
+    fn from(e: String) -> Self {
+        SaveError::Failed(e)
+    }
+}
+
 pub struct Models<'a> {
     pub analyses: Collection<'a>,
+    pub analyses_history: Collection<'a>,
     pub templates: Collection<'a>,
     pub comments: Collection<'a>
 }
@@ -26,6 +55,10 @@ impl<'a> Models<'a> {
                 client: db,
                 name: INDEX_ANALYSES
             },
+            analyses_history: Collection {
+                client: db,
+                name: INDEX_ANALYSES_HISTORY
+            },
             templates: Collection {
                 client: db,
                 name: INDEX_TEMPLATES
@@ -40,9 +73,22 @@ impl<'a> Models<'a> {
     pub async fn setup(self: &Self) {
         let result = analyses::setup(self).await;
         println!("Models::setup, result: {:?}", &result);
+        let result = analyses_history::setup(self).await;
+        println!("Models::setup, result: {:?}", &result);
         let result = comments::setup(self).await;
         println!("Models::setup, result: {:?}", &result);
         let result = comment_photos::setup(self).await;
         println!("Models::setup, result: {:?}", &result);
     }
+
+    /// Err naming what the indices lack if they are set up by an older
+    /// version, to be migrated or reindexed through the CLI.
+    pub async fn check(&self) -> Result<(), String> {
+        let stale = analyses::check(self).await?;
+        if stale.is_empty() {
+            Ok(())
+        } else {
+            Err(stale.join("; "))
+        }
+    }
 }
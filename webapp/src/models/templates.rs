@@ -1,12 +1,12 @@
 use std::convert::TryFrom;
 use serde_json::{json, Value};
 
-use crate::models::{Models};
+use crate::models::{Models, SaveError};
 use crate::template::{self, Template};
 use crate::utils::elasticsearch::{
     GetResult, SearchResultItem, OperationResultType,
     Operations, GetOptions, SearchOptions, InsertOptions, UpdateOptions,
-    DeleteOptions
+    UpdateResult, DeleteOptions, Version
 };
 
 pub struct DeleteTemplateOptions {
@@ -85,17 +85,31 @@ pub async fn select<'a>(models: &Models<'a>)
 
 pub async fn by_id<'a>(models: &Models<'a>, id: &String)
     -> Result<Option<Template>, String>
+{
+    by_id_with_version(models, id).await.map(|t| t.map(|(t, _)| t))
+}
+
+/// Template and its version, to be given to `save`.
+pub async fn by_id_with_version<'a>(models: &Models<'a>, id: &str)
+    -> Result<Option<(Template, Version)>, String>
 {
     let result = models.templates
         .get(GetOptions::new(id))
         .await;
     match result {
-        Ok(row) => Template::try_from(&row).map(Some),
+        Ok(Some(row)) =>
+            Template::try_from(&row).map(|t| Some((t, row.version()))),
+        Ok(None) => Ok(None),
         Err(e) => Err(format!("Failed to find template, e: {}", &e))
     }
 }
 
-pub async fn save<'a>(models: &Models<'a>, t: &Template) -> Result<Template, String> {
+/// Save template and return it with the version saved. Fails with
+/// `SaveError::Conflict` if `version` is given and is stale.
+pub async fn save<'a>(models: &Models<'a>, t: &Template,
+                      version: Option<Version>)
+    -> Result<(Template, Version), SaveError>
+{
     // TODO Check template is valid in handlebars syntax.
     debug!("templates::save, template: {:?} name: {}", &t.id, &t.name);
     // Clone object
@@ -103,20 +117,15 @@ pub async fn save<'a>(models: &Models<'a>, t: &Template) -> Result<Template, Str
     v.as_object_mut().unwrap().remove(template::KEY_ID);
     let result = match &t.id {
         Some(id) => { // Update exists object
-            models.templates
-                .update(&v, UpdateOptions::new(id))
-                .await
-                .map(|r| {
+            let update = UpdateOptions::new(id).if_version(version);
+            match models.templates.update(&v, update).await {
+                Ok(UpdateResult::Conflict) => return Err(SaveError::Conflict),
+                Ok(UpdateResult::Updated(r)) => {
                     debug!("templates::save to update, result: {:?}", &r);
-                    id.clone()
-                    /*
-                    if r.modified_count >= 0 {
-                        object_id(&t.id)
-                    } else {
-                        r.upserted_id
-                    }
-                    */
-                })
+                    Ok(r)
+                },
+                Err(e) => Err(e)
+            }
         },
         _ => { // Insert new object
             models.templates
@@ -124,20 +133,17 @@ pub async fn save<'a>(models: &Models<'a>, t: &Template) -> Result<Template, Str
                 .await
                 .map(|r| {
                     debug!("templates::save to create, result: {:?}", &r);
-                    "TODO".to_string()
+                    r
                 })
         }
     };
     match result {
-        Ok(id) => {
+        Ok(r) => {
             let mut t: Template = t.clone();
-            t.id = Some(id);
-            Ok(t)
+            t.id = Some(r._id.clone());
+            Ok((t, r.version()))
         },
-        // Ok(e) =>
-        //     Err(String::from(format!("unexpected result in template::save,
-        //                              e: {:?}", &e))),
-        Err(e) => Err(String::from(format!("{}", e)))
+        Err(e) => Err(SaveError::Failed(format!("{}", e)))
     }
 }
 
@@ -0,0 +1,174 @@
+/**
+ * Minimal PDF writer.
+ * Texts are written with the non-embedded Japanese font HeiseiKakuGo-W5 in
+ * UniJIS-UCS2-H encoding, which PDF readers supply by themselves, so no font
+ * file needs to be shipped.
+ */
+pub mod sheet;
+
+/// A4 portrait in points.
+pub const PAGE_WIDTH: f64 = 595.28;
+pub const PAGE_HEIGHT: f64 = 841.89;
+
+/// Ratio of font size for subscripts and superscripts.
+const SCRIPT_SCALE: f64 = 0.7;
+
+#[derive(Default, Debug)]
+pub struct Page {
+    content: String
+}
+
+/// Part of a text drawn by `Page::runs`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Run {
+    Normal(String),
+    Subscript(String),
+    Superscript(String)
+}
+
+/// Width of a text in points, taking ASCII as half-width.
+pub fn text_width(text: &str, size: f64) -> f64 {
+    text.chars().map(|c| if c.is_ascii() { 0.5 } else { 1.0 }).sum::<f64>()
+        * size
+}
+
+/// UCS-2 big endian hex string, dropping characters outside of the BMP.
+fn hex_string(text: &str) -> String {
+    let mut hex = String::from("<");
+    for u in text.encode_utf16() {
+        if (0xd800..0xe000).contains(&u) {
+            continue;
+        }
+        hex.push_str(&format!("{:04X}", u));
+    }
+    hex.push('>');
+    hex
+}
+
+impl Page {
+    pub fn new() -> Self {
+        Page::default()
+    }
+
+    /// Draw a text whose baseline starts at (x, y).
+    pub fn text(&mut self, x: f64, y: f64, size: f64, text: &str) {
+        self.runs(x, y, size, &[Run::Normal(text.to_string())]);
+    }
+
+    /// Draw a text whose baseline ends at (x, y).
+    pub fn text_right(&mut self, x: f64, y: f64, size: f64, text: &str) {
+        self.text(x - text_width(text, size), y, size, text);
+    }
+
+    /// Draw runs with subscripts and superscripts from (x, y).
+    pub fn runs(&mut self, x: f64, y: f64, size: f64, runs: &[Run]) {
+        self.content.push_str(&format!(
+            "BT /F1 {:.1} Tf {:.2} {:.2} Td ", size, x, y));
+        for run in runs {
+            let (text, scale, rise) = match run {
+                Run::Normal(t) => (t, 1.0, 0.0),
+                Run::Subscript(t) => (t, SCRIPT_SCALE, -size * 0.2),
+                Run::Superscript(t) => (t, SCRIPT_SCALE, size * 0.4)
+            };
+            self.content.push_str(&format!(
+                "/F1 {:.1} Tf {:.1} Ts {} Tj ",
+                size * scale, rise, hex_string(text)));
+        }
+        self.content.push_str("ET\n");
+    }
+
+    pub fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64, width: f64) {
+        self.content.push_str(&format!(
+            "{:.1} w {:.2} {:.2} m {:.2} {:.2} l S\n", width, x1, y1, x2, y2));
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Document {
+    pub pages: Vec<Page>
+}
+
+impl Document {
+    pub fn new() -> Self {
+        Document::default()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Object numbers: 1 catalog, 2 pages, 3-5 font, then a page and its
+        // content stream for each page.
+        let kids: Vec<String> = (0..self.pages.len())
+            .map(|i| format!("{} 0 R", 6 + i * 2))
+            .collect();
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!("<< /Type /Pages /Kids [{}] /Count {} >>",
+                    kids.join(" "), self.pages.len()),
+            "<< /Type /Font /Subtype /Type0 /BaseFont /HeiseiKakuGo-W5 \
+             /Encoding /UniJIS-UCS2-H /DescendantFonts [4 0 R] >>"
+                .to_string(),
+            "<< /Type /Font /Subtype /CIDFontType0 \
+             /BaseFont /HeiseiKakuGo-W5 \
+             /CIDSystemInfo << /Registry (Adobe) /Ordering (Japan1) \
+             /Supplement 2 >> /FontDescriptor 5 0 R \
+             /DW 1000 /W [1 95 500 231 325 500] >>".to_string(),
+            "<< /Type /FontDescriptor /FontName /HeiseiKakuGo-W5 /Flags 4 \
+             /FontBBox [-92 -250 1010 922] /ItalicAngle 0 /Ascent 752 \
+             /Descent -221 /CapHeight 737 /StemV 114 >>".to_string()
+        ];
+        for (i, page) in self.pages.iter().enumerate() {
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                 /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+                PAGE_WIDTH, PAGE_HEIGHT, 7 + i * 2));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{}endstream",
+                page.content.len(), page.content));
+        }
+        let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object)
+                       .as_bytes());
+        }
+        let xref = pdf.len();
+        pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n",
+                           objects.len() + 1).as_bytes());
+        for offset in offsets {
+            pdf.extend(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        pdf.extend(format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1, xref).as_bytes());
+        pdf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_write_pdf() {
+        assert_eq!(hex_string("Na温"), "<004E00616E29>");
+        assert_eq!(text_width("Na温", 10.0), 20.0);
+        let mut doc = Document::new();
+        let mut page = Page::new();
+        page.runs(10.0, 10.0, 10.0,
+                  &[Run::Normal("SO".to_string()),
+                    Run::Subscript("4".to_string()),
+                    Run::Superscript("2-".to_string())]);
+        doc.pages.push(page);
+        let pdf = doc.to_bytes();
+        assert!(pdf.starts_with(b"%PDF-1.4"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Count 1"));
+        assert!(text.contains("-2.0 Ts <0034> Tj"));
+        // startxref points at the cross reference table
+        let xref = text.rfind("startxref\n").unwrap();
+        let start: usize = text[xref + 10..].lines().next().unwrap()
+            .parse().unwrap();
+        assert!(pdf[start..].starts_with(b"xref"));
+    }
+}
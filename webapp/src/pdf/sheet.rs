@@ -0,0 +1,268 @@
+use crate::analysis::{Analysis, CellValue, ComponentTable, MgMvalMmol};
+use crate::component;
+use crate::template::{format_prec, format_fixed};
+use super::{Document, Page, Run, PAGE_WIDTH, PAGE_HEIGHT};
+
+/*
+ * Printable analysis sheet, paginated on A4.
+ */
+
+const MARGIN: f64 = 50.0;
+const SIZE: f64 = 10.0;
+const LINE: f64 = 16.0;
+/// Right edges of mg, mval and mval% columns.
+const COLUMNS: [f64; 3] = [360.0, 450.0, PAGE_WIDTH - MARGIN];
+
+/// (table key, title, total key)
+const TABLES: [(&str, &str, &str); 5] = [
+    ("positiveIon", "(イ) 陽イオン", "totalPositiveIon"),
+    ("negativeIon", "(ロ) 陰イオン", "totalNegativeIon"),
+    ("undissociated", "(ハ) 非解離成分", "totalUndissociated"),
+    ("gas", "(ニ) 溶存ガス成分", "totalGas"),
+    ("minor", "(ホ) その他微量成分", "totalMinor")
+];
+
+fn format_value(v: &CellValue, format: impl Fn(f64) -> String) -> String {
+    match v {
+        CellValue::Number(x) => format(*x),
+        CellValue::BelowLimit { threshold, .. } => {
+            format!("<{}", format(*threshold))
+        }
+        CellValue::Trace(text) |
+        CellValue::NotDetected(text) |
+        CellValue::Text(text) => text.to_string(),
+        CellValue::Null => String::new()
+    }
+}
+
+fn row_values(v: &MgMvalMmol) -> [String; 3] {
+    [format_value(&v.mg, |x| format_prec(x, 5)),
+     format_value(&v.mval, |x| format_prec(x, 5)),
+     format_value(&v.mval_percent, |x| format_fixed(x, 2))]
+}
+
+/// Formula of a component with subscripted digits and superscripted charge,
+/// as `htmlf` renders for HTML.
+pub fn formula_runs(key: &str) -> Vec<Run> {
+    let c = match component::lookup(key) {
+        Some(c) => c,
+        None => return vec![Run::Normal(key.to_string())]
+    };
+    let mut runs = vec![];
+    let mut text = String::new();
+    let mut digits = String::new();
+    for ch in c.formula.chars() {
+        if ch.is_ascii_digit() {
+            if !text.is_empty() {
+                runs.push(Run::Normal(text.clone()));
+                text.clear();
+            }
+            digits.push(ch);
+        } else {
+            if !digits.is_empty() {
+                runs.push(Run::Subscript(digits.clone()));
+                digits.clear();
+            }
+            text.push(ch);
+        }
+    }
+    if !text.is_empty() {
+        runs.push(Run::Normal(text));
+    }
+    if !digits.is_empty() {
+        runs.push(Run::Subscript(digits));
+    }
+    if c.charge != 0 {
+        runs.push(Run::Superscript(component::charge_text(c.charge)));
+    }
+    runs
+}
+
+/// Keys of a table in the order of the component registry.
+fn sorted_keys(table: &ComponentTable) -> Vec<&String> {
+    let mut keys: Vec<&String> = table.components.keys().collect();
+    keys.sort_by_key(|k| {
+        let order = component::all().iter().position(|c| c.key == k.as_str());
+        (order.unwrap_or(usize::MAX), k.to_string())
+    });
+    keys
+}
+
+struct Writer<'a> {
+    analysis: &'a Analysis,
+    doc: Document,
+    page: Page,
+    y: f64
+}
+
+impl<'a> Writer<'a> {
+    fn new(analysis: &'a Analysis) -> Self {
+        Writer {
+            analysis,
+            doc: Document::new(),
+            page: Page::new(),
+            y: PAGE_HEIGHT - MARGIN
+        }
+    }
+
+    fn new_page(&mut self) {
+        let page = std::mem::replace(&mut self.page, Page::new());
+        self.doc.pages.push(page);
+        self.y = PAGE_HEIGHT - MARGIN;
+        self.page.text(MARGIN, self.y, SIZE * 0.8, &self.analysis.name);
+        self.y -= LINE * 1.5;
+    }
+
+    /// Start a new page unless `height` fits in the rest of the page.
+    /// Returns true if a new page is started.
+    fn ensure(&mut self, height: f64) -> bool {
+        if self.y - height < MARGIN + LINE {
+            self.new_page();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn title(&mut self, text: &str) {
+        let size = SIZE * 1.6;
+        self.page.text((PAGE_WIDTH - super::text_width(text, size)) / 2.0,
+                       self.y, size, text);
+        self.y -= LINE * 2.0;
+    }
+
+    fn item(&mut self, label: &str, value: &str) {
+        self.ensure(LINE);
+        self.page.text(MARGIN, self.y, SIZE, label);
+        self.page.text(MARGIN + 150.0, self.y, SIZE, value);
+        self.y -= LINE;
+    }
+
+    fn table_header(&mut self) {
+        self.page.text(MARGIN, self.y, SIZE, "成分");
+        for (x, t) in COLUMNS.iter().zip(["mg/kg", "mval", "mval%"].iter()) {
+            self.page.text_right(*x, self.y, SIZE, t);
+        }
+        self.page.line(MARGIN, self.y - 4.0, PAGE_WIDTH - MARGIN,
+                       self.y - 4.0, 0.5);
+        self.y -= LINE;
+    }
+
+    fn row(&mut self, label: &[Run], values: &[String; 3]) {
+        if self.ensure(LINE) {
+            self.table_header();
+        }
+        self.page.runs(MARGIN + 10.0, self.y, SIZE, label);
+        for (x, v) in COLUMNS.iter().zip(values.iter()) {
+            self.page.text_right(*x, self.y, SIZE, v);
+        }
+        self.y -= LINE;
+    }
+
+    fn table(&mut self, title: &str, table: &ComponentTable,
+             total: &MgMvalMmol, total_label: &str) {
+        if table.components.is_empty() {
+            return;
+        }
+        self.y -= LINE / 2.0;
+        self.ensure(LINE * 4.0);
+        self.page.text(MARGIN, self.y, SIZE, title);
+        self.y -= LINE;
+        self.table_header();
+        for key in sorted_keys(table) {
+            self.row(&formula_runs(key), &row_values(&table.components[key]));
+        }
+        self.row(&[Run::Normal(total_label.to_string())], &row_values(total));
+    }
+
+    fn finish(mut self) -> Document {
+        let page = std::mem::replace(&mut self.page, Page::new());
+        self.doc.pages.push(page);
+        let count = self.doc.pages.len();
+        for (i, page) in self.doc.pages.iter_mut().enumerate() {
+            let text = format!("{} / {}", i + 1, count);
+            page.text((PAGE_WIDTH - super::text_width(&text, SIZE)) / 2.0,
+                      MARGIN / 2.0, SIZE, &text);
+        }
+        self.doc
+    }
+}
+
+pub fn render(a: &Analysis) -> Document {
+    let mut w = Writer::new(a);
+    w.title("温泉分析書");
+    let meta = &a.meta;
+    let text = |v: Option<&String>| v.cloned().unwrap_or_default();
+    w.item("源泉名", &a.name);
+    w.item("源泉湧出地", &text(meta.location.as_ref()));
+    w.item("調査及び試験年月日",
+           &meta.investigated_date.as_ref().map_or(String::new(),
+                                                   |d| d.to_string()));
+    w.item("泉温", &a.temperature.map_or(String::new(),
+                                        |t| format!("{} ℃", t)));
+    w.item("湧出量", &match &a.gensen_yield {
+        CellValue::Null => String::new(),
+        v => format!("{} L/min", v)
+    });
+    w.item("pH値", &a.ph.to_string());
+    w.item("泉質",
+           &text(a.computed.quality.as_ref().or(meta.quality.as_ref())));
+    let totals = a.totals();
+    let total = |key: &str| {
+        totals.iter().find(|(k, _)| *k == key).unwrap().1
+    };
+    for (key, table) in a.tables().iter() {
+        if let Some((_, title, total_key)) =
+            TABLES.iter().find(|(k, _, _)| k == key)
+        {
+            w.table(title, table, total(total_key), "計");
+        }
+    }
+    w.y -= LINE / 2.0;
+    w.ensure(LINE * 3.0);
+    w.table_header();
+    w.row(&[Run::Normal("溶存物質 (ガス性のものを除く)".to_string())],
+          &row_values(total("totalMelt")));
+    w.row(&[Run::Normal("成分総計".to_string())],
+          &row_values(total("total")));
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell(mg: f64) -> MgMvalMmol {
+        let mut v = MgMvalMmol::new();
+        v.mg = CellValue::Number(mg);
+        v
+    }
+
+    #[test]
+    fn it_render_sheet() {
+        assert_eq!(formula_runs("SO4"),
+                   vec![Run::Normal("SO".to_string()),
+                        Run::Subscript("4".to_string()),
+                        Run::Superscript("2-".to_string())]);
+        let mut a = Analysis::new("雨中温泉");
+        a.positive_ion.components.insert("Na".to_string(), cell(400.0));
+        let doc = render(&a);
+        assert_eq!(doc.pages.len(), 1);
+        // Many components spill over to following pages
+        for i in 0..100 {
+            a.minor.components.insert(format!("X{}", i), cell(0.1));
+        }
+        let doc = render(&a);
+        assert_eq!(doc.pages.len(), 3);
+    }
+
+    #[test]
+    fn it_format_value() {
+        let fixed = |x| format_fixed(x, 2);
+        assert_eq!(format_value(&CellValue::parse_text("0.1未満"), fixed),
+                   "<0.10");
+        assert_eq!(format_value(&CellValue::parse_text("N.D."), fixed),
+                   "N.D.");
+        assert_eq!(format_value(&CellValue::Null, fixed), "");
+    }
+}
@@ -0,0 +1,468 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::{Analysis, ComponentTable};
+
+/*
+ * Classification of 療養泉 by 泉質名, 浸透圧, 液性 and 泉温.
+ *
+ * Resources:
+ * - 鉱泉分析法指針 (平成26年改訂) 環境省
+ */
+
+/// 溶存物質 (ガス性のものを除く) [mg/kg] to be 塩類泉
+const THRESHOLD_SALT: f64 = 1000.0;
+/// 泉温 [℃] to be 温泉
+const THRESHOLD_TEMPERATURE: f64 = 25.0;
+/// mval% of an ion to appear in 泉質名
+const THRESHOLD_MVAL_PERCENT: f64 = 20.0;
+
+/// 特殊成分 [mg/kg]
+const THRESHOLD_CO2: f64 = 1000.0;
+const THRESHOLD_FE: f64 = 20.0;
+const THRESHOLD_H: f64 = 1.0;
+const THRESHOLD_I: f64 = 10.0;
+const THRESHOLD_S: f64 = 2.0;
+
+/// ラドン [Bq/kg], 30 and 50 × 10⁻¹⁰ Ci/kg, to be 弱放射能 and 放射能
+const THRESHOLD_RN_WEAK: f64 = 111.0;
+const THRESHOLD_RN: f64 = 185.0;
+
+/// 溶存物質総量 [mg/kg] to be 等張性 and 高張性
+const THRESHOLD_ISOTONIC: f64 = 8000.0;
+const THRESHOLD_HYPERTONIC: f64 = 10000.0;
+
+/// pH to be 弱酸性, 中性, 弱アルカリ性 and アルカリ性
+const THRESHOLD_PH_WEAKLY_ACIDIC: f64 = 3.0;
+const THRESHOLD_PH_NEUTRAL: f64 = 6.0;
+const THRESHOLD_PH_WEAKLY_ALKALINE: f64 = 7.5;
+const THRESHOLD_PH_ALKALINE: f64 = 8.5;
+
+/// 泉温 [℃] to be 温泉 and 高温泉. 低温泉 starts from THRESHOLD_TEMPERATURE.
+const THRESHOLD_TEMPERATURE_WARM: f64 = 34.0;
+const THRESHOLD_TEMPERATURE_HIGH: f64 = 42.0;
+
+const SEPARATOR_NAME: &str = "－";
+const SEPARATOR_ION: &str = "・";
+
+/// Ions named in 泉質名. Keys sharing the same name are summed up.
+const NAMES_POSITIVE_ION: &[(&str, &str)] =
+    &[("Na", "ナトリウム"),
+      ("K", "カリウム"),
+      ("Li", "リチウム"),
+      ("NH4", "アンモニウム"),
+      ("Ca", "カルシウム"),
+      ("Mg", "マグネシウム"),
+      ("Sr", "ストロンチウム"),
+      ("Ba", "バリウム"),
+      ("Al", "アルミニウム"),
+      ("MnII", "マンガン（Ⅱ）"),
+      ("FeII", "鉄（Ⅱ）"),
+      ("FeIII", "鉄（Ⅲ）")];
+
+const NAMES_NEGATIVE_ION: &[(&str, &str)] =
+    &[("Cl", "塩化物"),
+      ("HCO3", "炭酸水素塩"),
+      ("CO3", "炭酸塩"),
+      ("SO4", "硫酸塩"),
+      ("HSO4", "硫酸塩"),
+      ("F", "ふっ化物"),
+      ("Br", "臭化物"),
+      ("I", "よう化物"),
+      ("NO3", "硝酸塩")];
+
+/// Ratio of sulfur in each sulfur component, to compute 総硫黄.
+const SULFUR_RATIOS: &[(&str, f64)] =
+    &[("HS", 32.06 / 33.07),
+      ("S2O3", 2.0 * 32.06 / 112.13),
+      ("H2S", 32.06 / 34.08)];
+
+fn mg(table: &ComponentTable, key: &str) -> f64 {
+    table.components.get(key)
+        .and_then(|v| v.mg.as_f64())
+        .unwrap_or(0.0)
+}
+
+fn mg_any(a: &Analysis, key: &str) -> f64 {
+    [&a.positive_ion, &a.negative_ion, &a.undissociated, &a.gas, &a.minor]
+        .iter()
+        .map(|t| mg(t, key))
+        .sum()
+}
+
+/// 溶存物質 (ガス性のものを除く) [mg/kg]
+fn total_salt(a: &Analysis) -> f64 {
+    a.total_melt.mg.as_f64().unwrap_or_else(|| {
+        [&a.positive_ion, &a.negative_ion, &a.undissociated]
+            .iter()
+            .flat_map(|t| t.components.values())
+            .filter_map(|v| v.mg.as_f64())
+            .sum()
+    })
+}
+
+/// Names of ions having mval% not less than the threshold, in descending
+/// order of mval%.
+fn major_ions(table: &ComponentTable,
+              names: &'static [(&'static str, &'static str)])
+              -> Vec<&'static str> {
+    let mut percents: Vec<(&'static str, f64)> = vec![];
+    for (key, name) in names {
+        let percent = table.mval_percent(key);
+        if let Some(percent) = percent {
+            match percents.iter_mut().find(|(n, _)| n == name) {
+                Some(p) => p.1 += percent,
+                None => percents.push((name, percent))
+            }
+        }
+    }
+    percents.sort_by(|a, b| b.1.partial_cmp(&a.1)
+                     .unwrap_or(std::cmp::Ordering::Equal));
+    let majors: Vec<&'static str> = percents.iter()
+        .filter(|(_, p)| *p >= THRESHOLD_MVAL_PERCENT)
+        .map(|(n, _)| *n)
+        .collect();
+    if majors.is_empty() {
+        percents.iter().take(1).map(|(n, _)| *n).collect()
+    } else {
+        majors
+    }
+}
+
+/// 特殊成分 in the order of 泉質名, except 酸性 which is not prefixed by 含.
+fn specific_components(a: &Analysis) -> Vec<&'static str> {
+    let mut v = vec![];
+    if mg(&a.gas, "CO2") >= THRESHOLD_CO2 {
+        v.push("二酸化炭素");
+    }
+    let fe2 = mg(&a.positive_ion, "FeII");
+    let fe3 = mg(&a.positive_ion, "FeIII");
+    if fe2 + fe3 >= THRESHOLD_FE {
+        v.push(if fe2 >= fe3 { "鉄（Ⅱ）" } else { "鉄（Ⅲ）" });
+    }
+    if mg(&a.negative_ion, "I") >= THRESHOLD_I {
+        v.push("よう素");
+    }
+    let sulfur: f64 = SULFUR_RATIOS.iter()
+        .map(|(key, ratio)| mg_any(a, key) * ratio)
+        .sum();
+    if sulfur >= THRESHOLD_S {
+        v.push("硫黄");
+    }
+    match a.meta.radon {
+        Some(rn) if rn >= THRESHOLD_RN => v.push("放射能"),
+        Some(rn) if rn >= THRESHOLD_RN_WEAK => v.push("弱放射能"),
+        _ => {}
+    }
+    v
+}
+
+fn is_acid(a: &Analysis) -> bool {
+    mg(&a.positive_ion, "H") >= THRESHOLD_H
+}
+
+/// Compute 泉質名 of the analysis.
+/// None if the analysis does not satisfy conditions of 療養泉.
+pub fn classify(a: &Analysis) -> Option<String> {
+    let acid = is_acid(a);
+    let specifics = specific_components(a);
+    let is_hot = a.temperature.filter(|t| *t >= THRESHOLD_TEMPERATURE)
+        .is_some();
+    if total_salt(a) >= THRESHOLD_SALT {
+        // 塩類泉
+        let positives = major_ions(&a.positive_ion, NAMES_POSITIVE_ION);
+        let negatives = major_ions(&a.negative_ion, NAMES_NEGATIVE_ION);
+        let mut prefix = vec![];
+        if acid {
+            prefix.push("酸性".to_string());
+        }
+        if !specifics.is_empty() {
+            prefix.push(format!("含{}", specifics.join(SEPARATOR_ION)));
+        }
+        let mut parts = vec![];
+        if !prefix.is_empty() {
+            parts.push(prefix.join(SEPARATOR_ION));
+        }
+        if !positives.is_empty() {
+            parts.push(positives.join(SEPARATOR_ION));
+        }
+        if !negatives.is_empty() {
+            parts.push(negatives.join(SEPARATOR_ION));
+        }
+        Some(format!("{}泉", parts.join(SEPARATOR_NAME)))
+    } else if acid || !specifics.is_empty() {
+        // 単純泉 with 特殊成分
+        let mut names = vec![];
+        if acid {
+            names.push("酸性");
+        }
+        names.extend(specifics);
+        Some(format!("単純{}{}", names.join(SEPARATOR_ION),
+                     if is_hot { "温泉" } else { "冷鉱泉" }))
+    } else if is_hot {
+        Some("単純温泉".to_string())
+    } else {
+        None
+    }
+}
+
+/// 浸透圧による分類
+#[derive(Copy, Clone, PartialEq, Deserialize, Serialize, Debug)]
+pub enum Tonicity {
+    #[serde(rename = "低張性", alias = "hypotonic")]
+    Hypotonic,
+    #[serde(rename = "等張性", alias = "isotonic")]
+    Isotonic,
+    #[serde(rename = "高張性", alias = "hypertonic")]
+    Hypertonic
+}
+
+/// 液性による分類
+#[derive(Copy, Clone, PartialEq, Deserialize, Serialize, Debug)]
+pub enum PhClass {
+    #[serde(rename = "酸性", alias = "acidic")]
+    Acidic,
+    #[serde(rename = "弱酸性", alias = "weaklyAcidic")]
+    WeaklyAcidic,
+    #[serde(rename = "中性", alias = "neutral")]
+    Neutral,
+    #[serde(rename = "弱アルカリ性", alias = "weaklyAlkaline")]
+    WeaklyAlkaline,
+    #[serde(rename = "アルカリ性", alias = "alkaline")]
+    Alkaline
+}
+
+/// 泉温による分類
+#[derive(Copy, Clone, PartialEq, Deserialize, Serialize, Debug)]
+pub enum TemperatureClass {
+    #[serde(rename = "冷鉱泉", alias = "cold")]
+    Cold,
+    #[serde(rename = "低温泉", alias = "low")]
+    Low,
+    #[serde(rename = "温泉", alias = "warm")]
+    Warm,
+    #[serde(rename = "高温泉", alias = "high")]
+    High
+}
+
+/// Parse a class by the same names as in JSON, e.g. "中性" or "neutral".
+macro_rules! impl_from_str_by_serde {
+    ($t:ty) => {
+        impl FromStr for $t {
+            type Err = String;
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                serde_json::from_value(serde_json::Value::from(s))
+                    .map_err(|_| format!("unknown class: {}", s))
+            }
+        }
+    }
+}
+
+impl_from_str_by_serde!(Tonicity);
+impl_from_str_by_serde!(PhClass);
+impl_from_str_by_serde!(TemperatureClass);
+
+pub fn tonicity(a: &Analysis) -> Option<Tonicity> {
+    let total = total_salt(a);
+    if total <= 0.0 {
+        None
+    } else if total < THRESHOLD_ISOTONIC {
+        Some(Tonicity::Hypotonic)
+    } else if total < THRESHOLD_HYPERTONIC {
+        Some(Tonicity::Isotonic)
+    } else {
+        Some(Tonicity::Hypertonic)
+    }
+}
+
+pub fn ph_class(a: &Analysis) -> Option<PhClass> {
+    a.ph.as_f64().map(|ph| {
+        if ph < THRESHOLD_PH_WEAKLY_ACIDIC {
+            PhClass::Acidic
+        } else if ph < THRESHOLD_PH_NEUTRAL {
+            PhClass::WeaklyAcidic
+        } else if ph < THRESHOLD_PH_WEAKLY_ALKALINE {
+            PhClass::Neutral
+        } else if ph < THRESHOLD_PH_ALKALINE {
+            PhClass::WeaklyAlkaline
+        } else {
+            PhClass::Alkaline
+        }
+    })
+}
+
+pub fn temperature_class(a: &Analysis) -> Option<TemperatureClass> {
+    a.temperature.map(|t| {
+        if t < THRESHOLD_TEMPERATURE {
+            TemperatureClass::Cold
+        } else if t < THRESHOLD_TEMPERATURE_WARM {
+            TemperatureClass::Low
+        } else if t < THRESHOLD_TEMPERATURE_HIGH {
+            TemperatureClass::Warm
+        } else {
+            TemperatureClass::High
+        }
+    })
+}
+
+/// Normalize notation of 泉質名 to compare.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '－' | 'ー' | '−' | '‐' | '―' | 'ｰ' | '–' | '—' => '-',
+            '（' => '(',
+            '）' => ')',
+            '・' | '･' => '.',
+            c => c
+        })
+        .collect::<String>()
+        .replace("Ⅱ", "II")
+        .replace("Ⅲ", "III")
+}
+
+/// Whether stored 泉質名 differs from the computed one.
+pub fn is_mismatch(stored: &str, computed: Option<&str>) -> bool {
+    match computed {
+        Some(computed) => normalize(stored) != normalize(computed),
+        None => !stored.trim().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+    use crate::analysis::{CellValue, MgMvalMmol, Computed};
+    use crate::metadata::Metadata;
+
+    fn cell(mg: f64, mval: f64) -> MgMvalMmol {
+        MgMvalMmol {
+            mg: CellValue::Number(mg),
+            mval: CellValue::Number(mval),
+            mval_percent: CellValue::Null,
+            mmol: CellValue::Null
+        }
+    }
+
+    fn table(items: Vec<(&str, MgMvalMmol)>) -> ComponentTable {
+        let mut components = HashMap::new();
+        for (key, value) in items {
+            components.insert(key.to_string(), value);
+        }
+        ComponentTable { components }
+    }
+
+    fn analysis(temperature: f64, total_melt: f64,
+                positive_ion: ComponentTable,
+                negative_ion: ComponentTable) -> Analysis {
+        let mut total = MgMvalMmol::new();
+        total.mg = CellValue::Number(total_melt);
+        Analysis {
+            id: None,
+            name: "雨中温泉".to_string(),
+            gensen_yield: CellValue::Null,
+            temperature: Some(temperature),
+            ph: CellValue::Number(7.9),
+            positive_ion,
+            negative_ion,
+            undissociated: ComponentTable::new(),
+            gas: ComponentTable::new(),
+            minor: ComponentTable::new(),
+            total_positive_ion: MgMvalMmol::new(),
+            total_negative_ion: MgMvalMmol::new(),
+            total_undissociated: MgMvalMmol::new(),
+            total_gas: MgMvalMmol::new(),
+            total_minor: MgMvalMmol::new(),
+            total_melt: total,
+            total: MgMvalMmol::new(),
+            meta: Metadata::default(),
+            computed: Computed::default(),
+            last_modified: None,
+            created_at: None
+        }
+    }
+
+    #[test]
+    fn it_classify_salt_spring() {
+        let a = analysis(
+            44.6, 1500.0,
+            table(vec![("Na", cell(484.0, 21.1)), ("Ca", cell(10.0, 0.5))]),
+            table(vec![("Cl", cell(500.0, 14.1)),
+                       ("HCO3", cell(400.0, 6.5)),
+                       ("SO4", cell(20.0, 0.4))]));
+        assert_eq!(classify(&a),
+                   Some("ナトリウム－塩化物・炭酸水素塩泉".to_string()));
+    }
+
+    #[test]
+    fn it_classify_sulfur_salt_spring() {
+        let a = analysis(
+            44.6, 1500.0,
+            table(vec![("Na", cell(484.0, 21.1))]),
+            table(vec![("Cl", cell(700.0, 19.7)), ("HS", cell(5.0, 0.15))]));
+        assert_eq!(classify(&a),
+                   Some("含硫黄－ナトリウム－塩化物泉".to_string()));
+    }
+
+    #[test]
+    fn it_classify_simple_spring() {
+        let a = analysis(
+            38.0, 300.0,
+            table(vec![("Na", cell(80.0, 3.5))]),
+            table(vec![("Cl", cell(100.0, 2.8))]));
+        assert_eq!(classify(&a), Some("単純温泉".to_string()));
+        let a = analysis(
+            15.0, 300.0,
+            table(vec![("Na", cell(80.0, 3.5))]),
+            table(vec![("Cl", cell(100.0, 2.8))]));
+        assert_eq!(classify(&a), None);
+    }
+
+    #[test]
+    fn it_classify_radioactive_spring() {
+        let mut a = analysis(
+            18.0, 300.0,
+            table(vec![("Na", cell(80.0, 3.5))]),
+            table(vec![("Cl", cell(100.0, 2.8))]));
+        a.meta.radon = Some(110.0);
+        assert_eq!(classify(&a), None);
+        a.meta.radon = Some(111.0);
+        assert_eq!(classify(&a), Some("単純弱放射能冷鉱泉".to_string()));
+        a.meta.radon = Some(200.0);
+        a.temperature = Some(38.0);
+        assert_eq!(classify(&a), Some("単純放射能温泉".to_string()));
+        let mut a = analysis(
+            44.6, 1500.0,
+            table(vec![("Na", cell(484.0, 21.1))]),
+            table(vec![("Cl", cell(700.0, 19.7))]));
+        a.meta.radon = Some(150.0);
+        assert_eq!(classify(&a),
+                   Some("含弱放射能－ナトリウム－塩化物泉".to_string()));
+    }
+
+    #[test]
+    fn it_detect_mismatch() {
+        assert!(!is_mismatch("含硫黄ーナトリウムー塩化物泉",
+                             Some("含硫黄－ナトリウム－塩化物泉")));
+        assert!(is_mismatch("ナトリウム－塩化物泉",
+                            Some("含硫黄－ナトリウム－塩化物泉")));
+        assert!(is_mismatch("単純温泉", None));
+    }
+
+    #[test]
+    fn it_classify_tonicity_ph_and_temperature() {
+        let a = analysis(
+            44.6, 9000.0,
+            table(vec![("Na", cell(484.0, 21.1))]),
+            table(vec![("Cl", cell(700.0, 19.7))]));
+        assert_eq!(tonicity(&a), Some(Tonicity::Isotonic));
+        assert_eq!(ph_class(&a), Some(PhClass::WeaklyAlkaline));
+        assert_eq!(temperature_class(&a), Some(TemperatureClass::High));
+        assert_eq!(serde_json::to_string(&Tonicity::Isotonic).unwrap(),
+                   r#""等張性""#);
+        assert_eq!(serde_json::from_str::<PhClass>(r#""neutral""#).unwrap(),
+                   PhClass::Neutral);
+    }
+}
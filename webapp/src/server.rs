@@ -1,15 +1,30 @@
+use std::convert::TryFrom;
+
 use actix_web::{
-    web, App, HttpResponse, HttpServer, Responder
+    error, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+    http::header::{AUTHORIZATION, ETAG, IF_MATCH}
 };
 use listenfd::ListenFd;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::models::{self, Models, analyses};
+use crate::models::{self, Models, SaveError, analyses, analyses_history};
 use crate::services::{comment_service};
 use crate::utils;
-use crate::utils::elasticsearch::{DBConnectionPool, create_pool};
+use crate::utils::json::to_ascii_string;
+use crate::utils::elasticsearch::{
+    DBConnectionPool, Highlights, Cursor, Version, create_pool
+};
 use crate::template::{Template, Render};
-use crate::analysis::Analysis;
+use crate::analysis::{self, Analysis};
+use crate::component;
+use crate::diagram;
+use crate::pdf;
+use crate::diff::AnalysisDiff;
+use crate::tabular;
+use crate::quality::{Tonicity, PhClass, TemperatureClass};
+use crate::geo::{self, GeoPoint, BoundingBox, Distance};
+use crate::token::TokenData;
 
 #[derive(Deserialize)]
 struct ScrubQuery {
@@ -22,20 +37,175 @@ struct AnalysisPath {
 }
 
 #[derive(Debug, Deserialize)]
-struct AnalysisQuery {
-    template: Option<String>
+struct AnalysisSimilarQuery {
+    #[serde(rename = "l", default = "default_similar_limit")]
+    pub limit: u32
+}
+
+fn default_similar_limit() -> u32 { 10 }
+
+/// Most analyses `similar_analysis` answers at once.
+const MAX_SIMILAR_LIMIT: u32 = 100;
+
+#[derive(Serialize)]
+struct AnalysisSimilarList {
+    items: Vec<analyses::SimilarItem>
 }
 
 #[derive(Debug, Deserialize)]
-struct AnalysisListQuery {
-    /*
+struct AnalysisSuggestQuery {
+    #[serde(rename = "q", default)]
+    pub query: String,
+    #[serde(rename = "l", default = "default_suggest_limit")]
+    pub limit: u32
+}
+
+fn default_suggest_limit() -> u32 { 10 }
+
+/// Most names `suggest_analysis` answers at once.
+const MAX_SUGGEST_LIMIT: u32 = 50;
+
+#[derive(Serialize)]
+struct AnalysisSuggestList {
+    items: Vec<analyses::Suggestion>
+}
+
+#[derive(Debug, Deserialize)]
+struct AnalysisDiffPath {
+    a: String,
+    b: String
+}
+
+#[derive(Debug, Deserialize)]
+struct AnalysisRevisionPath {
+    id: String,
+    revision: u32
+}
+
+#[derive(Debug, Deserialize)]
+struct AnalysisRevisionsQuery {
+    #[serde(rename = "l", default = "default_revisions_limit")]
+    pub limit: u32
+}
+
+fn default_revisions_limit() -> u32 { 100 }
+
+#[derive(Serialize)]
+struct AnalysisRevisionList {
+    total: u32,
+    revisions: Vec<analyses_history::Revision>
+}
+
+#[derive(Serialize)]
+struct AnalysisRevision {
     #[serde(flatten)]
-    pub select: analyses::SelectOptions
-    */
+    revision: analyses_history::Revision,
+    analysis: Analysis
+}
+
+#[derive(Debug, Deserialize)]
+struct AnalysisQuery {
+    template: Option<String>,
+    #[serde(default = "default_analysis_format")]
+    format: AnalysisFormat
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AnalysisFormat {
+    Json,
+    Pdf
+}
+
+fn default_analysis_format() -> AnalysisFormat {
+    AnalysisFormat::Json
+}
+
+/// Header of warnings on a saved analysis.
+const HEADER_ANALYSIS_WARNINGS: &str = "X-Analysis-Warnings";
+
+#[derive(Debug, Deserialize)]
+struct AnalysisSaveQuery {
+    #[serde(rename = "overwrite", default)]
+    pub overwrite_derived: bool,
+    #[serde(rename = "recompute", default)]
+    pub recompute_totals: bool,
+    /// Version read before editing, unless If-Match is given
+    #[serde(default)]
+    pub version: Option<Version>,
+    /// Respond with the version, warnings and recomputed totals beside the
+    /// analysis
+    #[serde(default)]
+    pub report: bool
+}
+
+impl From<&AnalysisSaveQuery> for analyses::SaveOptions {
+    fn from(a: &AnalysisSaveQuery) -> Self {
+        analyses::SaveOptions {
+            overwrite_derived: a.overwrite_derived,
+            recompute_totals: a.recompute_totals,
+            version: a.version,
+            saved_by: None
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TemplateSaveQuery {
+    /// Version read before editing, unless If-Match is given
+    #[serde(default)]
+    pub version: Option<Version>
+}
+
+/// Filters shared by listing and exporting analyses.
+#[derive(Debug, Deserialize)]
+struct AnalysisFilterQuery {
     #[serde(rename = "q", default)]
     pub query: Option<String>,
+    #[serde(default)]
+    pub tonicity: Option<Tonicity>,
+    #[serde(rename = "phClass", default)]
+    pub ph_class: Option<PhClass>,
+    #[serde(rename = "temperatureClass", default)]
+    pub temperature_class: Option<TemperatureClass>,
+    #[serde(rename = "temperatureMin", default,
+            deserialize_with = "deserialize_number_text")]
+    pub temperature_min: Option<f64>,
+    #[serde(rename = "temperatureMax", default,
+            deserialize_with = "deserialize_number_text")]
+    pub temperature_max: Option<f64>,
+    #[serde(rename = "phMin", default,
+            deserialize_with = "deserialize_number_text")]
+    pub ph_min: Option<f64>,
+    #[serde(rename = "phMax", default,
+            deserialize_with = "deserialize_number_text")]
+    pub ph_max: Option<f64>,
+    /// Text contained in 泉質
+    #[serde(default)]
+    pub quality: Option<String>,
+    /// Minimum mg of components, e.g. "CO2:250,FeII:20"
+    #[serde(default, deserialize_with = "deserialize_component_filters")]
+    pub mg: Vec<analyses::ComponentFilter>,
+    /// "lat,lon"
+    #[serde(default)]
+    pub near: Option<GeoPoint>,
+    /// e.g. "10km", effective with `near`
+    #[serde(default)]
+    pub radius: Distance,
+    /// "west,south,east,north"
+    #[serde(default)]
+    pub bbox: Option<BoundingBox>
+}
+
+#[derive(Debug, Deserialize)]
+struct AnalysisListQuery {
+    #[serde(flatten)]
+    pub filter: AnalysisFilterQuery,
     #[serde(rename = "p", default)]
     pub page: u32,
+    /// `next` of the previous page, taking precedence over `p`
+    #[serde(rename = "c", default)]
+    pub cursor: Option<Cursor>,
     #[serde(rename = "l", default = "default_limit")]
     pub limit: u32,
     #[serde(rename = "o", default = "default_order_by")]
@@ -44,6 +214,76 @@ struct AnalysisListQuery {
     pub direction: i32
 }
 
+#[derive(Debug, Deserialize)]
+struct AnalysisExportQuery {
+    #[serde(flatten)]
+    pub filter: AnalysisFilterQuery,
+    #[serde(rename = "o", default = "default_order_by")]
+    pub order_by: analyses::SortKey,
+    #[serde(rename = "d", default = "default_direction")]
+    pub direction: i32,
+    #[serde(default = "default_export_format")]
+    pub format: tabular::ExportFormat,
+    /// Comma separated column names, e.g. "name,positiveIon.Na.mg"
+    #[serde(default)]
+    pub columns: Option<String>
+}
+
+fn default_export_format() -> tabular::ExportFormat {
+    tabular::ExportFormat::Csv
+}
+
+impl From<&AnalysisFilterQuery> for analyses::SelectOptions {
+    fn from(a: &AnalysisFilterQuery) -> Self {
+        analyses::SelectOptions {
+            query: a.query.clone(),
+            tonicity: a.tonicity,
+            ph_class: a.ph_class,
+            temperature_class: a.temperature_class,
+            temperature: analyses::Range::new(a.temperature_min,
+                                              a.temperature_max),
+            ph: analyses::Range::new(a.ph_min, a.ph_max),
+            quality: a.quality.clone(),
+            components: a.mg.clone(),
+            near: a.near,
+            radius: a.radius,
+            bbox: a.bbox,
+            skip: 0,
+            limit: default_limit(),
+            after: None,
+            order_by: default_order_by(),
+            direction: default_direction()
+        }
+    }
+}
+
+impl From<&AnalysisExportQuery> for analyses::SelectOptions {
+    fn from(a: &AnalysisExportQuery) -> Self {
+        analyses::SelectOptions {
+            order_by: a.order_by,
+            direction: a.direction,
+            ..analyses::SelectOptions::from(&a.filter)
+        }
+    }
+}
+
+fn deserialize_component_filters<'de, D>(deserializer: D)
+    -> Result<Vec<analyses::ComponentFilter>, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    let s = String::deserialize(deserializer)?;
+    analyses::ComponentFilter::parse_list(&s).map_err(serde::de::Error::custom)
+}
+
+/// Number in a query string. Flattened fields are given as text.
+fn deserialize_number_text<'de, D>(deserializer: D)
+    -> Result<Option<f64>, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    let s = String::deserialize(deserializer)?;
+    s.trim().parse::<f64>().map(Some).map_err(serde::de::Error::custom)
+}
+
 fn default_limit() -> u32 { 20 }
 fn default_order_by() -> analyses::SortKey { analyses::SortKey::LastModified }
 fn default_direction() -> i32 { -1 }
@@ -51,11 +291,12 @@ fn default_direction() -> i32 { -1 }
 impl From<&AnalysisListQuery> for analyses::SelectOptions {
     fn from(a: &AnalysisListQuery) -> Self {
         analyses::SelectOptions {
-            query: a.query.clone(),
             skip: a.page * a.limit,
             limit: a.limit,
+            after: a.cursor.clone(),
             order_by: a.order_by,
-            direction: a.direction
+            direction: a.direction,
+            ..analyses::SelectOptions::from(&a.filter)
         }
     }
 }
@@ -66,7 +307,46 @@ struct AnalysisList {
     total: u32,
     page: u32,
     limit: u32,
-    analysis: Vec<Analysis>
+    analysis: Vec<Analysis>,
+    facets: analyses::Facets,
+    /// Snippets matching `q` by analysis id and field
+    highlights: Highlights,
+    /// Cursor to the next page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<Cursor>
+}
+
+/// Saved analysis, with `version`, `warnings` and `recomputed` added to it
+/// when `report` is asked. Warnings are given in `X-Analysis-Warnings` either
+/// way.
+#[derive(Serialize)]
+struct AnalysisSaveResult {
+    #[serde(flatten)]
+    analysis: Analysis,
+    /// Version to give on the next update
+    version: Version,
+    warnings: Vec<analysis::Warning>,
+    /// Differences of totals from submitted ones, when recomputed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recomputed: Option<Vec<analysis::TotalDifference>>
+}
+
+impl AnalysisSaveResult {
+    fn new((a, version): (Analysis, Version), submitted: &Analysis,
+           options: &analyses::SaveOptions) -> Self {
+        let warnings = a.validate().warnings;
+        let recomputed = if options.recompute_totals {
+            Some(a.total_differences(submitted))
+        } else {
+            None
+        };
+        AnalysisSaveResult {
+            analysis: a,
+            version,
+            warnings,
+            recomputed
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -74,22 +354,97 @@ struct TemplateList {
     templates: Vec<Template>
 }
 
+#[derive(Debug, Deserialize)]
+struct ComponentListQuery {
+    category: Option<component::Category>
+}
+
+#[derive(Serialize)]
+struct ComponentList<'a> {
+    components: Vec<&'a component::Component>
+}
+
+/// Version the client expects to update, from If-Match or else `version`.
+/// If-Match "*" matches any version, and weak tags none as If-Match compares
+/// tags strongly (RFC 7232, section 3.1).
+fn expected_version(req: &HttpRequest, version: Option<Version>)
+                    -> Result<Option<Version>, HttpResponse> {
+    let v = match req.headers().get(IF_MATCH) {
+        None => return Ok(version),
+        Some(v) => v.to_str().map(|v| v.trim()).map_err(|e| {
+            HttpResponse::BadRequest().body(format!("If-Match: {}", e))
+        })?
+    };
+    if v == "*" {
+        Ok(None)
+    } else if v.starts_with("W/") {
+        Err(HttpResponse::PreconditionFailed().finish())
+    } else {
+        v.parse().map(Some).map_err(|e| HttpResponse::BadRequest().body(e))
+    }
+}
+
+/// Id of the user given by the bearer token, if valid.
+fn token_user(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let token = value.split_once(' ')?.1;
+    TokenData::try_from(token).ok().map(|t| t.get_id().to_string())
+}
+
+/// Saved analysis with its version in ETag and its warnings as a JSON array
+/// in `X-Analysis-Warnings`.
+fn saved_analysis_response(saved: (Analysis, Version), submitted: &Analysis,
+                           options: &analyses::SaveOptions, report: bool)
+                           -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response.header(ETAG, saved.1.etag());
+    match to_ascii_string(&saved.0.validate().warnings) {
+        Ok(warnings) => {
+            response.header(HEADER_ANALYSIS_WARNINGS, warnings);
+        },
+        Err(e) => println!("Failed to serialize warnings, {}", e)
+    }
+    if report {
+        response.json(AnalysisSaveResult::new(saved, submitted, options))
+    } else {
+        response.json(saved.0)
+    }
+}
+
+/// 409 Conflict with the analysis stored now.
+async fn analysis_conflict<'a>(models: &Models<'a>, id: &String)
+                               -> HttpResponse {
+    match analyses::by_id_with_version(models, id).await {
+        Ok(Some((mut a, version))) => {
+            a.compute();
+            HttpResponse::Conflict().header(ETAG, version.etag()).json(a)
+        },
+        _ => HttpResponse::Conflict().finish()
+    }
+}
+
 async fn index() -> impl Responder {
     HttpResponse::Ok().body("こんにちは世界")
 }
 
-async fn add_analysis(json: web::Json<Analysis>,
+async fn add_analysis(req: HttpRequest,
+                      json: web::Json<Analysis>,
+                      query: web::Query<AnalysisSaveQuery>,
                       pool: web::Data<DBConnectionPool>)
     -> impl Responder {
     println!("Start add_analysis");
     let models = Models::new(pool.get_ref());
     let a = &json.into_inner();
+    let query = query.into_inner();
+    let mut options = analyses::SaveOptions::from(&query);
+    options.saved_by = token_user(&req);
     match a.id {
         None => {
             // add_analysis is allowed when id is None
-            let a = analyses::save(&models, &a).await;
-            match a {
-                Ok(a) => HttpResponse::Ok().json(a),
+            let saved = analyses::save(&models, a, &options).await;
+            match saved {
+                Ok(saved) => saved_analysis_response(saved, a, &options,
+                                                    query.report),
                 Err(_) => HttpResponse::Forbidden().finish()
             }
         },
@@ -97,19 +452,31 @@ async fn add_analysis(json: web::Json<Analysis>,
     }
 }
 
-async fn update_analysis(info: web::Path<AnalysisPath>,
+async fn update_analysis(req: HttpRequest,
+                   info: web::Path<AnalysisPath>,
                    json: web::Json<Analysis>,
+                   query: web::Query<AnalysisSaveQuery>,
                    pool: web::Data<DBConnectionPool>)
                    -> impl Responder {
     println!("Start add_analysis");
     let models = Models::new(pool.get_ref());
     let a = &json.into_inner();
+    let query = query.into_inner();
+    let mut options = analyses::SaveOptions::from(&query);
+    options.version = match expected_version(&req, options.version) {
+        Ok(v) => v,
+        Err(response) => return response
+    };
+    options.saved_by = token_user(&req);
     match &a.id {
         Some(id) if id.clone() == info.id => {
             // update_analysis is allowed when id matches with path
-            let a = analyses::save(&models, &a).await;
-            match a {
-                Ok(a) => HttpResponse::Ok().json(a),
+            let saved = analyses::save(&models, a, &options).await;
+            match saved {
+                Ok(saved) => saved_analysis_response(saved, a, &options,
+                                                    query.report),
+                Err(SaveError::Conflict) =>
+                    analysis_conflict(&models, id).await,
                 Err(_) => HttpResponse::Forbidden().finish()
             }
         },
@@ -118,12 +485,23 @@ async fn update_analysis(info: web::Path<AnalysisPath>,
 }
 
 
+// POST /analysis/validate
+async fn validate_analysis(json: web::Json<Analysis>) -> impl Responder {
+    let mut a = json.into_inner();
+    a.derive_values(false);
+    HttpResponse::Ok().json(a.validate())
+}
 
 async fn list_analysis(query: web::Query<AnalysisListQuery>,
                  pool: web::Data<DBConnectionPool>) -> impl Responder {
     let models = Models::new(pool.get_ref());
     let query = &query.into_inner();
     let options = analyses::SelectOptions::from(query);
+    if let Some(Err(e)) = options.after.as_ref()
+        .map(|c| c.search_after(&options.sort()))
+    {
+        return HttpResponse::BadRequest().body(e);
+    }
     let result = models::analyses::select(&models, &options).await;
     match result {
         Ok(ans) => {
@@ -131,7 +509,10 @@ async fn list_analysis(query: web::Query<AnalysisListQuery>,
                 total: ans.total,
                 page: query.page,
                 limit: query.limit,
-                analysis: ans.items.collect::<Vec<Analysis>>()
+                analysis: ans.items.collect::<Vec<Analysis>>(),
+                facets: ans.facets,
+                highlights: ans.highlights,
+                next: ans.next
             };
             HttpResponse::Ok().json(json)
         },
@@ -148,28 +529,16 @@ async fn get_analysis(info: web::Path<AnalysisPath>,
                 -> impl Responder {
     println!("Start get_analysis, info: {:?}", &info);
     let models = Models::new(pool.get_ref());
-    let result = models::analyses::by_id(&models, &info.id).await;
+    let result = models::analyses::by_id_with_version(&models, &info.id).await;
     match result {
-        Ok(Some(a)) => {
-            match &query.template {
-                Some(template_id) => {
-                    let template =
-                        models::templates::by_id(&models, &template_id).await;
-                    match template {
-                        Ok(Some(template)) =>
-                            HttpResponse::Ok().body(match &a.render(&template) {
-                                Ok(body) => body,
-                                Err(e) => e
-                            }),
-                        Ok(None) => HttpResponse::NotFound().finish(),
-                        Err(e) => {
-                            println!("main::get_analysis, error: {}", e);
-                            HttpResponse::InternalServerError().finish()
-                        }
-                    }
-                },
-                None => // Return by JSON
-                   HttpResponse::Ok().json(a)
+        Ok(Some((mut a, version))) => {
+            a.compute();
+            match (&query.template, query.format) {
+                (Some(template_id), _) =>
+                    render_by_template(&models, template_id, &a).await,
+                (None, AnalysisFormat::Pdf) => pdf_response(&a),
+                (None, AnalysisFormat::Json) => // Return by JSON
+                   HttpResponse::Ok().header(ETAG, version.etag()).json(a)
             }
         },
         Ok(None) => HttpResponse::NotFound().finish(),
@@ -180,15 +549,321 @@ async fn get_analysis(info: web::Path<AnalysisPath>,
     }
 }
 
+// GET /analysis/suggest
+async fn suggest_analysis(query: web::Query<AnalysisSuggestQuery>,
+                          pool: web::Data<DBConnectionPool>)
+                          -> impl Responder {
+    println!("Start suggest_analysis, query: {:?}", &query);
+    let models = Models::new(pool.get_ref());
+    let prefix = query.query.trim();
+    if prefix.is_empty() {
+        return HttpResponse::Ok().json(AnalysisSuggestList { items: vec![] });
+    }
+    let limit = query.limit.min(MAX_SUGGEST_LIMIT);
+    match analyses::suggest(&models, prefix, limit).await {
+        Ok(items) => HttpResponse::Ok().json(AnalysisSuggestList {
+            items
+        }),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/{id}.pdf
+async fn pdf_analysis(info: web::Path<AnalysisPath>,
+                      pool: web::Data<DBConnectionPool>)
+                      -> impl Responder {
+    println!("Start pdf_analysis, info: {:?}", &info);
+    let models = Models::new(pool.get_ref());
+    match models::analyses::by_id(&models, &info.id).await {
+        Ok(Some(mut a)) => {
+            a.compute();
+            pdf_response(&a)
+        },
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn pdf_response(a: &Analysis) -> HttpResponse {
+    let filename = a.id.as_deref().unwrap_or("analysis");
+    HttpResponse::Ok()
+        .content_type("application/pdf")
+        .header("Content-Disposition",
+                format!("inline; filename=\"{}.pdf\"", filename))
+        .body(pdf::sheet::render(a).to_bytes())
+}
+
+// GET /analysis.geojson
+async fn geojson_analysis(query: web::Query<AnalysisListQuery>,
+                          pool: web::Data<DBConnectionPool>)
+                          -> impl Responder {
+    println!("Start geojson_analysis, query: {:?}", &query);
+    let models = Models::new(pool.get_ref());
+    let options = analyses::SelectOptions::from(&*query);
+    match analyses::select_features(&models, &options).await {
+        Ok(items) => HttpResponse::Ok()
+            .content_type("application/geo+json")
+            .body(geo::feature_collection(&items).to_string()),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/export
+async fn export_analysis(query: web::Query<AnalysisExportQuery>,
+                         pool: web::Data<DBConnectionPool>)
+                         -> impl Responder {
+    println!("Start export_analysis, query: {:?}", &query);
+    let models = Models::new(pool.get_ref());
+    let options = analyses::SelectOptions::from(&*query);
+    let columns: Option<Vec<String>> = query.columns.as_ref().map(|c| {
+        c.split(',').map(|c| c.trim().to_string()).collect()
+    });
+    let result = analyses::select_all(&models, &options).await
+        .and_then(|items| {
+            tabular::export(&items, query.format, columns.as_deref())
+        });
+    match result {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(query.format.content_type())
+            .header("Content-Disposition",
+                    format!("attachment; filename=\"analyses.{}\"",
+                            query.format.extension()))
+            .body(body),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/piper.svg
+async fn piper_analyses(query: web::Query<AnalysisListQuery>,
+                        pool: web::Data<DBConnectionPool>)
+                        -> impl Responder {
+    println!("Start piper_analyses, query: {:?}", &query);
+    let models = Models::new(pool.get_ref());
+    let options = analyses::SelectOptions::from(&*query);
+    match models::analyses::select(&models, &options).await {
+        Ok(ans) => HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .body(diagram::piper::render(&ans.items.collect::<Vec<_>>())),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/{id}/piper.svg
+async fn piper_analysis(info: web::Path<AnalysisPath>,
+                        pool: web::Data<DBConnectionPool>)
+                        -> impl Responder {
+    println!("Start piper_analysis, info: {:?}", &info);
+    let models = Models::new(pool.get_ref());
+    match models::analyses::by_id(&models, &info.id).await {
+        Ok(Some(a)) => HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .body(diagram::piper::render(&[a])),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/{id}/revisions
+async fn list_analysis_revisions(info: web::Path<AnalysisPath>,
+                                 query: web::Query<AnalysisRevisionsQuery>,
+                                 pool: web::Data<DBConnectionPool>)
+                                 -> impl Responder {
+    println!("Start list_analysis_revisions, info: {:?}", &info);
+    let models = Models::new(pool.get_ref());
+    match analyses_history::select(&models, &info.id, query.limit).await {
+        Ok((total, revisions)) => HttpResponse::Ok().json(AnalysisRevisionList {
+            total,
+            revisions
+        }),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/{id}/revisions/{revision}
+async fn get_analysis_revision(info: web::Path<AnalysisRevisionPath>,
+                               pool: web::Data<DBConnectionPool>)
+                               -> impl Responder {
+    println!("Start get_analysis_revision, info: {:?}", &info);
+    let models = Models::new(pool.get_ref());
+    match analyses_history::by_revision(&models, &info.id, info.revision)
+        .await
+    {
+        Ok(Some((revision, mut a))) => {
+            a.compute();
+            HttpResponse::Ok().json(AnalysisRevision {
+                revision,
+                analysis: a
+            })
+        },
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// POST /analysis/{id}/revisions/{revision}/restore
+async fn restore_analysis_revision(req: HttpRequest,
+                                   info: web::Path<AnalysisRevisionPath>,
+                                   query: web::Query<AnalysisSaveQuery>,
+                                   pool: web::Data<DBConnectionPool>)
+                                   -> impl Responder {
+    println!("Start restore_analysis_revision, info: {:?}", &info);
+    let models = Models::new(pool.get_ref());
+    let query = query.into_inner();
+    let mut options = analyses::SaveOptions::from(&query);
+    options.version = match expected_version(&req, options.version) {
+        Ok(v) => v,
+        Err(response) => return response
+    };
+    options.saved_by = token_user(&req);
+    match analyses::restore(&models, &info.id, info.revision, &options).await {
+        Ok(Some(saved)) => {
+            let restored = saved.0.clone();
+            saved_analysis_response(saved, &restored, &options,
+                                    query.report)
+        },
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(SaveError::Conflict) => analysis_conflict(&models, &info.id).await,
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/{id}/stiff.svg
+async fn stiff_analysis(info: web::Path<AnalysisPath>,
+                        pool: web::Data<DBConnectionPool>)
+                        -> impl Responder {
+    println!("Start stiff_analysis, info: {:?}", &info);
+    let models = Models::new(pool.get_ref());
+    match models::analyses::by_id(&models, &info.id).await {
+        Ok(Some(a)) => HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .body(diagram::stiff::render(&a)),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/{id}/similar
+async fn similar_analysis(info: web::Path<AnalysisPath>,
+                          query: web::Query<AnalysisSimilarQuery>,
+                          pool: web::Data<DBConnectionPool>)
+                          -> impl Responder {
+    println!("Start similar_analysis, info: {:?}", &info);
+    let models = Models::new(pool.get_ref());
+    let options = analyses::SimilarOptions {
+        size: query.limit.min(MAX_SIMILAR_LIMIT)
+    };
+    match analyses::similar(&models, &info.id, &options).await {
+        Ok(Some(items)) =>
+            HttpResponse::Ok().json(AnalysisSimilarList { items }),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// GET /analysis/{a}/diff/{b}
+async fn diff_analysis(info: web::Path<AnalysisDiffPath>,
+                       query: web::Query<AnalysisQuery>,
+                       pool: web::Data<DBConnectionPool>)
+                       -> impl Responder {
+    println!("Start diff_analysis, info: {:?}", &info);
+    let models = Models::new(pool.get_ref());
+    let before = models::analyses::by_id(&models, &info.a).await;
+    let after = models::analyses::by_id(&models, &info.b).await;
+    match (before, after) {
+        (Ok(Some(mut before)), Ok(Some(mut after))) => {
+            before.compute();
+            after.compute();
+            let d = AnalysisDiff::new(before, after);
+            match &query.template {
+                Some(template_id) =>
+                    render_by_template(&models, template_id, &d).await,
+                None => HttpResponse::Ok().json(d)
+            }
+        },
+        (Err(e), _) | (_, Err(e)) => {
+            println!("Error {}", e);
+            HttpResponse::InternalServerError().finish()
+        },
+        _ => HttpResponse::NotFound().finish()
+    }
+}
+
+async fn render_by_template<'a, T: Render>(models: &Models<'a>,
+                                           template_id: &String, item: &T)
+                                           -> HttpResponse {
+    let template = models::templates::by_id(models, template_id).await;
+    match template {
+        Ok(Some(template)) =>
+            HttpResponse::Ok().body(match &item.render(&template) {
+                Ok(body) => body,
+                Err(e) => e
+            }),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("main::render_by_template, error: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
 // POST /templates/
-async fn add_template(json: web::Json<Template>,
+async fn add_template(req: HttpRequest,
+                json: web::Json<Template>,
+                query: web::Query<TemplateSaveQuery>,
                 pool: web::Data<DBConnectionPool>)
                 -> impl Responder {
     println!("Start add_template");
     let models = Models::new(pool.get_ref());
-    let t = models::templates::save(&models, &json.into_inner()).await;
-    match t {
-        Ok(a) => HttpResponse::Ok().json(&a),
+    let version = match expected_version(&req, query.version) {
+        Ok(v) => v,
+        Err(response) => return response
+    };
+    let t = json.into_inner();
+    match models::templates::save(&models, &t, version).await {
+        Ok((t, version)) =>
+            HttpResponse::Ok().header(ETAG, version.etag()).json(&t),
+        Err(SaveError::Conflict) => {
+            let id = t.id.unwrap_or_default();
+            match models::templates::by_id_with_version(&models, &id).await {
+                Ok(Some((current, version))) => HttpResponse::Conflict()
+                    .header(ETAG, version.etag())
+                    .json(current),
+                _ => HttpResponse::Conflict().finish()
+            }
+        },
         Err(e) => {
             println!("Error {}", e);
             HttpResponse::Forbidden().finish() // TODO forbidden?
@@ -219,9 +894,10 @@ async fn get_template(info: web::Path<AnalysisPath>,
                 -> impl Responder {
     println!("Start get_template, info: {:?}", &info);
     let models = Models::new(pool.get_ref());
-    let result = models::templates::by_id(&models, &info.id).await;
+    let result = models::templates::by_id_with_version(&models, &info.id).await;
     match result {
-        Ok(Some(i)) => HttpResponse::Ok().json(i),
+        Ok(Some((t, version))) =>
+            HttpResponse::Ok().header(ETAG, version.etag()).json(t),
         Ok(None) => HttpResponse::NotFound().finish(),
         Err(e) => {
             println!("Error {}", e);
@@ -230,6 +906,15 @@ async fn get_template(info: web::Path<AnalysisPath>,
     }
 }
 
+// GET /components
+async fn list_components(query: web::Query<ComponentListQuery>)
+                         -> impl Responder {
+    let components = component::all().iter()
+        .filter(|c| query.category.is_none_or(|t| c.category == t))
+        .collect();
+    HttpResponse::Ok().json(ComponentList { components })
+}
+
 // /debug/scrube
 async fn debug_scrub(query: web::Query<ScrubQuery>) -> String {
     utils::scrub::scrub(&query.title)
@@ -240,6 +925,17 @@ fn setup_logger() {
     info!("Log initialized.");
 }
 
+/// Return why a request body was rejected, e.g. an invalid date in metadata.
+fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) ->
+    error::Error {
+        let message = format!("{}", &err);
+        println!("Invalid request body, {}", &message);
+        error::InternalError::from_response(
+            err,
+            HttpResponse::BadRequest().json(json!({ "error": message }))
+        ).into()
+    }
+
 #[actix_rt::main]
 // pub async fn start() -> std::io::Result<()> {
 // pub async fn start() -> std::result::Result<(), std::io::Error> {
@@ -260,17 +956,40 @@ pub async fn start() -> () {
     }
     let pool = pool.unwrap();
     Models::new(&pool).setup().await;
+    if let Err(e) = Models::new(&pool).check().await {
+        println!("Indices are out of date, {}; run `analysis migrate`, or \
+                  `analysis reindex` for fields to reindex", &e);
+        return;
+    }
     // Setup server
     let mut server = HttpServer::new(move || {
         App::new()
             .data(pool.clone())
+            .app_data(web::JsonConfig::default()
+                      .error_handler(json_error_handler))
             .route("/", web::get().to(index))
+            .route("/analysis.geojson", web::get().to(geojson_analysis))
             .service(
                 web::scope("/analysis")
                     .route("/", web::put().to(add_analysis))
+                    .route("/validate", web::post().to(validate_analysis))
                     .route("/{id}", web::post().to(update_analysis))
                     .route("/", web::get().to(list_analysis))
+                    .route("/export", web::get().to(export_analysis))
+                    .route("/piper.svg", web::get().to(piper_analyses))
+                    .route("/suggest", web::get().to(suggest_analysis))
+                    .route("/{id}.pdf", web::get().to(pdf_analysis))
                     .route("/{id}", web::get().to(get_analysis))
+                    .route("/{id}/similar", web::get().to(similar_analysis))
+                    .route("/{id}/stiff.svg", web::get().to(stiff_analysis))
+                    .route("/{id}/piper.svg", web::get().to(piper_analysis))
+                    .route("/{id}/revisions",
+                           web::get().to(list_analysis_revisions))
+                    .route("/{id}/revisions/{revision}",
+                           web::get().to(get_analysis_revision))
+                    .route("/{id}/revisions/{revision}/restore",
+                           web::post().to(restore_analysis_revision))
+                    .route("/{a}/diff/{b}", web::get().to(diff_analysis))
             )
             .service(
                 web::scope("/templates")
@@ -278,6 +997,7 @@ pub async fn start() -> () {
                     .route("/", web::get().to(list_templates))
                     .route("/{id}", web::get().to(get_template))
             )
+            .route("/components", web::get().to(list_components))
             .service(comment_service::service(web::scope("/comments")))
             .service(
                 web::scope("/static")
@@ -347,4 +1067,43 @@ mod tests {
         assert_eq!(result, "こんにちは世界");
     }
      */
+    use super::*;
+    use actix_web::http::StatusCode;
+
+    #[test]
+    fn it_read_filters_of_list_query() {
+        let query = web::Query::<AnalysisListQuery>::from_query(
+            "q=%E8%8D%89%E6%B4%A5&temperatureMin=42.5&phMax=3\
+             &mg=CO2:250&bbox=138,36,139,37&p=2&l=10").unwrap();
+        let options = analyses::SelectOptions::from(&*query);
+        assert_eq!(options.query.as_deref(), Some("草津"));
+        assert_eq!(options.temperature, analyses::Range::new(Some(42.5), None));
+        assert_eq!(options.ph, analyses::Range::new(None, Some(3.0)));
+        assert_eq!(options.components.len(), 1);
+        assert!(options.bbox.is_some());
+        assert_eq!((options.skip, options.limit), (20, 10));
+        assert!(web::Query::<AnalysisExportQuery>::from_query(
+            "temperatureMin=hot").is_err());
+    }
+
+    #[test]
+    fn it_read_expected_version() {
+        let version = Version { seq_no: 5, primary_term: 1 };
+        let req = |tag: &str| actix_web::test::TestRequest::default()
+            .header(IF_MATCH, tag).to_http_request();
+        let none = actix_web::test::TestRequest::default().to_http_request();
+        let status = |r: Result<Option<Version>, HttpResponse>| {
+            r.map_err(|e| e.status())
+        };
+        assert_eq!(status(expected_version(&none, Some(version))),
+                   Ok(Some(version)));
+        assert_eq!(status(expected_version(&req("*"), Some(version))),
+                   Ok(None));
+        assert_eq!(status(expected_version(&req("\"5-1\""), None)),
+                   Ok(Some(version)));
+        assert_eq!(status(expected_version(&req("W/\"5-1\""), None)),
+                   Err(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(status(expected_version(&req("\"abc\""), None)),
+                   Err(StatusCode::BAD_REQUEST));
+    }
 }
@@ -22,7 +22,7 @@ use crate::models::{
 };
 use crate::utils::{
     identifier::Generate,
-    elasticsearch::DBConnectionPool,   
+    elasticsearch::{DBConnectionPool, Highlights, Cursor},
     web::{read_content_length, save_uploaded_file,
           SaveUploadedFileOptions},
     image::ImagePath
@@ -129,6 +129,9 @@ struct CommentListQuery {
     pub parent_id: Option<String>,
     #[serde(rename = "l", default = "default_limit")]
     pub limit: u32,
+    /// `next` of the previous page
+    #[serde(rename = "c", default)]
+    pub cursor: Option<Cursor>,
     // #[serde(rename = "o", default = "default_order_by")]
     // pub order_by: analyses::SortKey,
     // #[serde(rename = "d", default = "default_direction")]
@@ -142,7 +145,12 @@ struct CommentList {
     total: u32,
     page: u32,
     limit: u32,
-    comments: Vec<Comment>
+    comments: Vec<Comment>,
+    /// Snippets matching `q` by comment id and field
+    highlights: Highlights,
+    /// Cursor to the next page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<Cursor>
 }
 
 impl From<&CommentListQuery> for SelectOptions {
@@ -155,7 +163,8 @@ impl From<&CommentListQuery> for SelectOptions {
                     Some(comments::SelectQuery::Parent(p.to_string())),
                 _ => None
             },
-            limit: item.limit
+            limit: item.limit,
+            after: item.cursor.clone()
         }
     }
 }
@@ -398,6 +407,11 @@ async fn list_comments(query: web::Query<CommentListQuery>,
     let models = Models::new(pool.get_ref());
     let query = &query.into_inner();
     let options = SelectOptions::from(query);
+    if let Some(Err(e)) = options.after.as_ref()
+        .map(|c| c.search_after(&options.sort()))
+    {
+        return HttpResponse::BadRequest().body(e);
+    }
     let result = comments::select(&models, &options).await;
     match result {
         Ok(cs) => {
@@ -405,7 +419,9 @@ async fn list_comments(query: web::Query<CommentListQuery>,
                 total: cs.total,
                 page: 0,
                 limit: query.limit,
-                comments: cs.items.collect::<Vec<Comment>>()
+                comments: cs.items.collect::<Vec<Comment>>(),
+                highlights: cs.highlights,
+                next: cs.next
             };
             HttpResponse::Ok().json(json)
         },
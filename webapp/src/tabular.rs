@@ -0,0 +1,384 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::analysis::{Analysis, CellValue, MgMvalMmol};
+use crate::component;
+use crate::metadata::Metadata;
+
+/*
+ * Mapping between Analysis and flat rows of tabular files such as CSV.
+ * Each column is named by a path, e.g. "name", "temperature",
+ * "positiveIon.Na.mg", "totalMelt.mg" or "location" for metadata.
+ */
+
+const FIELDS: [&str; 4] = ["mg", "mval", "mvalPercent", "mmol"];
+
+fn field<'a>(v: &'a MgMvalMmol, field: &str) -> Option<&'a CellValue> {
+    match field {
+        "mg" => Some(&v.mg),
+        "mval" => Some(&v.mval),
+        "mvalPercent" => Some(&v.mval_percent),
+        "mmol" => Some(&v.mmol),
+        _ => None
+    }
+}
+
+fn field_mut<'a>(v: &'a mut MgMvalMmol, field: &str)
+                 -> Option<&'a mut CellValue> {
+    match field {
+        "mg" => Some(&mut v.mg),
+        "mval" => Some(&mut v.mval),
+        "mvalPercent" => Some(&mut v.mval_percent),
+        "mmol" => Some(&mut v.mmol),
+        _ => None
+    }
+}
+
+/// Read a cell. Empty cells are Null.
+pub fn parse_cell(text: &str) -> CellValue {
+    let t = text.trim();
+    if t.is_empty() {
+        return CellValue::Null;
+    }
+    match t.parse::<f64>() {
+        Ok(x) => CellValue::Number(x),
+        Err(_) => CellValue::parse_text(t)
+    }
+}
+
+fn set_component(a: &mut Analysis, table: &str, key: &str, field: &str,
+                 value: CellValue) -> Result<(), String> {
+    if component::lookup(key).is_none() {
+        return Err(format!("unknown component: {}", key));
+    }
+    let mut tables = a.tables_mut();
+    let t = tables.iter_mut()
+        .find(|(name, _)| *name == table)
+        .map(|(_, t)| t)
+        .ok_or_else(|| format!("unknown table: {}", table))?;
+    let v = t.components.entry(key.to_string())
+        .or_insert_with(MgMvalMmol::new);
+    let cell = field_mut(v, field)
+        .ok_or_else(|| format!("unknown field: {}", field))?;
+    *cell = value;
+    Ok(())
+}
+
+fn set_total(a: &mut Analysis, total: &str, field: &str, value: CellValue)
+             -> Result<(), String> {
+    let mut totals = a.totals_mut();
+    let v = totals.iter_mut()
+        .find(|(name, _)| *name == total)
+        .map(|(_, v)| v)
+        .ok_or_else(|| format!("unknown column: {}.{}", total, field))?;
+    let cell = field_mut(v, field)
+        .ok_or_else(|| format!("unknown field: {}", field))?;
+    *cell = value;
+    Ok(())
+}
+
+fn set_column(a: &mut Analysis, meta: &mut Map<String, Value>,
+              column: &str, text: &str) -> Result<(), String> {
+    let path: Vec<&str> = column.split('.').collect();
+    match path.as_slice() {
+        ["id"] => {
+            let id = text.trim();
+            a.id = if id.is_empty() { None } else { Some(id.to_string()) };
+        },
+        ["name"] => a.name = text.trim().to_string(),
+        ["yield"] => a.gensen_yield = parse_cell(text),
+        ["pH"] => a.ph = parse_cell(text),
+        ["temperature"] => a.temperature = match parse_cell(text) {
+            CellValue::Number(x) => Some(x),
+            CellValue::Null => None,
+            _ => return Err(format!("temperature is not a number: {}", text))
+        },
+        // Computed values are not entered
+        ["computed", _] => {},
+        [table, key, field] => set_component(a, table, key, field,
+                                             parse_cell(text))?,
+        [total, field] => set_total(a, total, field, parse_cell(text))?,
+        [key] => {
+            if !text.trim().is_empty() {
+                meta.insert(key.to_string(), Value::from(text.trim()));
+            }
+        },
+        _ => return Err(format!("unknown column: {}", column))
+    }
+    Ok(())
+}
+
+/// Build an analysis from pairs of a column name and a cell.
+/// All problems in the row are reported at once.
+pub fn from_row<'a, I>(row: I) -> Result<Analysis, String>
+    where I: Iterator<Item = (&'a str, &'a str)>
+{
+    let mut a = Analysis::new("");
+    let mut meta = Map::new();
+    let mut errors = vec![];
+    for (column, text) in row {
+        if let Err(e) = set_column(&mut a, &mut meta, column.trim(), text) {
+            errors.push(e);
+        }
+    }
+    match serde_json::from_value::<Metadata>(Value::Object(meta)) {
+        Ok(m) => a.meta = m,
+        Err(e) => errors.push(format!("invalid metadata: {}", e))
+    }
+    if a.name.is_empty() {
+        errors.push("name is empty".to_string());
+    }
+    if errors.is_empty() {
+        Ok(a)
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn json_text(v: &Value) -> String {
+    match v {
+        Value::Null => String::new(),
+        Value::String(s) => s.to_string(),
+        v => v.to_string()
+    }
+}
+
+fn object_of<T: serde::Serialize>(v: &T) -> Map<String, Value> {
+    match serde_json::to_value(v) {
+        Ok(Value::Object(m)) => m,
+        _ => Map::new()
+    }
+}
+
+/// Text of a column in an analysis. Unknown columns are empty.
+pub fn get_column(a: &Analysis, column: &str) -> String {
+    let path: Vec<&str> = column.split('.').collect();
+    let cell = match path.as_slice() {
+        ["id"] => return a.id.clone().unwrap_or_default(),
+        ["name"] => return a.name.to_string(),
+        ["yield"] => Some(&a.gensen_yield),
+        ["pH"] => Some(&a.ph),
+        ["temperature"] =>
+            return a.temperature.map_or(String::new(), |t| t.to_string()),
+        ["computed", key] =>
+            return object_of(&a.computed).get(*key).map_or(String::new(),
+                                                           json_text),
+        [table, key, f] => a.tables().iter()
+            .find(|(name, _)| name == table)
+            .and_then(|(_, t)| t.components.get(*key))
+            .and_then(|v| field(v, f)),
+        [total, f] => a.totals().iter()
+            .find(|(name, _)| name == total)
+            .and_then(|(_, v)| field(v, f)),
+        [key] =>
+            return object_of(&a.meta).get(*key).map_or(String::new(),
+                                                       json_text),
+        _ => None
+    };
+    cell.map_or(String::new(), |c| c.to_string())
+}
+
+/// Columns covering every value in the analyses.
+pub fn default_columns(analyses: &[Analysis]) -> Vec<String> {
+    let mut columns: Vec<String> =
+        ["id", "name", "yield", "temperature", "pH"]
+        .iter().map(|c| c.to_string()).collect();
+    let tables = analyses.first().map(|a| a.tables().len()).unwrap_or(0);
+    for i in 0..tables {
+        let mut keys: Vec<&String> = vec![];
+        let mut table_name = "";
+        for a in analyses {
+            let (name, t) = a.tables()[i];
+            table_name = name;
+            for key in t.components.keys() {
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort_by_key(|k| {
+            component::all().iter().position(|c| c.key == k.as_str())
+        });
+        for key in keys {
+            for f in FIELDS.iter() {
+                columns.push(format!("{}.{}.{}", table_name, key, f));
+            }
+        }
+    }
+    if let Some(a) = analyses.first() {
+        for (total, _) in a.totals().iter() {
+            for f in FIELDS.iter() {
+                columns.push(format!("{}.{}", total, f));
+            }
+        }
+        for key in object_of(&a.computed).keys() {
+            columns.push(format!("computed.{}", key));
+        }
+    }
+    let mut meta: Vec<String> = vec![];
+    for a in analyses {
+        for key in object_of(&a.meta).keys() {
+            if !meta.contains(key) {
+                meta.push(key.to_string());
+            }
+        }
+    }
+    meta.sort();
+    columns.extend(meta);
+    columns
+}
+
+#[derive(Copy, Clone, PartialEq, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Tsv,
+    Jsonl
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Tsv => "text/tab-separated-values; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson"
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Tsv => "tsv",
+            ExportFormat::Jsonl => "jsonl"
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "tsv" => Ok(ExportFormat::Tsv),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            _ => Err(format!("unknown format: {}, expected csv, tsv or jsonl",
+                             s))
+        }
+    }
+}
+
+/// Write analyses in the format. Without `columns`, CSV and TSV have
+/// `default_columns` and JSON lines have whole analyses as `analysis all`.
+pub fn export(analyses: &[Analysis], format: ExportFormat,
+              columns: Option<&[String]>) -> Result<String, String> {
+    let delimiter = match format {
+        ExportFormat::Csv => b',',
+        ExportFormat::Tsv => b'\t',
+        ExportFormat::Jsonl => {
+            let mut lines = String::new();
+            for a in analyses {
+                let line = match columns {
+                    Some(columns) => Value::Object(columns.iter().map(|c| {
+                        (c.to_string(), Value::from(get_column(a, c)))
+                    }).collect()),
+                    None => serde_json::to_value(a)
+                        .map_err(|e| format!("{}", e))?
+                };
+                lines.push_str(&line.to_string());
+                lines.push('\n');
+            }
+            return Ok(lines);
+        }
+    };
+    let defaults;
+    let columns = match columns {
+        Some(columns) => columns,
+        None => {
+            defaults = default_columns(analyses);
+            &defaults
+        }
+    };
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(vec![]);
+    writer.write_record(columns).map_err(|e| format!("{}", e))?;
+    for a in analyses {
+        writer.write_record(columns.iter().map(|c| get_column(a, c)))
+            .map_err(|e| format!("{}", e))?;
+    }
+    let bytes = writer.into_inner().map_err(|e| format!("{}", e))?;
+    String::from_utf8(bytes).map_err(|e| format!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_read_row() {
+        let row = vec![("name", "雨中温泉"),
+                       ("temperature", "48.8"),
+                       ("pH", "7.9"),
+                       ("positiveIon.Na.mg", "484"),
+                       ("negativeIon.Cl.mg", "<0.1"),
+                       ("totalMelt.mg", "1500"),
+                       ("location", "群馬県"),
+                       ("testedDate", "令和2年3月4日")];
+        let r = from_row(row.into_iter());
+        assert!(r.is_ok());
+        let a = r.unwrap();
+        assert_eq!(a.temperature, Some(48.8));
+        assert_eq!(a.positive_ion.components.get("Na").unwrap().mg,
+                   CellValue::Number(484.0));
+        assert_eq!(a.negative_ion.components.get("Cl").unwrap().mg,
+                   CellValue::BelowLimit {
+                       threshold: 0.1,
+                       text: "<0.1".to_string()
+                   });
+        assert_eq!(a.total_melt.mg, CellValue::Number(1500.0));
+        assert_eq!(a.meta.location, Some("群馬県".to_string()));
+        assert!(a.meta.tested_date.is_some());
+    }
+
+    #[test]
+    fn it_report_errors_in_row() {
+        let row = vec![("name", ""),
+                       ("temperature", "hot"),
+                       ("positiveIon.Xx.mg", "1"),
+                       ("testedDate", "昨日")];
+        let r = from_row(row.into_iter());
+        assert!(r.is_err());
+        let e = r.unwrap_err();
+        assert!(e.contains("temperature"));
+        assert!(e.contains("unknown component: Xx"));
+        assert!(e.contains("invalid metadata"));
+        assert!(e.contains("name is empty"));
+    }
+
+    #[test]
+    fn it_export_csv() {
+        let row = vec![("name", "雨中温泉"),
+                       ("temperature", "48.8"),
+                       ("negativeIon.Cl.mg", "<0.1"),
+                       ("location", "群馬県")];
+        let a = from_row(row.into_iter()).unwrap();
+        let columns: Vec<String> =
+            vec!["name".to_string(), "negativeIon.Cl.mg".to_string(),
+                 "location".to_string()];
+        let r = export(std::slice::from_ref(&a), ExportFormat::Tsv,
+                       Some(&columns));
+        assert_eq!(r, Ok("name\tnegativeIon.Cl.mg\tlocation\n\
+                          雨中温泉\t<0.1\t群馬県\n".to_string()));
+        // Exported rows can be imported again
+        let r = export(std::slice::from_ref(&a), ExportFormat::Csv, None)
+            .unwrap();
+        let mut reader = csv::Reader::from_reader(r.as_bytes());
+        let headers = reader.headers().unwrap().clone();
+        let record = reader.records().next().unwrap().unwrap();
+        let b = from_row(headers.iter().zip(record.iter()));
+        assert_eq!(b.map(|b| (b.name, b.negative_ion, b.meta)),
+                   Ok((a.name, a.negative_ion, a.meta)));
+    }
+}
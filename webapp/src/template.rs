@@ -1,10 +1,15 @@
 use handlebars::{
     Handlebars, Context, Helper, HelperResult,
-    Output, RenderContext
+    Output, RenderContext, html_escape
 };
 use serde::{Serialize, Deserialize};
 use serde_json::value::{Value};
 
+use crate::analysis::{Analysis, CellValue};
+use crate::component;
+use crate::diagram;
+use crate::quality;
+
 pub static KEY_ID: &str = "_id";
 pub static KEY_NAME: &str = "name";
 // static KEY_CONTENT_TYPE: &str = "contentType";
@@ -24,6 +29,46 @@ pub trait Render {
 
 const MAX_FRAC: usize = 2;
 
+pub fn format_prec(v: f64, prec: usize) -> String {
+    let i;
+    let f;
+    if v >= 1.0 {
+        i = (v.log(10f64).floor().abs() + 1f64) as usize;
+        f = if i > prec { 0 } else {
+            usize::min(prec - i, MAX_FRAC)
+        };
+    } else {
+        i = 1;
+        f = MAX_FRAC;
+    }
+    format!("{v:i$.f$}", v=v, i=i, f=f)
+}
+
+pub fn format_fixed(v: f64, prec: usize) -> String {
+    let i = if v >= 1.0 {
+        (v.log(10f64).floor().abs() + 1f64) as usize
+    } else {
+        1
+    };
+    format!("{v:i$.f$}", v=v, i=i, f=prec)
+}
+
+/// Format a cell value, numbers and detection limits by `format` and texts
+/// as written.
+fn format_cell(value: &Value, format: impl Fn(f64) -> String)
+               -> Option<String> {
+    match serde_json::from_value::<CellValue>(value.clone()) {
+        Ok(CellValue::Number(v)) => Some(format(v)),
+        Ok(CellValue::BelowLimit { threshold, .. }) => {
+            Some(html_escape(&format!("<{}", format(threshold))))
+        }
+        Ok(CellValue::Trace(text)) |
+        Ok(CellValue::NotDetected(text)) |
+        Ok(CellValue::Text(text)) => Some(html_escape(&text)),
+        Ok(CellValue::Null) | Err(_) => None
+    }
+}
+
 fn prec_helper (h: &Helper, _: &Handlebars, _: &Context,
                 _rc: &mut RenderContext, out: &mut dyn Output)
                 -> HelperResult
@@ -33,28 +78,9 @@ fn prec_helper (h: &Helper, _: &Handlebars, _: &Context,
     // Precision
     let prec = h.param(1)
         .and_then(|p| p.value().as_u64()).unwrap_or(2) as usize;
-    let text = match value {
-        None => None,
-        Some(p) => match p.value() {
-            Value::Number(n) => {
-                let v = n.as_f64().unwrap();
-                let i;
-                let f;
-                if v >= 1.0 {
-                    i = (v.log(10f64).floor().abs() + 1f64) as usize;
-                    f = if i > prec { 0 } else {
-                        usize::min(prec - i, MAX_FRAC)
-                    };
-                } else {
-                    i = 1;
-                    f = MAX_FRAC;
-                }
-                Some(format!("{v:i$.f$}", v=v, i=i, f=f))
-            },
-            Value::String(s) => Some(s.to_string()),
-            _ => None
-        }
-    };
+    let text = value.and_then(|p| {
+        format_cell(p.value(), |v| format_prec(v, prec))
+    });
     match text {
         Some(t) => {
             out.write(&t).or_else(|e| {
@@ -75,22 +101,9 @@ fn fixed_helper (h: &Helper, _: &Handlebars, _: &Context,
     // Precision
     let prec = h.param(1)
         .and_then(|p| p.value().as_u64()).unwrap_or(2) as usize;
-    let text = match value {
-        None => None,
-        Some(p) => match p.value() {
-            Value::Number(n) => {
-                let v = n.as_f64().unwrap();
-                let i = if v >= 1.0 {
-                    (v.log(10f64).floor().abs() + 1f64) as usize
-                } else {
-                    1
-                };
-                Some(format!("{v:i$.f$}", v=v, i=i, f=prec))
-            },
-            Value::String(s) => Some(s.to_string()),
-            _ => None
-        }
-    };
+    let text = value.and_then(|p| {
+        format_cell(p.value(), |v| format_fixed(v, prec))
+    });
     match text {
         Some(t) => {
             out.write(&t).or_else(|e| {
@@ -103,62 +116,7 @@ fn fixed_helper (h: &Helper, _: &Handlebars, _: &Context,
 }
 
 fn lookup_html_formula(key: &str) -> &str {
-    match key {
-        "H" => "H<sup>+</sup>",
-        "Li" => "Li<sup>+</sup>",
-        "B" => "B<sup>3+</sup>",
-        "C" => "C<sup>4+</sup>",
-        "N" => "N<sup>3-</sup>",
-        "O" => "O<sup>2-</sup>",
-        "F" => "F<sup>-</sup>",
-        "Na" => "Na<sup>+</sup>",
-        "Mg" => "Mg<sup>2+</sup>",
-        "Al" => "Al<sup>3+</sup>",
-        "Si" => "Si<sup>4-</sup>",
-        "P" => "P<sup>3-</sup>",
-        "S" => "S<sup>2-</sup>",
-        "Cl" => "Cl<sup>-</sup>",
-        "K" => "K<sup>+</sup>",
-        "Ca" => "Ca<sup>2+</sup>",
-        "Cr" => "Cr",
-        "MnII" => "Mn<sup>2+</sup>",
-        "FeII" => "Fe<sup>2+</sup>",
-        "FeIII" => "Fe<sup>3+</sup>",
-        "Cu" => "Cu",
-        "CuII" => "Cu<sup>2+</sup>",
-        "Zn" => "Zn",
-        "ZnII" => "Zn<sup>2+</sup>",
-        "As" => "As",
-        "Br" => "Br<sup>-</sup>",
-        "Sr" => "Sr<sup>2+</sup>",
-        "Ag" => "Ag",
-        "Cd" => "Cd",
-        "I" => "I<sup>-</sup>",
-        "Ba" => "Ba<sup>2+</sup>",
-        "Hg" => "Hg",
-        "Pb" => "Pb",
-        "H2SiO3" => "H<sub>2</sub>SiO<sub>3</sub>",
-        "H2S" => "H<sub>2</sub>S",
-        "HBO2" => "HBO<sub>2</sub>",
-        "HCO3" => "HCO<sub>3</sub><sup>-</sup>",
-        "HNO2" => "HNO<sub>2</sub><sup>-</sup>",
-        "HSiO3" => "HSiO<sub>3</sub><sup>-</sup>",
-        "HPO4" => "HPO<sub>4</sub><sup>2-</sup>",
-        "HS" => "HS<sup>-</sup>",
-        "HSO4" => "HSO<sub>4</sub><sup>-</sup>",
-        "HAsO2" => "HAsO<sub>2</sub>",
-        "BO2" => "BO<sub>2</sub>",
-        "CO2" => "CO<sub>2</sub>",
-        "CO3" => "CO<sub>3</sub><sup>2-</sup>",
-        "NH4" => "NH<sub>4</sub><sup>+</sup>",
-        "NO3" => "NO<sub>3</sub><sup>-</sup>",
-        "OH" => "OH<sup>-</sup>",
-        "PO4" => "PO<sub>4</sub><sup>2-</sup>",
-        "SO4" => "SO<sub>4</sub><sup>2-</sup>",
-        "S2O3" => "S<sub>2</sub>O<sub>3</sub><sup>2-</sup>",
-        "AsO2" => "AsO<sub>2</sub><sup>-</sup>",
-        _ => "Unknown"
-    }
+    component::lookup(key).map_or("Unknown", |c| c.html.as_str())
 }
 
 fn htmlf_helper(h: &Helper, _: &Handlebars, _: &Context,
@@ -185,6 +143,49 @@ fn htmlf_helper(h: &Helper, _: &Handlebars, _: &Context,
     }
 }
 
+fn springquality_helper(h: &Helper, _: &Handlebars, ctx: &Context,
+                        _rc: &mut RenderContext, out: &mut dyn Output)
+                        -> HelperResult
+{
+    // Analysis to classify, current context by default
+    let value = match h.param(0) {
+        Some(p) => p.value(),
+        None => ctx.data()
+    };
+    let text = serde_json::from_value::<Analysis>(value.clone()).ok()
+        .and_then(|a| quality::classify(&a));
+    match text {
+        Some(t) => {
+            out.write(&t).or_else(|e| {
+                warn!("springquality_helper writing error: {:?}", &e);
+                Ok(())
+            })
+        },
+        None => Ok(())
+    }
+}
+
+fn stiff_helper(h: &Helper, _: &Handlebars, ctx: &Context,
+                _rc: &mut RenderContext, out: &mut dyn Output)
+                -> HelperResult
+{
+    // Analysis to draw, current context by default
+    let value = match h.param(0) {
+        Some(p) => p.value(),
+        None => ctx.data()
+    };
+    let svg = serde_json::from_value::<Analysis>(value.clone()).ok()
+        .map(|a| diagram::stiff::render(&a));
+    match svg {
+        Some(t) => {
+            out.write(&t).or_else(|e| {
+                warn!("stiff_helper writing error: {:?}", &e);
+                Ok(())
+            })
+        },
+        None => Ok(())
+    }
+}
 
 // Resources:
 // - Handlebars https://handlebarsjs.com/
@@ -197,6 +198,8 @@ pub fn render_template<T: Serialize>(data: &T, template: &Template)
     reg.register_helper("prec", Box::new(prec_helper));
     reg.register_helper("fixed", Box::new(fixed_helper));
     reg.register_helper("htmlf", Box::new(htmlf_helper));
+    reg.register_helper("springquality", Box::new(springquality_helper));
+    reg.register_helper("stiff", Box::new(stiff_helper));
     reg.register_template_string(&template.name, &template.body)
         .or_else(|e| Err(format!("{}", e)))?;
     reg.render(&template.name, &data)
@@ -1,3 +1,7 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
 use async_trait::async_trait;
 use futures::prelude::*;
 use elasticsearch::{
@@ -5,13 +9,19 @@ use elasticsearch::{
     Elasticsearch,
     CountParts, GetParts, SearchParts, ScrollParts,
     CreateParts, DeleteParts, IndexParts,
-    indices::IndicesCreateParts,
+    indices::{
+        IndicesCreateParts, IndicesExistsParts, IndicesDeleteParts,
+        IndicesGetMappingParts, IndicesPutMappingParts,
+        IndicesGetSettingsParts, IndicesPutSettingsParts,
+        IndicesCloseParts, IndicesOpenParts
+    },
     http::{
+        StatusCode,
         headers::{CONTENT_TYPE, HeaderValue},
         transport::Transport
     }
 };
-use serde::{self, Deserialize, Serialize};
+use serde::{self, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value};
 
 pub type DBConnectionPool = Elasticsearch;
@@ -99,6 +109,66 @@ pub struct GetResult {
     pub _source: Value,
 }
 
+impl GetResult {
+    pub fn version(&self) -> Version {
+        Version { seq_no: self._seq_no, primary_term: self._primary_term }
+    }
+}
+
+/// Version of a stored document, given by its sequence number and primary
+/// term, written as "<seq_no>-<primary_term>" in ETags.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Version {
+    pub seq_no: u64,
+    pub primary_term: u64
+}
+
+impl Version {
+    /// Strong entity tag for HTTP headers.
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.seq_no, self.primary_term)
+    }
+}
+
+/// Either "3-1" or the quoted entity tag "\"3-1\"".
+impl FromStr for Version {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = s.trim();
+        let text = text.strip_prefix('"').and_then(|t| t.strip_suffix('"'))
+            .unwrap_or(text);
+        let mut parts = text.splitn(2, '-').map(|p| p.parse::<u64>());
+        match (parts.next(), parts.next()) {
+            (Some(Ok(seq_no)), Some(Ok(primary_term))) =>
+                Ok(Version { seq_no, primary_term }),
+            _ => Err(format!("invalid version: {}", s))
+        }
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct SearchOptions {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -111,7 +181,22 @@ pub struct SearchOptions {
     pub from: Option<u32>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub size: Option<u32>
+    pub size: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggs: Option<Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggest: Option<Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub highlight: Option<Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search_after: Option<Value>,
+
+    #[serde(rename = "_source", skip_serializing_if = "Option::is_none")]
+    pub source: Option<Value>
 }
 
 impl Default for SearchOptions {
@@ -120,7 +205,12 @@ impl Default for SearchOptions {
             query: None,
             sort: None,
             from: None,
-            size: None
+            size: None,
+            aggs: None,
+            suggest: None,
+            highlight: None,
+            search_after: None,
+            source: None
         }
     }
 }
@@ -166,6 +256,20 @@ pub struct OperationResult {
     pub result: OperationResultType,
 }
 
+impl OperationResult {
+    pub fn version(&self) -> Version {
+        Version { seq_no: self._seq_no, primary_term: self._primary_term }
+    }
+}
+
+/// Result of update, which conflicts if the document is no longer at the
+/// version given by `UpdateOptions::if_version`.
+#[derive(Debug)]
+pub enum UpdateResult {
+    Updated(OperationResult),
+    Conflict
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct ResultShards {
@@ -187,15 +291,24 @@ pub enum OperationResultType {
 #[derive(Serialize)]
 pub struct UpdateOptions {
     #[serde(skip)]
-    id: String
+    id: String,
+    #[serde(skip)]
+    version: Option<Version>
 }
 
 impl UpdateOptions {
     pub fn new(id: &str) -> Self {
         UpdateOptions {
-            id: id.to_string()
+            id: id.to_string(),
+            version: None
         }
     }
+
+    /// Update only if the document is still at `version`.
+    pub fn if_version(mut self, version: Option<Version>) -> Self {
+        self.version = version;
+        self
+    }
 }
 
 #[derive(Serialize)]
@@ -225,7 +338,121 @@ pub struct SearchResult {
     pub took: u64,
     pub timed_out: bool,
     pub hits: SearchResultHits,
-    pub _scroll_id: Option<String>
+    pub _scroll_id: Option<String>,
+    #[serde(default)]
+    pub aggregations: Option<Value>,
+    #[serde(default)]
+    pub suggest: Option<Value>
+}
+
+/// Snippets of matched text by field, of each document by id.
+pub type Highlights = BTreeMap<String, BTreeMap<String, Vec<String>>>;
+
+/// Highlight clause of search for `fields`, wrapping matched words with
+/// <em> in HTML escaped snippets.
+pub fn highlight(fields: &[&str]) -> Value {
+    let fields: serde_json::Map<String, Value> = fields.iter()
+        .map(|f| (f.to_string(), json!({})))
+        .collect();
+    json!({
+        "encoder": "html",
+        "pre_tags": ["<em>"],
+        "post_tags": ["</em>"],
+        "fields": fields
+    })
+}
+
+/// Opaque position in search results, made of the sort values of the last
+/// hit of a page and the sort they follow, to get the next page in the same
+/// order by `search_after`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Cursor {
+    sort: Value,
+    after: Value
+}
+
+impl Cursor {
+    /// Values to search after in `sort`, or Err if the cursor is made in
+    /// another order.
+    pub fn search_after(&self, sort: &Value) -> Result<Value, String> {
+        if &self.sort == sort {
+            Ok(self.after.clone())
+        } else {
+            Err(format!("cursor for another order: {}", self))
+        }
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = json!({"s": self.sort, "a": self.after});
+        f.write_str(&base32::encode(base32::Alphabet::Crockford,
+                                    value.to_string().as_bytes()))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        base32::decode(base32::Alphabet::Crockford, s)
+            .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok())
+            .filter(|v| v["s"].is_array() && v["a"].is_array())
+            .map(|v| Cursor { sort: v["s"].clone(), after: v["a"].clone() })
+            .ok_or(format!("invalid cursor: {}", s))
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl SearchResult {
+    /// Cursor to the page after this one of `size` hits sorted by `sort`, or
+    /// None if this is the last page.
+    pub fn next_cursor(&self, sort: &Value, size: u32) -> Option<Cursor> {
+        if (self.hits.hits.len() as u32) < size {
+            return None;
+        }
+        self.hits.hits.last()?.sort.clone().map(|after| Cursor {
+            sort: sort.clone(),
+            after
+        })
+    }
+
+    /// Highlighted snippets of hits, with fields renamed by `name`.
+    /// Fields for which `name` returns None are left out.
+    pub fn highlights<F>(&self, name: F) -> Highlights
+        where F: for<'k> Fn(&'k str) -> Option<&'k str>
+    {
+        self.hits.hits.iter().filter_map(|item| {
+            let fields: BTreeMap<String, Vec<String>> = item.highlight
+                .as_ref()?.as_object()?.iter()
+                .filter_map(|(key, snippets)| {
+                    let snippets = serde_json::from_value(snippets.clone())
+                        .ok()?;
+                    Some((name(key)?.to_string(), snippets))
+                })
+                .collect();
+            if fields.is_empty() {
+                None
+            } else {
+                Some((item._id.clone(), fields))
+            }
+        }).collect()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -248,7 +475,12 @@ pub struct SearchResultItem {
     pub _index: String,
     pub _id: String,
     pub _score: Option<f64>,
-    pub _source: Value
+    pub _source: Value,
+    #[serde(default)]
+    pub highlight: Option<Value>,
+    /// Sort values of the hit
+    #[serde(default)]
+    pub sort: Option<Value>
 }
 
 #[async_trait]
@@ -258,8 +490,18 @@ pub trait Setup {
     type SetupOptions;
     type SetupResult;
     
+    /// Create the index unless it exists.
     async fn setup(&self, options: Self::SetupOptions)
                    -> Result<Self::SetupResult, Self::Error>;
+    /// What of `options` the index lacks or maps otherwise, empty if the
+    /// index is up to date.
+    async fn check(&self, options: Self::SetupOptions)
+                   -> Result<Vec<String>, Self::Error>;
+    /// Add analyzers and fields of `options` missing from the index.
+    async fn migrate(&self, options: Self::SetupOptions)
+                     -> Result<Self::SetupResult, Self::Error>;
+    /// Delete the index with all its documents.
+    async fn teardown(&self) -> Result<Self::SetupResult, Self::Error>;
 }
 
 pub struct SetupOptions {
@@ -274,6 +516,104 @@ impl SetupOptions {
     }
 }
 
+/// Properties of mappings `wanted` that `current` lacks, and paths of those
+/// mapped with other types. The latter cannot be changed but by reindexing.
+fn missing_properties(current: &Value, wanted: &Value)
+                      -> (serde_json::Map<String, Value>, Vec<String>)
+{
+    let mut missing = serde_json::Map::new();
+    let mut conflicts = vec![];
+    let wanted = match wanted.as_object() {
+        Some(wanted) => wanted,
+        None => return (missing, conflicts)
+    };
+    for (key, w) in wanted {
+        let c = match current.get(key) {
+            Some(c) => c,
+            None => {
+                missing.insert(key.to_string(), w.clone());
+                continue;
+            }
+        };
+        if w.get("properties").is_some() && w.get("type").is_none() {
+            if c.get("type").is_some_and(|t| t != "object") {
+                conflicts.push(key.to_string());
+                continue;
+            }
+            let (m, cs) = missing_properties(&c["properties"],
+                                             &w["properties"]);
+            if !m.is_empty() {
+                missing.insert(key.to_string(), json!({"properties": m}));
+            }
+            conflicts.extend(cs.iter().map(|p| format!("{}.{}", key, p)));
+        } else if c.get("type") != w.get("type") {
+            conflicts.push(key.to_string());
+        } else if w["fields"].as_object().is_some_and(|fields| {
+            fields.keys().any(|f| c["fields"].get(f).is_none())
+        }) {
+            // Multi-fields are added by giving the whole field again
+            missing.insert(key.to_string(), w.clone());
+        }
+    }
+    (missing, conflicts)
+}
+
+/// Whether `current` analysis settings lack any tokenizer, analyzer or
+/// filter named in `wanted`.
+fn lacks_analysis(current: &Value, wanted: &Value) -> bool {
+    wanted.as_object().is_some_and(|wanted| {
+        wanted.iter().any(|(kind, items)| {
+            items.as_object().is_some_and(|items| {
+                items.keys().any(|name| current[kind].get(name).is_none())
+            })
+        })
+    })
+}
+
+/// How an index differs from its setup options.
+struct IndexDifference {
+    /// Whether any analyzer of the options is missing
+    lacks_analysis: bool,
+    /// Properties missing from the mappings
+    missing: serde_json::Map<String, Value>,
+    /// Paths of properties mapped with other types
+    conflicts: Vec<String>
+}
+
+impl<'a> Collection<'a> {
+    async fn difference(&self, options: &SetupOptions)
+                        -> Result<IndexDifference, elasticsearch::Error>
+    {
+        let index = &[self.name];
+        let settings = self.client.indices()
+            .get_settings(IndicesGetSettingsParts::Index(index))
+            .send()
+            .and_then(|r| async {
+                r.error_for_status_code_ref()?;
+                r.json::<Value>().await
+            })
+            .await?;
+        let mappings = self.client.indices()
+            .get_mapping(IndicesGetMappingParts::Index(index))
+            .send()
+            .and_then(|r| async {
+                r.error_for_status_code_ref()?;
+                r.json::<Value>().await
+            })
+            .await?;
+        let (missing, conflicts) = missing_properties(
+            &mappings[self.name]["mappings"]["properties"],
+            &options.value["mappings"]["properties"]);
+        Ok(IndexDifference {
+            lacks_analysis: lacks_analysis(
+                &settings[self.name]["settings"]["index"]["analysis"],
+                &options.value["settings"]["analysis"]),
+            missing,
+            conflicts
+        })
+    }
+}
+
 #[async_trait]
 impl<'a> Setup for Collection<'a> {
     type Error = elasticsearch::Error;
@@ -284,6 +624,14 @@ impl<'a> Setup for Collection<'a> {
     async fn setup(&self, options: Self::SetupOptions)
                    -> Result<Self::SetupResult, Self::Error>
     {
+        let exists = self.client.indices()
+            .exists(IndicesExistsParts::Index(&[self.name]))
+            .send()
+            .await?
+            .status_code() == StatusCode::OK;
+        if exists {
+            return Ok(format!("{} exists", self.name));
+        }
         self.client.indices()
             .create(IndicesCreateParts::Index(self.name))
             .body(options.value)
@@ -293,6 +641,88 @@ impl<'a> Setup for Collection<'a> {
             })
             .await
     }
+
+    async fn check(&self, options: Self::SetupOptions)
+                   -> Result<Vec<String>, Self::Error>
+    {
+        let difference = self.difference(&options).await?;
+        let mut stale = vec![];
+        if difference.lacks_analysis {
+            stale.push(format!("{} lacks analyzers", self.name));
+        }
+        if !difference.missing.is_empty() {
+            let keys = difference.missing.keys().cloned()
+                .collect::<Vec<String>>();
+            stale.push(format!("{} lacks fields: {}", self.name,
+                               keys.join(", ")));
+        }
+        if !difference.conflicts.is_empty() {
+            stale.push(format!("fields of {} to reindex: {}", self.name,
+                               difference.conflicts.join(", ")));
+        }
+        Ok(stale)
+    }
+
+    async fn migrate(&self, options: Self::SetupOptions)
+                     -> Result<Self::SetupResult, Self::Error>
+    {
+        let index = &[self.name];
+        let mut messages = vec![];
+        let IndexDifference {lacks_analysis, missing, conflicts} =
+            self.difference(&options).await?;
+        // Analyzers can be added only while the index is closed
+        let analysis = &options.value["settings"]["analysis"];
+        if lacks_analysis {
+            self.client.indices()
+                .close(IndicesCloseParts::Index(index))
+                .send()
+                .and_then(|r| async { r.error_for_status_code() })
+                .await?;
+            let put = self.client.indices()
+                .put_settings(IndicesPutSettingsParts::Index(index))
+                .body(json!({"analysis": analysis}))
+                .send()
+                .and_then(|r| async { r.error_for_status_code() })
+                .await;
+            self.client.indices()
+                .open(IndicesOpenParts::Index(index))
+                .send()
+                .and_then(|r| async { r.error_for_status_code() })
+                .await?;
+            put?;
+            messages.push(format!("added analyzers to {}", self.name));
+        }
+        if !missing.is_empty() {
+            let keys = missing.keys().cloned().collect::<Vec<String>>();
+            self.client.indices()
+                .put_mapping(IndicesPutMappingParts::Index(index))
+                .body(json!({"properties": missing}))
+                .send()
+                .and_then(|r| async { r.error_for_status_code() })
+                .await?;
+            messages.push(format!("added fields to {}: {}", self.name,
+                                  keys.join(", ")));
+        }
+        if !conflicts.is_empty() {
+            messages.push(format!("fields of {} to reindex: {}", self.name,
+                                  conflicts.join(", ")));
+        }
+        if messages.is_empty() {
+            messages.push(format!("{} is up to date", self.name));
+        }
+        Ok(messages.join("; "))
+    }
+
+    async fn teardown(&self) -> Result<Self::SetupResult, Self::Error> {
+        self.client.indices()
+            .delete(IndicesDeleteParts::Index(&[self.name]))
+            .send()
+            .and_then(|r| async {
+                r.error_for_status_code_ref()?;
+                r.text().await
+            })
+            .await
+    }
 }
 
 
@@ -303,7 +733,8 @@ impl<'a> Operations for Collection<'a> {
     type Client = Elasticsearch;
 
     type GetOptions = GetOptions;
-    type GetResult = GetResult;
+    /// None if the document is not found.
+    type GetResult = Option<GetResult>;
 
     type SelectOptions = SearchOptions;
     type SelectResult = SearchResult;
@@ -312,7 +743,7 @@ impl<'a> Operations for Collection<'a> {
     type InsertResult = OperationResult;
 
     type UpdateOptions = UpdateOptions;
-    type UpdateResult = OperationResult;
+    type UpdateResult = UpdateResult;
 
     type DeleteOptions = DeleteOptions;
     type DeleteResult = OperationResult;
@@ -334,8 +765,11 @@ impl<'a> Operations for Collection<'a> {
             .get(GetParts::IndexId(self.name, &options.id))
             .send()
             .and_then(|r| async {
+                if r.status_code() == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
                 r.error_for_status_code_ref()?;
-                r.json::<Self::GetResult>().await
+                r.json::<GetResult>().await.map(Some)
             })
             .await
     }
@@ -388,13 +822,22 @@ impl<'a> Operations for Collection<'a> {
     async fn update(&self, value: &Value, options: Self::UpdateOptions)
         -> Result<Self::UpdateResult, Self::Error>
     {
-        self.client
+        let mut request = self.client
             .index(IndexParts::IndexId(self.name, &options.id))
-            .body(value)
+            .body(value);
+        if let Some(v) = options.version {
+            request = request
+                .if_seq_no(v.seq_no as i64)
+                .if_primary_term(v.primary_term as i64);
+        }
+        request
             .send()
             .and_then(|r| async {
+                if r.status_code() == StatusCode::CONFLICT {
+                    return Ok(UpdateResult::Conflict);
+                }
                 r.error_for_status_code_ref()?;
-                r.json::<Self::UpdateResult>().await
+                r.json::<OperationResult>().await.map(UpdateResult::Updated)
             })
             .await
     }
@@ -461,3 +904,96 @@ impl<'a> Scroll for Collection<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_find_missing_properties() {
+        let current = json!({
+            "name": {"type": "text", "analyzer": "kuromoji"},
+            "heating": {"type": "text", "analyzer": "kuromoji"},
+            "ph": {"properties": {"t": {"type": "text"}}},
+            "quality": {"type": "text"},
+            "_sugg": {"properties": {"input": {"type": "text"}}}
+        });
+        let wanted = json!({
+            "name": {"type": "text", "analyzer": "kuromoji"},
+            "heating": {"type": "keyword"},
+            "ph": {"properties": {"n": {"type": "float"}}},
+            "quality": {
+                "type": "text",
+                "fields": {"keyword": {"type": "keyword"}}
+            },
+            "_sugg": {"type": "completion"},
+            "_tnct": {"type": "keyword"}
+        });
+        let (missing, conflicts) = missing_properties(&current, &wanted);
+        assert_eq!(Value::Object(missing), json!({
+            "ph": {"properties": {"n": {"type": "float"}}},
+            "quality": {
+                "type": "text",
+                "fields": {"keyword": {"type": "keyword"}}
+            },
+            "_tnct": {"type": "keyword"}
+        }));
+        assert_eq!(conflicts, vec!["_sugg", "heating"]);
+        let analysis = json!({"analyzer": {"reading": {"type": "custom"}}});
+        assert!(lacks_analysis(&Value::Null, &analysis));
+        assert!(!lacks_analysis(&analysis, &analysis));
+        assert!(!lacks_analysis(&Value::Null, &Value::Null));
+    }
+
+    #[test]
+    fn it_collect_highlights() {
+        let result: SearchResult = serde_json::from_value(json!({
+            "took": 1,
+            "timed_out": false,
+            "hits": {
+                "total": {"value": 2, "relation": "eq"},
+                "hits": [
+                    {"_index": "comments", "_id": "c1", "_score": 1.0,
+                     "_source": {},
+                     "highlight": {"body": ["<em>草津</em>の湯"],
+                                   "_hidden": ["x"]}},
+                    {"_index": "comments", "_id": "c2", "_score": 0.5,
+                     "_source": {}}
+                ]
+            }
+        })).unwrap();
+        let h = result.highlights(|key| match key {
+            "body" => Some("text"),
+            _ => None
+        });
+        assert_eq!(h.len(), 1);
+        assert_eq!(h["c1"]["text"], vec!["<em>草津</em>の湯".to_string()]);
+    }
+
+    #[test]
+    fn it_encode_cursor() {
+        let sort = json!([{"_lamo": "desc"}, {"id.keyword": "desc"}]);
+        let c = Cursor {
+            sort: sort.clone(),
+            after: json!([1592301234.5, "kusatsu"])
+        };
+        let text = c.to_string();
+        assert!(text.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_eq!(text.parse::<Cursor>(), Ok(c.clone()));
+        assert_eq!(c.search_after(&sort), Ok(json!([1592301234.5, "kusatsu"])));
+        assert!(c.search_after(&json!([{"_lamo": "asc"}])).is_err());
+        assert!("not a cursor!".parse::<Cursor>().is_err());
+        let c = Cursor { sort, after: json!(1) };
+        assert!(c.to_string().parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn it_parse_version() {
+        let v = Version { seq_no: 12, primary_term: 3 };
+        assert_eq!(v.etag(), "\"12-3\"");
+        assert_eq!("\"12-3\"".parse::<Version>(), Ok(v));
+        assert_eq!("12-3".parse::<Version>(), Ok(v));
+        assert!("12".parse::<Version>().is_err());
+        assert!("*".parse::<Version>().is_err());
+    }
+}
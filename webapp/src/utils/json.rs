@@ -1,3 +1,4 @@
+use serde::Serialize;
 use serde_json::Value;
 use std::convert::TryFrom;
 
@@ -8,3 +9,35 @@ where
     let value = value.ok_or("Not found")?;
     T::try_from(value)
 }
+
+/// JSON text of `value` with characters beyond ASCII escaped, to be given in
+/// HTTP headers.
+pub fn to_ascii_string<T: Serialize>(value: &T)
+                                     -> Result<String, serde_json::Error> {
+    let text = serde_json::to_string(value)?;
+    let mut ascii = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii() {
+            ascii.push(c);
+        } else {
+            for u in c.encode_utf16(&mut [0; 2]) {
+                ascii.push_str(&format!("\\u{:04x}", u));
+            }
+        }
+    }
+    Ok(ascii)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn it_escape_non_ascii() {
+        let value = json!({"field": "positiveIon.ナトリウム.mg", "n": 1});
+        let text = to_ascii_string(&value).unwrap();
+        assert!(text.is_ascii());
+        assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), value);
+    }
+}
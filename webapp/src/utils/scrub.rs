@@ -315,11 +315,49 @@ fn katakana_alphabet_map() -> Vec<KatakanaAlphabet> {
     v
 }
 
-pub fn scrub(text: &String) -> String {
-    // let mut yomis = vec![];
-    let katakanas: Vec<String> = tokenize_japanese(&text);
-    let katakanas: String = katakanas.join("");
-    let katakanas: Vec<Pron> = tokenize_katakana(&katakanas);
+/// Reading of Japanese text in katakana.
+pub fn yomi(text: &String) -> String {
+    tokenize_japanese(text).join("")
+}
+
+/// Katakana in text converted to hiragana.
+pub fn hiragana(text: &str) -> String {
+    text.chars().map(|c| match c {
+        'ァ'..='ヶ' => std::char::from_u32(c as u32 - 0x60).unwrap_or(c),
+        c => c
+    }).collect()
+}
+
+/// Readings of words in Japanese text in katakana.
+pub fn yomi_words(text: &String) -> Vec<String> {
+    tokenize_japanese(text).into_iter().filter(|w| !w.is_empty()).collect()
+}
+
+/// Romaji of katakana.
+pub fn romaji(katakanas: &String) -> String {
+    let katakanas: Vec<Pron> = tokenize_katakana(katakanas);
     let yomis: Vec<String> = katakana_yomis(katakanas);
     yomis.join("")
 }
+
+pub fn scrub(text: &String) -> String {
+    // let mut yomis = vec![];
+    romaji(&yomi(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_convert_katakana_to_hiragana() {
+        assert_eq!(hiragana("クサツオンセン"), "くさつおんせん");
+        assert_eq!(hiragana("ヴァ草津ー"), "ゔぁ草津ー");
+    }
+
+    #[test]
+    fn it_convert_katakana_to_romaji() {
+        assert_eq!(romaji(&"クサツ".to_string()), "kusatsu");
+        assert_eq!(romaji(&"ベップ".to_string()), "beppu");
+    }
+}